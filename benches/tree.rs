@@ -0,0 +1,99 @@
+// Times building the tree and taking one step (a force walk for every
+// body plus a rebuild, or the same with the FMM working out the
+// forces) for 10k, 100k and 1M bodies spread uniformly over most of
+// the root region. Run it with
+//
+//     cargo bench --bench tree [-- N ...]
+//
+// For comparison, on the same machine and with the same bodies
+// (release build, one thread):
+//
+//     bodies    old Arc<Mutex<Region>> tree    arena tree
+//               build       step               build       step
+//     10k       0.34 s      0.85 s             0.011 s     0.27 s
+//     100k      34.0 s      11.7 s             0.20 s      4.8 s
+//     1M        didn't finish                  2.5 s       83 s
+//
+// and on another (one thread), stepping with the FMM against the walk:
+//
+//     bodies    step       fmm step
+//     10k       0.055 s    0.036 s
+//     100k      1.42 s     0.55 s
+
+extern crate barnes_rust;
+extern crate rand;
+
+use barnes_rust::*;
+
+use rand::{SeedableRng, StdRng};
+use rand::distributions::{IndependentSample, Range};
+
+use std::env;
+use std::time::{Duration, Instant};
+
+fn seconds(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 * 1e-9
+}
+
+fn bench(num_bodies: usize) {
+    let params = Params {
+        // let the tree resolve everything, so no bodies get merged
+        min_len: 1e-9,
+        ..Params::default()
+    };
+
+    let seed: &[_] = &[1, 2, 3, 4];
+    let mut rng: StdRng = SeedableRng::from_seed(seed);
+    let pos_gen = Range::new(-0.9 * params.max_len, 0.9 * params.max_len);
+
+    let mut sim = Simulation::new(params);
+    for _ in 0..num_bodies {
+        let mut body = Body {
+            pos_vec: [0.0; DIMS],
+            vel_vec: [0.0; DIMS],
+            mass: 1.0,
+            charge: 0.0,
+            radius: 0.0
+        };
+        for xi in body.pos_vec.iter_mut() {
+            *xi = pos_gen.ind_sample(&mut rng);
+        }
+        sim.push_body(body);
+    }
+
+    let start = Instant::now();
+    sim.update();
+    let build = seconds(start.elapsed());
+
+    let mut fmm_sim = sim.clone();
+    fmm_sim.params.solver = Solver::Fmm;
+
+    let start = Instant::now();
+    sim.step();
+    let step = seconds(start.elapsed());
+
+    let start = Instant::now();
+    fmm_sim.step();
+    let fmm_step = seconds(start.elapsed());
+
+    println!("{}\t{:.3} s\t{:.3} s\t{:.3} s", num_bodies, build, step, fmm_step);
+}
+
+fn main() {
+    let sizes = env::args()
+        .skip(1)
+        .filter_map(|arg| arg.parse::<usize>().ok())
+        .collect::<Vec<usize>>();
+
+    let sizes = if sizes.is_empty() {
+        vec![10_000, 100_000, 1_000_000]
+    } else {
+        sizes
+    };
+
+    println!("# {} threads", Params::default().num_threads);
+    println!("bodies\tbuild\tstep\tfmm step");
+    for n in sizes {
+        bench(n);
+    }
+}
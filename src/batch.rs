@@ -0,0 +1,189 @@
+// batch runs a simulation without a window. It steps the tree for a
+// fixed number of steps or until some simulation time, writing a row
+// of diagnostics and (optionally) a snapshot of every body as it
+// goes, along with a line for every body that leaves the root region
+// and gets dropped. None of this touches piston, so it runs fine on machines
+// without a display.
+
+use super::physics::analysis;
+use super::simulation::Simulation;
+use super::snapshot;
+
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::io::{self, BufWriter};
+use std::path::PathBuf;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stop {
+    Steps(usize),
+    Time(f64)
+}
+
+#[derive(Clone, Debug)]
+pub struct Batch {
+    pub stop: Stop,
+    // write a diagnostics row every this many steps (0 for never)
+    pub diagnostics_every: usize,
+    // write a snapshot every this many steps (0 for never)
+    pub snapshot_every: usize,
+    pub out_dir: PathBuf
+}
+
+impl Batch {
+
+    pub fn new(stop: Stop, out_dir: PathBuf) -> Batch {
+        Batch {
+            stop,
+            diagnostics_every: 1,
+            snapshot_every: 0,
+            out_dir
+        }
+    }
+
+    fn done<const D: usize>(&self, steps: usize, sim: &Simulation<D>) -> bool {
+        match self.stop {
+            Stop::Steps(n) => steps >= n,
+            // half a step of slack so that rounding in sim.time
+            // doesn't cost us an extra step at the end
+            Stop::Time(t) => {
+                let dt = if sim.last_dt > 0.0 { sim.last_dt } else { sim.params.dt };
+                sim.time + 0.5 * dt >= t
+            }
+        }
+    }
+
+    // run steps the passed simulation until the stop condition is
+    // met, returning the number of steps taken. The simulation
+    // should already have had its bodies pushed; we call update
+    // ourselves before the first step.
+    pub fn run<const D: usize>(&self, sim: &mut Simulation<D>) -> io::Result<usize> {
+        fs::create_dir_all(&self.out_dir)?;
+
+        let mut diagnostics = BufWriter::new(
+            File::create(self.out_dir.join("diagnostics.txt"))?
+        );
+        writeln!(diagnostics, "# step\ttime\tbodies\tkinetic\tpotential\ttotal\tdt\tlost\tcollisions")?;
+
+        // escapes are written in the same form as a snapshot, with the
+        // time each body left in front
+        let mut escapes = BufWriter::new(
+            File::create(self.out_dir.join("escapes.txt"))?
+        );
+        writeln!(escapes, "# time\tmass\tposition\tvelocity\tcharge\tradius")?;
+        let mut escaped = sim.escapes.len();
+
+        sim.update();
+
+        let mut steps = 0;
+        loop {
+            if self.diagnostics_every > 0 && steps % self.diagnostics_every == 0 {
+                self.write_diagnostics(&mut diagnostics, steps, sim)?;
+            }
+            if self.snapshot_every > 0 && steps % self.snapshot_every == 0 {
+                self.write_snapshot(steps, sim)?;
+            }
+
+            if self.done(steps, sim) {
+                break;
+            }
+
+            sim.step();
+            steps += 1;
+
+            for escape in sim.escapes[escaped..].iter() {
+                let body = &escape.body;
+                write!(escapes, "{}\t{}", escape.time, body.mass)?;
+                for xi in body.pos_vec.iter().chain(body.vel_vec.iter()) {
+                    write!(escapes, "\t{}", xi)?;
+                }
+                writeln!(escapes, "\t{}\t{}", body.charge, body.radius)?;
+            }
+            escaped = sim.escapes.len();
+        }
+
+        diagnostics.flush()?;
+        escapes.flush()?;
+        Ok(steps)
+    }
+
+    fn write_diagnostics<W: Write, const D: usize>(&self, out: &mut W, steps: usize, sim: &Simulation<D>) -> io::Result<()> {
+        // the potential comes from the tree (unless the solver is
+        // Direct), since summing over every pair for every row would
+        // make the whole run O(N^2)
+        let masses = sim.bodies();
+        let kinetic = analysis::kinetic_energy(masses);
+        let potential = sim.potential_energy();
+
+        // dt is the step that got us here (0 for the initial state),
+        // lost is the energy lost in collisions so far, which total
+        // plus lost should be conserving, and collisions is how many
+        // there were during that step
+        writeln!(
+            out, "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            steps, sim.time, masses.len(), kinetic, potential, kinetic + potential,
+            sim.last_dt, sim.lost_energy, sim.collisions
+        )
+    }
+
+    fn write_snapshot<const D: usize>(&self, steps: usize, sim: &Simulation<D>) -> io::Result<()> {
+        let path = self.out_dir.join(format!("snapshot_{:06}.txt", steps));
+        snapshot::write_snapshot(path, sim.time, sim.bodies())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use data::*;
+    use tree::*;
+    use config::Params;
+
+    use std::env;
+
+    #[test]
+    fn test_batch_run() {
+        let mut sim = Simulation::new(Params::default());
+        sim.push_body(Body {
+            pos_vec: [-100.0; DIMS],
+            vel_vec: [0.0; DIMS],
+            mass: 10.0,
+            charge: 0.0,
+            radius: 0.0
+        });
+        sim.push_body(Body {
+            pos_vec: [100.0; DIMS],
+            vel_vec: [0.0; DIMS],
+            mass: 10.0,
+            charge: 0.0,
+            radius: 0.0
+        });
+
+        let out_dir = env::temp_dir().join("barnes_rust_test_batch_run");
+        let mut batch = Batch::new(Stop::Time(4.0 * sim.params.dt), out_dir.clone());
+        batch.snapshot_every = 2;
+
+        assert_eq!(batch.run(&mut sim).unwrap(), 4);
+
+        let (time, masses) = snapshot::read_snapshot::<_, DIMS>(
+            out_dir.join("snapshot_000004.txt")
+        ).unwrap();
+        assert_eq!(time, sim.time);
+        assert_eq!(masses.len(), 2);
+        assert!(!out_dir.join("snapshot_000003.txt").exists());
+
+        let mut diagnostics = String::new();
+        File::open(out_dir.join("diagnostics.txt")).unwrap()
+            .read_to_string(&mut diagnostics).unwrap();
+        // header plus steps 0 through 4
+        assert_eq!(diagnostics.lines().count(), 6);
+
+        // and nobody went anywhere
+        let mut escapes = String::new();
+        File::open(out_dir.join("escapes.txt")).unwrap()
+            .read_to_string(&mut escapes).unwrap();
+        assert_eq!(escapes.lines().count(), 1);
+
+        fs::remove_dir_all(&out_dir).unwrap();
+    }
+}
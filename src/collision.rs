@@ -0,0 +1,257 @@
+// Bodies with a radius can collide. What happens when two of them
+// touch is up to params.collisions: with None they pass through each
+// other as point masses (as they always have), and with Merge they
+// stick together into one body, keeping their total mass, charge and
+// momentum, with a radius that keeps their total volume. Merging
+// always throws some energy away, the kinetic energy of the pair's
+// relative motion plus whatever binding energy they had, and the
+// Simulation keeps a tally of it (see Simulation::lost_energy) so that
+// the energy diagnostics still add up.
+//
+// With Bounce they're hard spheres instead: two touching bodies that
+// are heading into each other get kicked apart along the line between
+// their centres, leaving with params.restitution times the speed they
+// came in with (1 for an elastic collision, 0 for them to stop dead
+// relative to each other). Whatever kinetic energy that costs goes
+// into the same tally.
+//
+// The tree does the broad phase: a body can only be touching bodies
+// in regions that come within its radius plus the biggest radius of
+// all, so everywhere else gets skipped.
+
+use super::tree::{Body, Region, Tree};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Collisions {
+    None,
+    Merge,
+    Bounce
+}
+
+pub const COLLISIONS: [Collisions; 3] = [
+    Collisions::None,
+    Collisions::Merge,
+    Collisions::Bounce
+];
+
+impl Collisions {
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Collisions::None => "none",
+            Collisions::Merge => "merge",
+            Collisions::Bounce => "bounce"
+        }
+    }
+}
+
+named_enum!(Collisions, COLLISIONS, "collisions");
+
+// touching returns every pair (i, j) of bodies, with i < j, that are
+// overlapping, i.e. closer together than their radii added up. Only
+// bodies the tree holds get looked at, so it has to be up to date.
+pub fn touching<const D: usize>(bodies: &[Body<D>], tree: &Tree<D>) -> Vec<(usize, usize)> {
+    let mut pairs = Vec::new();
+    let biggest = bodies.iter().fold(0.0, |max: f64, body| max.max(body.radius));
+    if biggest == 0.0 || tree.root().is_none() {
+        return pairs;
+    }
+
+    let mut stack = Vec::new();
+    for (i, body) in bodies.iter().enumerate() {
+        if body.radius == 0.0 {
+            continue;
+        }
+        let reach = body.radius + biggest;
+
+        stack.push(0);
+        while let Some(n) = stack.pop() {
+            let reg = &tree.regions[n];
+
+            // how far the body is from the nearest point of the region
+            let mut d2 = 0.0;
+            for (ck, xk) in reg.coord_vec.iter().zip(body.pos_vec.iter()) {
+                let out = (ck - xk).abs() - reg.half_length;
+                if out > 0.0 {
+                    d2 += out * out;
+                }
+            }
+            if d2 > reach * reach {
+                continue;
+            }
+
+            match (reg.children, reg.body) {
+                (Some(first), _) => stack.extend(first..first + Region::<D>::NUM_CHILDREN),
+                // every pair gets found from both ends, so we only keep
+                // it from the lower one, unless that's a point mass,
+                // which we never look from at all
+                (None, Some(j)) if j > i || (j < i && bodies[j].radius == 0.0) => {
+                    let other = &bodies[j];
+                    let r = body.radius + other.radius;
+                    if body.squared_dist_to(other) < r * r {
+                        pairs.push((i.min(j), i.max(j)));
+                    }
+                },
+                _ => ()
+            }
+        }
+    }
+    pairs.sort();
+    pairs
+}
+
+// merged_radius is the radius of the body two touching bodies merge
+// into, which holds as much volume as both of them did.
+pub fn merged_radius<const D: usize>(r1: f64, r2: f64) -> f64 {
+    (r1.powi(D as i32) + r2.powi(D as i32)).powf(1.0 / D as f64)
+}
+
+// bounce kicks two touching bodies apart if they're heading into each
+// other, with coefficient of restitution e, and returns the kinetic
+// energy that cost. Bodies that are already moving apart are left
+// alone, which returns None. A massless body bounces off a massive one
+// as if it were a wall.
+pub fn bounce<const D: usize>(b1: &mut Body<D>, b2: &mut Body<D>, e: f64) -> Option<f64> {
+    let den = b1.mass + b2.mass;
+    let r = b1.sq_magnitude(&b1.vec_rel(b2)).sqrt();
+    if den == 0.0 || r == 0.0 {
+        return None;
+    }
+
+    // the unit vector from b1 to b2, and how fast they're closing in
+    // along it
+    let mut n = [0.0; D];
+    let mut closing = 0.0;
+    for (k, nk) in n.iter_mut().enumerate() {
+        *nk = (b2.pos_vec[k] - b1.pos_vec[k]) / r;
+        closing += (b1.vel_vec[k] - b2.vel_vec[k]) * *nk;
+    }
+    if closing <= 0.0 {
+        return None;
+    }
+
+    let dv = (1.0 + e) * closing;
+    for (k, nk) in n.iter().enumerate() {
+        b1.vel_vec[k] -= dv * b2.mass / den * nk;
+        b2.vel_vec[k] += dv * b1.mass / den * nk;
+    }
+    Some(0.5 * b1.mass * b2.mass / den * (1.0 - e * e) * closing * closing)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::Params;
+
+    fn ball(x: f64, y: f64, radius: f64) -> Body<2> {
+        Body { pos_vec: [x, y], vel_vec: [0.0; 2], mass: 1.0, charge: 0.0, radius }
+    }
+
+    #[test]
+    fn test_touching() {
+        let params = Params::default();
+        let bodies = vec![
+            ball(0.0, 0.0, 1.0),
+            ball(1.5, 0.0, 1.0),
+            ball(5.0, 5.0, 1.0),
+            ball(5.8, 5.0, 0.0),
+            ball(-300.0, 200.0, 0.5),
+            // a point mass only touches bodies with a radius
+            ball(-300.0, 200.2, 0.0)
+        ];
+        let mut tree = Tree::new();
+        tree.build(&bodies, [0.0; 2], params.max_len, &params);
+
+        assert_eq!(touching(&bodies, &tree), vec![(0, 1), (2, 3), (4, 5)]);
+
+        // and it doesn't matter which of the two comes first
+        let point_first = vec![ball(0.0, 0.0, 0.0), ball(0.5, 0.0, 1.0)];
+        tree.build(&point_first, [0.0; 2], params.max_len, &params);
+        assert_eq!(touching(&point_first, &tree), vec![(0, 1)]);
+
+        // point masses never touch at all
+        let points = bodies.iter().map(|b| Body { radius: 0.0, ..b.clone() }).collect::<Vec<Body<2>>>();
+        assert!(touching(&points, &tree).is_empty());
+    }
+
+    #[test]
+    fn test_touching_matches_every_pair() {
+        // a few hundred balls of different sizes packed into a small
+        // part of the root, so there are lots of overlaps
+        let params = Params::default();
+        let mut bodies = Vec::new();
+        for i in 0..400 {
+            let x = ((i * 37) % 101) as f64 - 50.0;
+            let y = ((i * 59) % 97) as f64 - 48.0;
+            bodies.push(ball(x + 0.01 * i as f64, y, 1.0 + (i % 5) as f64));
+        }
+        let mut tree = Tree::new();
+        tree.build(&bodies, [0.0; 2], params.max_len, &params);
+
+        let mut pairs = Vec::new();
+        for i in 0..bodies.len() {
+            for j in i + 1..bodies.len() {
+                let r = bodies[i].radius + bodies[j].radius;
+                if bodies[i].squared_dist_to(&bodies[j]) < r * r {
+                    pairs.push((i, j));
+                }
+            }
+        }
+        assert!(pairs.len() > 100);
+        assert_eq!(touching(&bodies, &tree), pairs);
+    }
+
+    #[test]
+    fn test_bounce() {
+        // equal masses hitting head on swap velocities
+        let mut b1 = Body { vel_vec: [3.0, 0.0], ..ball(0.0, 0.0, 1.0) };
+        let mut b2 = Body { vel_vec: [-1.0, 0.0], ..ball(1.5, 0.0, 1.0) };
+        assert_eq!(bounce(&mut b1, &mut b2, 1.0), Some(0.0));
+        assert_eq!(b1.vel_vec, [-1.0, 0.0]);
+        assert_eq!(b2.vel_vec, [3.0, 0.0]);
+
+        // and now that they're moving apart, nothing happens
+        assert_eq!(bounce(&mut b1, &mut b2, 1.0), None);
+        assert_eq!(b1.vel_vec, [-1.0, 0.0]);
+
+        // a glancing blow only changes the velocity along the line
+        // between the centres, and an inelastic one loses energy
+        let mut b1 = Body { vel_vec: [2.0, 5.0], mass: 3.0, ..ball(0.0, 0.0, 1.0) };
+        let mut b2 = Body { vel_vec: [0.0, 5.0], ..ball(1.5, 0.0, 1.0) };
+        let momentum = |b1: &Body<2>, b2: &Body<2>| [
+            b1.mass * b1.vel_vec[0] + b2.mass * b2.vel_vec[0],
+            b1.mass * b1.vel_vec[1] + b2.mass * b2.vel_vec[1]
+        ];
+        let energy = |b: &Body<2>| 0.5 * b.mass * b.sq_magnitude(&b.vel_vec);
+        let (p0, e0) = (momentum(&b1, &b2), energy(&b1) + energy(&b2));
+
+        let lost = bounce(&mut b1, &mut b2, 0.5).unwrap();
+        assert_eq!(momentum(&b1, &b2), p0);
+        assert!((energy(&b1) + energy(&b2) + lost - e0).abs() < 1e-12);
+        assert!((lost - 0.5 * 0.75 * 0.75 * 4.0).abs() < 1e-12);
+        assert!((b2.vel_vec[0] - b1.vel_vec[0] - 0.5 * 2.0).abs() < 1e-12);
+        assert_eq!((b1.vel_vec[1], b2.vel_vec[1]), (5.0, 5.0));
+
+        // a massless body bounces straight back off a massive one
+        let mut b1 = Body { vel_vec: [2.0, 0.0], mass: 0.0, ..ball(0.0, 0.0, 1.0) };
+        let mut b2 = ball(1.5, 0.0, 1.0);
+        bounce(&mut b1, &mut b2, 1.0);
+        assert_eq!(b1.vel_vec, [-2.0, 0.0]);
+        assert_eq!(b2.vel_vec, [0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_merged_radius() {
+        assert!((merged_radius::<3>(1.0, 1.0) - 2.0f64.powf(1.0 / 3.0)).abs() < 1e-12);
+        assert!((merged_radius::<2>(3.0, 4.0) - 5.0).abs() < 1e-12);
+        assert_eq!(merged_radius::<2>(2.0, 0.0), 2.0);
+    }
+
+    #[test]
+    fn test_collisions_names() {
+        for collisions in COLLISIONS.iter() {
+            assert_eq!(collisions.name().parse::<Collisions>(), Ok(*collisions));
+        }
+        assert!("explode".parse::<Collisions>().is_err());
+    }
+}
@@ -0,0 +1,353 @@
+// Params holds every physical and numerical knob of a run. The
+// compile-time constants in data.rs (and G in physics.rs) are only
+// the defaults now; a Params can be read from a config file and then
+// have individual values overridden, e.g. from the command line.
+//
+// Config files are lists of `key = value` lines, with # starting a
+// comment, so a file looks like
+//
+//     # a tighter opening angle and a smaller step
+//     theta = 0.3
+//     dt = 0.0001
+//
+// and the keys are just the field names below.
+
+use super::data::*;
+use super::physics::{G, Forces, Kernel, Opening, PostNewtonian};
+use super::integrator::{Scheme, Timestep};
+use super::law::Law;
+use super::collision::Collisions;
+use super::simulation::{Boundary, Root, Solver};
+
+use num_cpus;
+
+use std::fs::File;
+use std::io::prelude::*;
+use std::io;
+use std::path::Path;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Params {
+    // opening angle for the tree walk
+    pub theta: f64,
+    // how many terms of the multipole expansion of a region get used
+    // when it's far enough away: 1 for just its mass at its com, 2 to
+    // add the quadrupole, 3 for the octupole too
+    pub multipole_order: usize,
+    // whether the forces come from walking the tree for every body,
+    // from the fast multipole method (see fmm), or from summing over
+    // every pair directly
+    pub solver: Solver,
+    // which test decides whether a region is far enough away to take
+    // as a whole (see physics::Opening), and the error the relative
+    // ones allow, as a fraction of a body's acceleration
+    pub opening: Opening,
+    pub opening_tolerance: f64,
+    pub dt: f64,
+    pub g: f64,
+    // half-length of the root region
+    pub max_len: f64,
+    // regions won't split below this half-length, and forces between
+    // masses closer than this are dropped
+    pub min_len: f64,
+
+    // ranges used by the generators
+    pub max_vel: f64,
+    pub max_mass: f64,
+    pub gamma_shape: f64,
+    pub gamma_scale: f64,
+    pub gamma_shape_tf: f64,
+    pub gamma_scale_tf: f64,
+    pub normal_mean: f64,
+    pub normal_std_dev: f64,
+    pub normal_mean_tf: f64,
+    pub normal_std_dev_tf: f64,
+
+    pub num_threads: usize,
+
+    // which integrator Simulation::step uses
+    pub integrator: Scheme,
+    // for the block integrator: the smallest step a body can take is
+    // dt / 2^block_levels, and block_eta scales the step each body
+    // asks for
+    pub block_levels: usize,
+    pub block_eta: f64,
+
+    // how the size of each step gets chosen; unless it's fixed, dt is
+    // the largest step and dt_min the smallest
+    pub timestep: Timestep,
+    pub dt_min: f64,
+    pub timestep_eta: f64,
+
+    // the softening applied to gravity (see physics::Kernel) and its
+    // length, which is also what the accel timestep resolves
+    pub kernel: Kernel,
+    pub softening: f64,
+    // the largest relative change in energy allowed in one step with
+    // the energy timestep
+    pub energy_tolerance: f64,
+
+    // the force between masses (see law::ForceLaw), the length the
+    // yukawa law is screened over, and the power the power law falls
+    // off with
+    pub force_law: Law,
+    pub screening_length: f64,
+    pub force_exponent: f64,
+
+    // which of gravity and Coulomb forces act (see physics::Forces),
+    // and the Coulomb constant
+    pub forces: Forces,
+    pub coulomb_k: f64,
+
+    // which post-Newtonian corrections get added to gravity between
+    // pairs of bodies (see physics::PostNewtonian), and the speed of
+    // light they're expanded in
+    pub post_newtonian: PostNewtonian,
+    pub speed_of_light: f64,
+
+    // what happens at the edges of the root region, and whether the
+    // root is the fixed box of max_len or gets fitted to the bodies on
+    // every rebuild (see simulation::Root)
+    pub boundary: Boundary,
+    pub root: Root,
+
+    // what happens when bodies with a radius touch (see collision),
+    // and the coefficient of restitution when they bounce
+    pub collisions: Collisions,
+    pub restitution: f64
+}
+
+impl Default for Params {
+    fn default() -> Params {
+        Params {
+            theta: THETA,
+            multipole_order: MULTIPOLE_ORDER,
+            solver: Solver::Tree,
+            opening: Opening::Geometric,
+            opening_tolerance: OPENING_TOLERANCE,
+            dt: DT,
+            g: G,
+            max_len: MAX_LEN,
+            min_len: MIN_LEN,
+            max_vel: MAX_VEL,
+            max_mass: MAX_MASS,
+            gamma_shape: GAMMA_SHAPE,
+            gamma_scale: GAMMA_SCALE,
+            gamma_shape_tf: GAMMA_SHAPE_TF,
+            gamma_scale_tf: GAMMA_SCALE_TF,
+            normal_mean: NORMAL_MEAN,
+            normal_std_dev: NORMAL_STD_DEV,
+            normal_mean_tf: NORMAL_MEAN_TF,
+            normal_std_dev_tf: NORMAL_STD_DEV_TF,
+            num_threads: num_cpus::get(),
+            integrator: Scheme::Leapfrog,
+            block_levels: BLOCK_LEVELS,
+            block_eta: BLOCK_ETA,
+            timestep: Timestep::Fixed,
+            dt_min: DT_MIN,
+            timestep_eta: TIMESTEP_ETA,
+            kernel: Kernel::None,
+            softening: SOFTENING,
+            energy_tolerance: ENERGY_TOLERANCE,
+            force_law: Law::Newtonian,
+            screening_length: SCREENING_LENGTH,
+            force_exponent: FORCE_EXPONENT,
+            forces: Forces::Gravity,
+            coulomb_k: COULOMB_K,
+            post_newtonian: PostNewtonian::None,
+            speed_of_light: SPEED_OF_LIGHT,
+            boundary: Boundary::Delete,
+            root: Root::Bounds,
+            collisions: Collisions::None,
+            restitution: RESTITUTION
+        }
+    }
+}
+
+impl Params {
+
+    // Read a config file on top of the defaults.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Params> {
+        let mut params = Params::default();
+        params.load_file(path)?;
+        Ok(params)
+    }
+
+    // Read a config file on top of the calling params, so that
+    // anything the file doesn't mention is left alone.
+    pub fn load_file<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let mut contents = String::new();
+        File::open(path)?.read_to_string(&mut contents)?;
+        self.load_str(&contents)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn load_str(&mut self, contents: &str) -> Result<(), String> {
+        for (num, line) in contents.lines().enumerate() {
+            let line = match line.find('#') {
+                None => line,
+                Some(i) => &line[..i]
+            }.trim();
+
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut pair = line.splitn(2, '=');
+            match (pair.next(), pair.next()) {
+                (Some(key), Some(value)) => {
+                    self.set(key.trim(), value.trim())
+                        .map_err(|e| format!("line {}: {}", num + 1, e))?
+                },
+                _ => return Err(format!("line {}: expected `key = value`", num + 1))
+            }
+        }
+        Ok(())
+    }
+
+    // Set a single parameter by name, e.g. set("theta", "0.7").
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        // the parameters that aren't plain floats
+        match key {
+            "num_threads" => {
+                self.num_threads = value.parse::<usize>()
+                    .map_err(|_| format!("bad value {:?} for num_threads", value))?;
+                return Ok(());
+            },
+            "multipole_order" => {
+                self.multipole_order = value.parse::<usize>()
+                    .map_err(|_| format!("bad value {:?} for multipole_order", value))?;
+                return Ok(());
+            },
+            "block_levels" => {
+                let levels = value.parse::<usize>()
+                    .map_err(|_| format!("bad value {:?} for block_levels", value))?;
+                if levels > MAX_BLOCK_LEVELS {
+                    return Err(format!(
+                        "block_levels can't be more than {}, got {}", MAX_BLOCK_LEVELS, levels
+                    ));
+                }
+                self.block_levels = levels;
+                return Ok(());
+            },
+            "integrator" => {
+                self.integrator = value.parse::<Scheme>()?;
+                return Ok(());
+            },
+            "solver" => {
+                self.solver = value.parse::<Solver>()?;
+                return Ok(());
+            },
+            "opening" => {
+                self.opening = value.parse::<Opening>()?;
+                return Ok(());
+            },
+            "force_law" => {
+                self.force_law = value.parse::<Law>()?;
+                return Ok(());
+            },
+            "forces" => {
+                self.forces = value.parse::<Forces>()?;
+                return Ok(());
+            },
+            "boundary" => {
+                self.boundary = value.parse::<Boundary>()?;
+                return Ok(());
+            },
+            "root" => {
+                self.root = value.parse::<Root>()?;
+                return Ok(());
+            },
+            "collisions" => {
+                self.collisions = value.parse::<Collisions>()?;
+                return Ok(());
+            },
+            "post_newtonian" => {
+                self.post_newtonian = value.parse::<PostNewtonian>()?;
+                return Ok(());
+            },
+            "kernel" => {
+                self.kernel = value.parse::<Kernel>()?;
+                return Ok(());
+            },
+            "timestep" => {
+                self.timestep = value.parse::<Timestep>()?;
+                return Ok(());
+            },
+            _ => ()
+        }
+
+        let field = match key {
+            "theta" => &mut self.theta,
+            "opening_tolerance" => &mut self.opening_tolerance,
+            "dt" => &mut self.dt,
+            "g" => &mut self.g,
+            "max_len" => &mut self.max_len,
+            "min_len" => &mut self.min_len,
+            "max_vel" => &mut self.max_vel,
+            "max_mass" => &mut self.max_mass,
+            "gamma_shape" => &mut self.gamma_shape,
+            "gamma_scale" => &mut self.gamma_scale,
+            "gamma_shape_tf" => &mut self.gamma_shape_tf,
+            "gamma_scale_tf" => &mut self.gamma_scale_tf,
+            "normal_mean" => &mut self.normal_mean,
+            "normal_std_dev" => &mut self.normal_std_dev,
+            "normal_mean_tf" => &mut self.normal_mean_tf,
+            "normal_std_dev_tf" => &mut self.normal_std_dev_tf,
+            "block_eta" => &mut self.block_eta,
+            "dt_min" => &mut self.dt_min,
+            "timestep_eta" => &mut self.timestep_eta,
+            "softening" => &mut self.softening,
+            "energy_tolerance" => &mut self.energy_tolerance,
+            "screening_length" => &mut self.screening_length,
+            "force_exponent" => &mut self.force_exponent,
+            "coulomb_k" => &mut self.coulomb_k,
+            "speed_of_light" => &mut self.speed_of_light,
+            "restitution" => &mut self.restitution,
+            _ => return Err(format!("unknown parameter {:?}", key))
+        };
+
+        *field = value.parse::<f64>()
+            .map_err(|_| format!("bad value {:?} for {}", value, key))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_str() {
+        let mut params = Params::default();
+        params.load_str("
+            # comments and blank lines are skipped
+
+            theta = 0.3
+            dt=0.001   # trailing comments too
+            num_threads = 4
+            integrator = yoshida
+        ").unwrap();
+
+        assert_eq!(params.theta, 0.3);
+        assert_eq!(params.dt, 0.001);
+        assert_eq!(params.num_threads, 4);
+        assert_eq!(params.integrator, Scheme::Yoshida);
+        assert_eq!(params.g, G);
+    }
+
+    #[test]
+    fn test_set_errors() {
+        let mut params = Params::default();
+        assert!(params.set("theta", "wide").is_err());
+        assert!(params.set("phi", "0.5").is_err());
+        assert!(params.set("integrator", "verlet").is_err());
+        assert!(params.load_str("theta 0.5").is_err());
+        assert!(params.set("block_levels", "64").is_err());
+        assert!(params.load_str("block_levels = 21").is_err());
+        assert_eq!(params, Params::default());
+
+        params.set("block_levels", "20").unwrap();
+        assert_eq!(params.block_levels, MAX_BLOCK_LEVELS);
+    }
+}
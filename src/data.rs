@@ -1,14 +1,44 @@
 pub extern crate rand;
 
-use super::*;
-
-use std::sync::{Mutex, Arc};
-use std::thread;
-
+// DIMS is the number of dimensions the command line simulates unless
+// it's told otherwise. The simulation itself is generic over the
+// dimension, so this is only a default.
 pub const DIMS: usize = 2;
+
+// Everything below DIMS is only a default too: the values a run
+// actually uses live in config::Params, which starts from these.
 pub const THETA: f64 = 0.5;
+pub const MULTIPOLE_ORDER: usize = 2;
+pub const OPENING_TOLERANCE: f64 = 0.005;
 pub const DT: f64 = 0.00025;
 
+// block timesteps go down to DT / 2^BLOCK_LEVELS, and there can't be
+// more than MAX_BLOCK_LEVELS of them (a million substeps a step)
+pub const BLOCK_LEVELS: usize = 6;
+pub const MAX_BLOCK_LEVELS: usize = 20;
+pub const BLOCK_ETA: f64 = 0.02;
+
+// adaptive timesteps never go below DT_MIN
+pub const DT_MIN: f64 = DT / 1024.0;
+pub const TIMESTEP_ETA: f64 = 0.2;
+pub const SOFTENING: f64 = 1.0;
+pub const ENERGY_TOLERANCE: f64 = 1e-6;
+
+// for the force laws besides Newtonian gravity
+pub const SCREENING_LENGTH: f64 = 100.0;
+pub const FORCE_EXPONENT: f64 = 2.0;
+
+// the Coulomb constant; the same as G by default, so that a body with
+// equal mass and charge feels pushes as strong as the pulls
+pub const COULOMB_K: f64 = 16000.0;
+
+// the speed of light, for post-Newtonian corrections
+pub const SPEED_OF_LIGHT: f64 = 300_000.0;
+
+// how much of their approach speed bouncing bodies leave with (1 for
+// perfectly elastic collisions)
+pub const RESTITUTION: f64 = 1.0;
+
 // approximate radius of the milky way
 //pub const MAX_LEN: f64 = 500_000_000_000_000_000_000.0;
 
@@ -33,40 +63,13 @@ pub const NORMAL_STD_DEV: f64 = 0.5 * NORMAL_MEAN;
 pub const NORMAL_MEAN_TF: f64 = 2.0 * NORMAL_MEAN;
 pub const NORMAL_STD_DEV_TF: f64 = NORMAL_STD_DEV;
 
-pub static mut NUM_THREADS: i64 = 20;
-
-pub struct TreeWrapper {
-    pub tree: Region
-}
-
-// TODO: make our organization here more intelligent. Should probably
-// offload most statics  to their own dedicated module, along with
-// static generation. Maybe data.rs?
-pub mod gen_mult {
-    pub fn populate_mult(n: usize, mult: f64) -> Vec<Vec<f64>> {
-        if n <= 0 {
-            return vec![vec![mult]];
-        }
-
-        let mut v1: Vec<Vec<f64>> = populate_mult(n - 1, -1.0);
-        v1.extend(populate_mult(n - 1, 1.0));
-
-        if mult != 0.0 {
-            for i in 0..v1.len() {
-                v1[i].push(mult);
-            }
-        }
-
-        v1
-    }
-}
-
 pub mod generate {
 
     use data::rand::*;
     use data::rand::distributions::{IndependentSample};
     use data::*;
     use tree::*;
+    use simulation::Simulation;
 
     use std::f64::consts::PI;
 
@@ -93,26 +96,37 @@ pub mod generate {
     // for an explanation on what this function is _supposed_ to be
     // doing
 
-    pub fn nd_vec_from_mag<T: IndependentSample<f64>>(
+    pub fn nd_vec_from_mag<T: IndependentSample<f64>, const D: usize>(
         mag: f64,
         t_generator: &T,
         final_theta: f64,
         mut rng: StdRng
-    ) -> Vec<f64> {
+    ) -> [f64; D] {
 
-        let mut vec = vec![0.0; DIMS];
+        let mut vec = [0.0; D];
+
+        // A circle is the smallest sphere with any angles in it. On a
+        // line there's only +mag and -mag, so the final theta just
+        // picks between them (and with no dimensions there's nothing
+        // to pick).
+        if D < 2 {
+            if let Some(x) = vec.first_mut() {
+                *x = mag * final_theta.cos().signum();
+            }
+            return vec;
+        }
 
         // The final case is special, so we don't iterate all the way
-        // through DIMS.
+        // through D.
 
         // This'll hold the running product of sin values of each of
         // the thetas defining our position
 
         let mut product: f64 = 1.0;
 
-        for i in 0..(DIMS-2) {
+        for vi in vec.iter_mut().take(D-2) {
             let theta = t_generator.ind_sample(&mut rng);
-            vec[i] = mag*(theta.cos())*product;
+            *vi = mag*(theta.cos())*product;
 
             // all future calculations will involve product of
             // preceding theta.sin() values, so we increment it here
@@ -126,44 +140,106 @@ pub mod generate {
         // involve it in special cases outside of our loop. Note that
         // the final r_vec entry involves just .sin()'s, no .cos()'s.
 
-        vec[DIMS-2] = mag * 1.0*final_theta.sin() * product;
-        vec[DIMS-1] = mag * 1.0*final_theta.cos() * product;
+        vec[D-2] = mag * 1.0*final_theta.sin() * product;
+        vec[D-1] = mag * 1.0*final_theta.cos() * product;
 
         // return vec
         vec
     }
 
-    pub fn gb_from_mags<T: IndependentSample<f64>>(
+    pub fn gb_from_mags<T: IndependentSample<f64>, const D: usize>(
         t_f1: f64,
         t_f2: f64,
         p_mag: f64,
         v_mag: f64,
         m: f64,
         t_generator: T,
-    ) -> Arc<Mutex<Body>> {
-        let mut rng1 = rand::StdRng::new().unwrap();
-        let mut rng2 = rand::StdRng::new().unwrap();
+    ) -> Body<D> {
+        let rng1 = rand::StdRng::new().unwrap();
+        let rng2 = rand::StdRng::new().unwrap();
 
         let pos = nd_vec_from_mag(p_mag, &t_generator, t_f1, rng1);
         let vel = nd_vec_from_mag(v_mag, &t_generator, t_f2, rng2);
 
-        let body = Body {
+        Body {
             pos_vec: pos,
             vel_vec: vel,
-            mass: m + 1.0
-        };
+            mass: m + 1.0,
+            charge: 0.0,
+            radius: 0.0
+        }
+    }
 
-        Arc::new(Mutex::new(body))
+    // planar puts (x, y) in the first two coordinates of a
+    // D-dimensional vector, leaving the rest at 0. The hand-built
+    // scenarios below all happen in that plane, so they can't be set
+    // up with fewer than 2 dimensions (gt_by_name checks).
+    pub fn planar<const D: usize>(x: f64, y: f64) -> [f64; D] {
+        assert!(D >= 2, "planar needs at least 2 dims, not {}", D);
+        let mut vec = [0.0; D];
+        vec[0] = x;
+        vec[1] = y;
+        vec
+    }
+
+    // The names of every scenario gt_by_name knows about, so that
+    // frontends can list them.
+    pub const SCENARIOS: [&str; 10] = [
+        "all_ranges",
+        "two_body",
+        "binary_system",
+        "scattering",
+        "rutherford_scattering",
+        "binary_scattering",
+        "coulomb_scattering",
+        "plasma",
+        "all_gamma",
+        "all_normal"
+    ];
+
+    // The scenarios built with planar.
+    const PLANAR_SCENARIOS: [&str; 6] = [
+        "two_body",
+        "binary_system",
+        "scattering",
+        "rutherford_scattering",
+        "binary_scattering",
+        "coulomb_scattering"
+    ];
+
+    // gt_by_name runs the gt_ generator with the passed name (minus
+    // the gt_ prefix). Scenarios with a fixed set of bodies ignore
+    // num_bodies.
+    pub fn gt_by_name<const D: usize>(sim: &mut Simulation<D>, name: &str, num_bodies: usize) -> Result<(), String> {
+        if D < 2 && PLANAR_SCENARIOS.contains(&name) {
+            return Err(format!("scenario {:?} needs at least 2 dims, not {}", name, D));
+        }
+        match name {
+            "all_ranges" => gt_all_ranges(sim, num_bodies),
+            "two_body" => gt_two_body(sim),
+            "binary_system" => gt_binary_system(sim),
+            "scattering" => gt_scattering(sim, num_bodies),
+            "rutherford_scattering" => gt_rutherford_scattering(sim, num_bodies),
+            "binary_scattering" => gt_binary_scattering(sim, num_bodies),
+            "coulomb_scattering" => gt_coulomb_scattering(sim, num_bodies),
+            "plasma" => gt_plasma(sim, num_bodies),
+            "all_gamma" => gt_all_gamma(sim, num_bodies),
+            "all_normal" => gt_all_normal(sim, num_bodies),
+            _ => return Err(format!(
+                "unknown scenario {:?}, expected one of {}", name, SCENARIOS.join(", ")
+            ))
+        };
+        Ok(())
     }
 
     // gt is for gen_tree
-    pub fn gt_all_ranges(num_bodies: usize) {
+    pub fn gt_all_ranges<const D: usize>(sim: &mut Simulation<D>, num_bodies: usize) {
         use data::rand::distributions::*;
         // let mut seeder = get_seeder_rng();
 
-        let m_gen = Range::new(0.0, MAX_MASS);
-        let p_mag_gen = Range::new(0.0, 0.7*MAX_LEN);
-        let v_mag_gen = Range::new(0.2*MAX_VEL, 0.5*MAX_VEL);
+        let m_gen = Range::new(0.0, sim.params.max_mass);
+        let p_mag_gen = Range::new(0.0, 0.7*sim.params.max_len);
+        let v_mag_gen = Range::new(0.2*sim.params.max_vel, 0.5*sim.params.max_vel);
         let t_gen = Range::new(0.0, PI);
         let t_f_gen = &Range::new(0.0, 2.0*PI);
 
@@ -171,7 +247,7 @@ pub mod generate {
 
         for _ in 0..num_bodies {
 
-            Region::push_body_global(
+            sim.push_body(
                 gb_from_mags(
                     t_f_gen.ind_sample(&mut rng),
                     t_f_gen.ind_sample(&mut rng),
@@ -185,35 +261,37 @@ pub mod generate {
 
     }
 
-    pub fn gt_two_body() {
-        Region::push_body_global(
-            Arc::new(Mutex::new(
+    pub fn gt_two_body<const D: usize>(sim: &mut Simulation<D>) {
+        sim.push_body(
             Body {
-                pos_vec: vec![-100.0, 0.0],
-                vel_vec: vec![0.0, 2000.0],
-                mass: 100000.01//m
+                pos_vec: planar(-100.0, 0.0),
+                vel_vec: planar(0.0, 2000.0),
+                mass: 100000.01, //m
+                charge: 0.0,
+                radius: 0.0
             }
-        )));
-        Region::push_body_global(
-            Arc::new(Mutex::new(
+        );
+        sim.push_body(
             Body {
-                pos_vec: vec![100.0, 0.0],
-                vel_vec: vec![0.0, -2000.0],
-                mass: 100000.0//m
+                pos_vec: planar(100.0, 0.0),
+                vel_vec: planar(0.0, -2000.0),
+                mass: 100000.0, //m
+                charge: 0.0,
+                radius: 0.0
             }
-        )));
+        );
     }
 
     //a system of two large objects, i.e. stars, with a number of
     //smaller objects injected around them
-    pub fn gt_binary_system() {
-        gt_two_body();
+    pub fn gt_binary_system<const D: usize>(sim: &mut Simulation<D>) {
+        gt_two_body(sim);
 
-        gt_all_ranges(300);
+        gt_all_ranges(sim, 300);
     }
 
     //inject masses horizontally
-    pub fn gt_scattering(num_bodies: usize) {
+    pub fn gt_scattering<const D: usize>(sim: &mut Simulation<D>, num_bodies: usize) {
         use data::rand::distributions::*;
 
         //impact parameters
@@ -221,64 +299,106 @@ pub mod generate {
         let mut rng = rand::StdRng::new().unwrap();
 
         let velocities = Range::new(750.0, 10000.0);
-        let offsets = Range::new(50.0, 150.0);
+        let x = -sim.params.max_len + 10.0;
 
         for _ in 0..num_bodies {
 
             let b = impact_parameters.ind_sample(&mut rng);
             let v = velocities.ind_sample(&mut rng);
-            // let x = offsets.ind_sample(&mut rng);
 
-            Region::push_body_global(
-                Arc::new(Mutex::new(
+            sim.push_body(
                 Body {
-                    pos_vec: vec![-MAX_LEN + 10.0, b],
-                    vel_vec: vec![v, 0.0],
-                    mass: 0.0000001//m
+                    pos_vec: planar(x, b),
+                    vel_vec: planar(v, 0.0),
+                    mass: 0.0000001, //m
+                    charge: 0.0,
+                    radius: 0.0
                 }
-            )));
+            );
         }
     }
 
     //scattering in a 1/r potential
-    pub fn gt_rutherford_scattering(num_bodies: usize) {
+    pub fn gt_rutherford_scattering<const D: usize>(sim: &mut Simulation<D>, num_bodies: usize) {
 
-        Region::push_body_global(
-            Arc::new(Mutex::new(
+        sim.push_body(
             Body {
-                pos_vec: vec![0.0, 0.0],
-                vel_vec: vec![0.0, 0.0],
-                mass: 100000.0//m
+                pos_vec: [0.0; D],
+                vel_vec: [0.0; D],
+                mass: 100000.0, //m
+                charge: 0.0,
+                radius: 0.0
             }
-        )));
+        );
 
-        gt_scattering(num_bodies);
+        gt_scattering(sim, num_bodies);
     }
 
     //scattering onto a binary system
-    pub fn gt_binary_scattering(num_bodies: usize) {
-        gt_two_body();
+    pub fn gt_binary_scattering<const D: usize>(sim: &mut Simulation<D>, num_bodies: usize) {
+        gt_two_body(sim);
 
-        gt_scattering(num_bodies);
+        gt_scattering(sim, num_bodies);
     }
 
+    //rutherford scattering with charges, where everything has as
+    //much charge as mass. With forces = coulomb the nucleus pushes
+    //just as hard as it pulls with gravity, so the projectiles get
+    //deflected away from it instead.
+    pub fn gt_coulomb_scattering<const D: usize>(sim: &mut Simulation<D>, num_bodies: usize) {
+        let first = sim.bodies.len();
+        gt_rutherford_scattering(sim, num_bodies);
 
+        for body in sim.bodies[first..].iter_mut() {
+            body.charge = body.mass;
+        }
+    }
 
-    pub fn gt_all_gamma(num_bodies: usize) {
+    //a neutral plasma: equal numbers of unit positive and negative
+    //charges of unit mass, spread over the middle of the root region
+    //with small random velocities. It needs forces = coulomb (or both)
+    //to do anything interesting.
+    pub fn gt_plasma<const D: usize>(sim: &mut Simulation<D>, num_bodies: usize) {
+        use data::rand::distributions::*;
+
+        let p_gen = Range::new(-0.5*sim.params.max_len, 0.5*sim.params.max_len);
+        let v_gen = Range::new(-0.01*sim.params.max_vel, 0.01*sim.params.max_vel);
+        let mut rng = rand::StdRng::new().unwrap();
+
+        for i in 0..num_bodies {
+            let mut body = Body {
+                pos_vec: [0.0; D],
+                vel_vec: [0.0; D],
+                mass: 1.0,
+                charge: if i % 2 == 0 { 1.0 } else { -1.0 },
+                radius: 0.0
+            };
+            for k in 0..D {
+                body.pos_vec[k] = p_gen.ind_sample(&mut rng);
+                body.vel_vec[k] = v_gen.ind_sample(&mut rng);
+            }
+            sim.push_body(body);
+        }
+    }
+
+
+
+    pub fn gt_all_gamma<const D: usize>(sim: &mut Simulation<D>, num_bodies: usize) {
         use data::rand::distributions::*;
         // let mut seeder = get_seeder_rng();
 
-        let m_gen = Gamma::new(GAMMA_SHAPE, GAMMA_SCALE);
-        let p_mag_gen = Gamma::new(GAMMA_SHAPE, GAMMA_SCALE);
-        let v_mag_gen = Gamma::new(GAMMA_SHAPE, GAMMA_SCALE);
-        let t_gen = Gamma::new(GAMMA_SHAPE, GAMMA_SCALE);
-        let t_f_gen = &Gamma::new(GAMMA_SHAPE_TF, GAMMA_SCALE_TF);
+        let (shape, scale) = (sim.params.gamma_shape, sim.params.gamma_scale);
+        let m_gen = Gamma::new(shape, scale);
+        let p_mag_gen = Gamma::new(shape, scale);
+        let v_mag_gen = Gamma::new(shape, scale);
+        let t_gen = Gamma::new(shape, scale);
+        let t_f_gen = &Gamma::new(sim.params.gamma_shape_tf, sim.params.gamma_scale_tf);
 
         let mut rng = rand::StdRng::new().unwrap();
 
         for _ in 0..num_bodies {
 
-            Region::push_body_global(
+            sim.push_body(
                 gb_from_mags(
                     t_f_gen.ind_sample(&mut rng),
                     t_f_gen.ind_sample(&mut rng),
@@ -290,31 +410,33 @@ pub mod generate {
             )
         }
 
-        Region::push_body_global(
-            Arc::new(Mutex::new(
+        sim.push_body(
             Body {
-                pos_vec: vec![-50.0; DIMS],
-                vel_vec: vec![0.0; DIMS],
-                mass: 10000.0//m
+                pos_vec: [-50.0; D],
+                vel_vec: [0.0; D],
+                mass: 10000.0, //m
+                charge: 0.0,
+                radius: 0.0
             }
-        )));
+        );
     }
 
-    pub fn gt_all_normal(num_bodies: usize) {
+    pub fn gt_all_normal<const D: usize>(sim: &mut Simulation<D>, num_bodies: usize) {
         use data::rand::distributions::*;
         // let mut seeder = get_seeder_rng();
 
-        let m_gen = Normal::new(NORMAL_MEAN, NORMAL_STD_DEV);
-        let p_mag_gen = Normal::new(NORMAL_MEAN, NORMAL_STD_DEV);
-        let v_mag_gen = Normal::new(NORMAL_MEAN, NORMAL_STD_DEV);
-        let t_gen = Normal::new(NORMAL_MEAN, NORMAL_STD_DEV);
-        let t_f_gen = Normal::new(NORMAL_MEAN_TF, NORMAL_STD_DEV_TF);
+        let (mean, std_dev) = (sim.params.normal_mean, sim.params.normal_std_dev);
+        let m_gen = Normal::new(mean, std_dev);
+        let p_mag_gen = Normal::new(mean, std_dev);
+        let v_mag_gen = Normal::new(mean, std_dev);
+        let t_gen = Normal::new(mean, std_dev);
+        let t_f_gen = Normal::new(sim.params.normal_mean_tf, sim.params.normal_std_dev_tf);
 
         let mut rng = rand::StdRng::new().unwrap();
 
         for _ in 0..num_bodies {
 
-            Region::push_body_global(
+            sim.push_body(
                 gb_from_mags(
                     t_f_gen.ind_sample(&mut rng),
                     t_f_gen.ind_sample(&mut rng),
@@ -326,68 +448,29 @@ pub mod generate {
             )
         }
     }
-
-    // fn push_body_global(body_arc: Arc<Mutex<Body>>) {
-    //     let match_me = TREE_POINTER.try_lock().unwrap().tree.add_queue.clone();
-    //     match match_me {
-
-    //         None => {
-    //             let mut add_me  = Vec::new();
-    //             add_me.push(body_arc);
-
-    //             TREE_POINTER.try_lock().unwrap().tree.add_queue = Some(add_me);
-    //         },
-
-    //         Some(_) => {
-    //             let mut queue =
-    //                 TREE_POINTER.try_lock().unwrap().tree.add_queue.clone().unwrap();
-    //             queue.push(body_arc);
-    //             TREE_POINTER.try_lock().unwrap().tree.add_queue = Some(queue);
-    //         }
-    //     }
-    // }
 }
 
-lazy_static! {
-
-    // I know, I know... Making a global variable is bad enough, but
-    // _this_... I mean, why not just construct it in main, and pass a
-    // reference to all functions that need it?? Answer: refactoring
-    // is a pain. Will fix later? Maybe with our data-generation
-    // scheme this might be ideal.
-
-    // TOFIX: redefine TREE_POINTER such that we can access the global
-    // region_vector without try_locking the Region itself. This will
-    // allow us to handle the add_queue and reg_vec separately, which
-    // will improve computation times.
-
-
-    //Stores a TreeWrapper that holds the global tree
-    pub static ref TREE_POINTER: Arc<Mutex<TreeWrapper>> =
-        Arc::new(
-            Mutex::new(
-                TreeWrapper {
-                    tree: Region {
-                        reg_vec: None,
-                        coord_vec: vec![0.0; DIMS],
-                        half_length: MAX_LEN,
-                        add_queue: Some(Vec::new()),
-                        com: None
-                    }
-                }
-            )
-        );
-
-    /*
-    // MULTIPLIERS is a static array that we'll use later to quickly
-    // determine the centers of subregions when we recurse. If we
-    // multiply each of the sub-arrays in MULTIPLIERS by the
-    // sidelength of our region, then _add_ those to our position
-    // vector for our starting region, it'll get us the center of our
-    // new region.
-    */
-
-    pub static ref MULTIPLIERS: Mutex<Vec<Vec<f64>>> = Mutex::new(
-        gen_mult::populate_mult(DIMS, 0.0)
-    );
+#[cfg(test)]
+mod tests {
+    use super::generate::*;
+    use super::rand::StdRng;
+    use super::rand::distributions::Range;
+    use config::Params;
+    use simulation::Simulation;
+
+    #[test]
+    fn test_generate_on_a_line() {
+        let t_gen = Range::new(0.0, 3.0);
+        let rng = StdRng::new().unwrap();
+        let vec = nd_vec_from_mag::<_, 1>(5.0, &t_gen, 3.0, rng);
+        assert_eq!(vec, [-5.0]);
+
+        // the planar scenarios don't fit on a line, the others do
+        let mut sim = Simulation::<1>::new(Params::default());
+        assert!(gt_by_name(&mut sim, "two_body", 10).is_err());
+        assert!(sim.bodies().is_empty());
+        gt_by_name(&mut sim, "all_normal", 10).unwrap();
+        gt_by_name(&mut sim, "all_ranges", 10).unwrap();
+        assert_eq!(sim.bodies().len(), 20);
+    }
 }
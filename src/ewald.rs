@@ -0,0 +1,333 @@
+// In a periodic box every body has an infinite lattice of copies, one
+// in every periodic replica of the box, and all of them pull on
+// everything. The tree walk only ever looks at the copy of a region
+// that's nearest (see Body::nearest_image); the pull of all the rest
+// is what's in here, worked out with Ewald summation the way Gadget
+// does it (Hernquist, Bouchet & Suto 1991). The sum over the lattice
+// gets split into a short-range part, summed over nearby copies in
+// real space, and a long-range part, summed over nearby wavevectors,
+// which both converge quickly. Like any periodic gravity it assumes a
+// uniform background that cancels out the mean density, otherwise the
+// whole lattice would just collapse.
+//
+// The correction only depends on where a body is relative to the
+// nearest copy of a mass, in units of the box size, so rather than
+// redoing the sums for every pair we tabulate it once, over an eighth
+// of the box (it's symmetric under flipping any axis), and
+// interpolate. The table is computed the first time anything asks for
+// it and shared from then on; it's the same for every simulation.
+//
+// It's only worked out for 1/r^2 gravity in 3D. Anything else in a
+// periodic box just feels the nearest copies.
+
+use std::f64::consts::PI;
+use std::sync::OnceLock;
+
+// the number of intervals the table splits half the box into along
+// each axis
+pub const TABLE_SIZE: usize = 16;
+
+// alpha sets where the split between the two sums falls, and the sums
+// run over copies and wavevectors up to REACH away on each axis,
+// which is plenty for alpha = 2
+const ALPHA: f64 = 2.0;
+const REACH: i32 = 4;
+
+struct Table {
+    force: Vec<[f64; 3]>,
+    psi: Vec<f64>
+}
+
+static TABLE: OnceLock<Table> = OnceLock::new();
+
+fn table() -> &'static Table {
+    TABLE.get_or_init(|| {
+        let n = TABLE_SIZE + 1;
+        let mut force = Vec::with_capacity(n * n * n);
+        let mut psi = Vec::with_capacity(n * n * n);
+        for i in 0..n {
+            for j in 0..n {
+                for k in 0..n {
+                    let x = [i, j, k].map(|c| 0.5 * c as f64 / TABLE_SIZE as f64);
+                    force.push(exact_force(&x));
+                    psi.push(exact_psi(&x));
+                }
+            }
+        }
+        Table { force, psi }
+    })
+}
+
+// erfc is the complementary error function. Near 0 it's 1 minus the
+// Taylor series of erf, which keeps the cancellations in exact_force
+// accurate for small x, and further out it's the Chebyshev fit from
+// Numerical Recipes (erfcc), good to about 1e-7 relative.
+pub fn erfc(x: f64) -> f64 {
+    let z = x.abs();
+    if z < 0.5 {
+        // erf(z) = 2 / sqrt(pi) sum (-1)^n z^(2n+1) / (n! (2n+1))
+        let (mut term, mut sum) = (z, z);
+        for n in 1..20 {
+            term *= -z * z / n as f64;
+            sum += term / (2 * n + 1) as f64;
+        }
+        let erf = 2.0 / PI.sqrt() * sum;
+        return if x >= 0.0 { 1.0 - erf } else { 1.0 + erf };
+    }
+
+    let t = 1.0 / (1.0 + 0.5 * z);
+    let ans = t * (-z * z - 1.26551223 + t * (1.00002368 + t * (0.37409196 + t * (0.09678418
+        + t * (-0.18628806 + t * (0.27886807 + t * (-1.13520398 + t * (1.48851587
+        + t * (-0.82215223 + t * 0.17087277))))))))).exp();
+    if x >= 0.0 { ans } else { 2.0 - ans }
+}
+
+// exact_force is the correction to the pull of a unit mass (with
+// G = 1) in a box of side 1, at x from its nearest copy: the pull of
+// the whole lattice minus that of the nearest copy, -x / |x|^3.
+pub fn exact_force(x: &[f64; 3]) -> [f64; 3] {
+    let mut force = [0.0; 3];
+    let r2 = x.iter().fold(0.0, |sum, xk| sum + xk * xk);
+    if r2 == 0.0 {
+        return force;
+    }
+
+    // take the nearest copy back out (the real space sum puts it in)
+    let r = r2.sqrt();
+    for k in 0..3 {
+        force[k] += x[k] / (r2 * r);
+    }
+
+    for n0 in -REACH..REACH + 1 {
+        for n1 in -REACH..REACH + 1 {
+            for n2 in -REACH..REACH + 1 {
+                let d = [x[0] - n0 as f64, x[1] - n1 as f64, x[2] - n2 as f64];
+                let r = d.iter().fold(0.0, |sum, dk| sum + dk * dk).sqrt();
+                let val = erfc(ALPHA * r)
+                    + 2.0 * ALPHA * r / PI.sqrt() * (-ALPHA * ALPHA * r * r).exp();
+                for k in 0..3 {
+                    force[k] -= d[k] / (r * r * r) * val;
+                }
+            }
+        }
+    }
+
+    for h0 in -REACH..REACH + 1 {
+        for h1 in -REACH..REACH + 1 {
+            for h2 in -REACH..REACH + 1 {
+                let h2_sum = h0 * h0 + h1 * h1 + h2 * h2;
+                if h2_sum == 0 {
+                    continue;
+                }
+                let hx = x[0] * h0 as f64 + x[1] * h1 as f64 + x[2] * h2 as f64;
+                let val = 2.0 / h2_sum as f64
+                    * (-PI * PI * h2_sum as f64 / (ALPHA * ALPHA)).exp()
+                    * (2.0 * PI * hx).sin();
+                force[0] -= h0 as f64 * val;
+                force[1] -= h1 as f64 * val;
+                force[2] -= h2 as f64 * val;
+            }
+        }
+    }
+    force
+}
+
+// exact_psi is the same correction for the potential: the potential of
+// the whole lattice is -1 / |x| + psi. At x = 0 that's the limit, which
+// is the potential a body feels from its own copies.
+pub fn exact_psi(x: &[f64; 3]) -> f64 {
+    let r = x.iter().fold(0.0, |sum, xk| sum + xk * xk).sqrt();
+
+    let mut sum1 = 0.0;
+    for n0 in -REACH..REACH + 1 {
+        for n1 in -REACH..REACH + 1 {
+            for n2 in -REACH..REACH + 1 {
+                // the nearest copy is dealt with below
+                if (n0, n1, n2) == (0, 0, 0) {
+                    continue;
+                }
+                let d = [x[0] - n0 as f64, x[1] - n1 as f64, x[2] - n2 as f64];
+                let rn = d.iter().fold(0.0, |sum, dk| sum + dk * dk).sqrt();
+                sum1 += erfc(ALPHA * rn) / rn;
+            }
+        }
+    }
+
+    let mut sum2 = 0.0;
+    for h0 in -REACH..REACH + 1 {
+        for h1 in -REACH..REACH + 1 {
+            for h2 in -REACH..REACH + 1 {
+                let h2_sum = h0 * h0 + h1 * h1 + h2 * h2;
+                if h2_sum == 0 {
+                    continue;
+                }
+                let hx = x[0] * h0 as f64 + x[1] * h1 as f64 + x[2] * h2 as f64;
+                sum2 += 1.0 / (PI * h2_sum as f64)
+                    * (-PI * PI * h2_sum as f64 / (ALPHA * ALPHA)).exp()
+                    * (2.0 * PI * hx).cos();
+            }
+        }
+    }
+
+    // the nearest copy's part of the real space sum, plus the 1/r of
+    // it we take back out, which goes to 2 alpha / sqrt(pi) at r = 0
+    let near = if r > 0.0 {
+        (1.0 - erfc(ALPHA * r)) / r
+    } else {
+        2.0 * ALPHA / PI.sqrt()
+    };
+    PI / (ALPHA * ALPHA) - sum1 - sum2 + near
+}
+
+// lookup interpolates the table at x (in units of the box, each
+// component at most a half in size), returning the force correction
+// and psi.
+fn lookup(x: &[f64; 3]) -> ([f64; 3], f64) {
+    let table = table();
+    let n = TABLE_SIZE + 1;
+
+    let mut index = [0; 3];
+    let mut frac = [0.0; 3];
+    for k in 0..3 {
+        let u = (2.0 * x[k].abs() * TABLE_SIZE as f64).min(TABLE_SIZE as f64);
+        index[k] = (u as usize).min(TABLE_SIZE - 1);
+        frac[k] = u - index[k] as f64;
+    }
+
+    let mut force = [0.0; 3];
+    let mut psi = 0.0;
+    for corner in 0..8 {
+        let mut weight = 1.0;
+        let mut at = 0;
+        for k in 0..3 {
+            let up = corner & (1 << k) != 0;
+            weight *= if up { frac[k] } else { 1.0 - frac[k] };
+            at = at * n + index[k] + up as usize;
+        }
+        for (fk, tk) in force.iter_mut().zip(table.force[at].iter()) {
+            *fk += weight * tk;
+        }
+        psi += weight * table.psi[at];
+    }
+
+    // the table only covers positive x, and the force is odd along
+    // each axis
+    for k in 0..3 {
+        if x[k] < 0.0 {
+            force[k] = -force[k];
+        }
+    }
+    (force, psi)
+}
+
+// accel is the pull of every copy of a mass with gm = G m other than
+// the nearest one, on a body dx from the nearest one, in a periodic
+// box of side box_len. It's zero outside of 3D.
+pub fn accel<const D: usize>(dx: &[f64; D], gm: f64, box_len: f64) -> [f64; D] {
+    let mut acc = [0.0; D];
+    if D != 3 {
+        return acc;
+    }
+    let x = [dx[0] / box_len, dx[1] / box_len, dx[2] / box_len];
+    let (force, _) = lookup(&x);
+    for k in 0..3 {
+        acc[k] = gm * force[k] / (box_len * box_len);
+    }
+    acc
+}
+
+// potential is the same thing for the potential.
+pub fn potential<const D: usize>(dx: &[f64; D], gm: f64, box_len: f64) -> f64 {
+    if D != 3 {
+        return 0.0;
+    }
+    let x = [dx[0] / box_len, dx[1] / box_len, dx[2] / box_len];
+    let (_, psi) = lookup(&x);
+    gm * psi / box_len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_erfc() {
+        // values from Abramowitz and Stegun
+        let values = [
+            (0.0, 1.0), (0.1, 0.887537083981715), (0.4, 0.571607644953332),
+            (0.5, 0.479500122186953), (1.0, 0.157299207050285),
+            (2.0, 0.004677734981047), (3.0, 2.209049699858544e-5)
+        ];
+        for &(x, y) in values.iter() {
+            assert!((erfc(x) - y).abs() < 1.2e-7 * y, "erfc({})", x);
+            assert!((erfc(-x) - (2.0 - y)).abs() < 1.2e-7);
+        }
+    }
+
+    #[test]
+    fn test_force_is_gradient_of_psi() {
+        let h = 1e-5;
+        for x in [[0.1, 0.2, 0.3], [0.45, -0.05, 0.2], [-0.3, 0.3, -0.1]].iter() {
+            let force = exact_force(x);
+            for k in 0..3 {
+                let (mut up, mut down) = (*x, *x);
+                up[k] += h;
+                down[k] -= h;
+                let slope = (exact_psi(&up) - exact_psi(&down)) / (2.0 * h);
+                assert!((force[k] + slope).abs() < 1e-6, "{:?} {}", x, k);
+            }
+        }
+    }
+
+    #[test]
+    fn test_half_box_cancels() {
+        // a mass half a box away pulls equally both ways, so the lattice
+        // has to cancel out the nearest copy's pull of 1 / 0.5^2 = 4
+        let exact = exact_force(&[0.5, 0.0, 0.0]);
+        assert!((exact[0] - 4.0).abs() < 1e-6);
+        let table = accel(&[0.5, 0.0, 0.0], 1.0, 1.0);
+        assert!((table[0] - 4.0).abs() < 1e-6);
+        assert!(table[1].abs() < 1e-9 && table[2].abs() < 1e-9);
+
+        // the same in the middle of a face of the box, and a corner
+        let face = exact_force(&[0.5, 0.5, 0.0]);
+        let r3 = 0.5f64.powf(1.5);
+        assert!((face[0] - 0.5 / r3).abs() < 1e-6 && (face[1] - 0.5 / r3).abs() < 1e-6);
+        let corner = exact_force(&[0.5, 0.5, 0.5]);
+        let r3 = 0.75f64.powf(1.5);
+        assert!((corner[0] - 0.5 / r3).abs() < 1e-6);
+
+        // a body's own copies, which Gadget has as 2.8372975
+        assert!((exact_psi(&[0.0; 3]) - 2.8372975).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_table_matches_exact() {
+        // the interpolated table against the sums themselves, in
+        // units of the largest correction (at the corner, about 5)
+        let mut worst: f64 = 0.0;
+        for i in 0..60 {
+            let x = [
+                ((i * 37) % 100) as f64 / 100.0 - 0.5,
+                ((i * 59) % 100) as f64 / 100.0 - 0.5,
+                ((i * 71) % 100) as f64 / 100.0 - 0.5
+            ];
+            let exact = exact_force(&x);
+            let table = accel(&x, 1.0, 1.0);
+            for k in 0..3 {
+                worst = worst.max((exact[k] - table[k]).abs());
+            }
+            assert!((potential(&x, 1.0, 1.0) - exact_psi(&x)).abs() < 1e-2);
+        }
+        // (it comes out at 2.5e-3)
+        assert!(worst < 5e-3, "worst {}", worst);
+
+        // scaling to another box
+        let x = [0.1, -0.2, 0.3];
+        let big = accel(&[10.0, -20.0, 30.0], 2.0, 100.0);
+        let unit = accel(&x, 1.0, 1.0);
+        for k in 0..3 {
+            assert!((big[k] - 2.0 * unit[k] / 1e4).abs() < 1e-15);
+        }
+    }
+}
@@ -0,0 +1,322 @@
+// The fast multipole method (FMM) is the other way we can work out the
+// forces, besides walking the tree once for every body (see
+// Body::get_total_acc). Rather than every body looking at the regions
+// around it, whole pairs of regions that are far enough apart
+// interact at once: the pull of one (its mass and quadrupole about its
+// com) gets turned into a local expansion of the field about the com
+// of the other, which says what that pull looks like anywhere nearby.
+// Once every pair has been dealt with, the local expansions get pushed
+// down the tree, shifted onto each child's com along the way, and
+// every leaf reads its body's acceleration off of its own. Only leaves
+// that end up close together feel each other directly, through the
+// usual kernel. All that makes computing the forces O(N) rather than
+// O(N log N).
+//
+// It uses the same Region tree as the walk, so switching between them
+// is just `solver = fmm` in the params.
+//
+// ******** TODO ********
+// + the walk over pairs of regions runs on a single thread
+// + the expansions stop at third order, so the octupoles only get
+//   used by the tree walk
+
+use super::tree::{Body, Region, Tree};
+use super::config::Params;
+use super::law::ForceLaw;
+use super::simulation::Boundary;
+
+// Local is the expansion of the field about a region's com due to
+// everything its been paired up with, up to third order: near the com
+// the acceleration at an offset a is
+//
+//     G (c1 + c2 a + c3 a a / 2)
+//
+// c2 is the tidal tensor and c3 its gradient.
+#[derive(Clone, Copy)]
+struct Local<const D: usize> {
+    c1: [f64; D],
+    c2: [[f64; D]; D],
+    c3: [[[f64; D]; D]; D]
+}
+
+impl<const D: usize> Local<D> {
+
+    fn zero() -> Local<D> {
+        Local {
+            c1: [0.0; D],
+            c2: [[0.0; D]; D],
+            c3: [[[0.0; D]; D]; D]
+        }
+    }
+
+    // add_source adds the pull of a region with mass and quadrupole
+    // quad about its com (which is at -r from ours) to the expansion.
+    // That's the taylor series of 1/|r + a - y| out to third order in
+    // a and y; the terms linear in y vanish about the com, and the
+    // quadrupole shows up in c1 alone.
+    fn add_source(&mut self, r: &[f64; D], mass: f64, quad: Option<&[[f64; D]; D]>) {
+        let r2 = r.iter().fold(0.0, |sum, rk| sum + rk * rk);
+        let r1 = r2.sqrt();
+        let r3 = r2 * r1;
+        let r5 = r3 * r2;
+        let r7 = r5 * r2;
+
+        let delta = |i: usize, j: usize| if i == j { 1.0 } else { 0.0 };
+
+        // the first three derivatives of 1/r
+        let mut d3 = [[[0.0; D]; D]; D];
+        for i in 0..D {
+            self.c1[i] -= mass * r[i] / r3;
+            for j in 0..D {
+                self.c2[i][j] += mass * (3.0 * r[i] * r[j] / r5 - delta(i, j) / r3);
+                for k in 0..D {
+                    d3[i][j][k] = -15.0 * r[i] * r[j] * r[k] / r7
+                        + 3.0 * (delta(i, j) * r[k] + delta(i, k) * r[j] + delta(j, k) * r[i]) / r5;
+                    self.c3[i][j][k] += mass * d3[i][j][k];
+                }
+            }
+        }
+
+        if let Some(quad) = quad {
+            for (c1, d3i) in self.c1.iter_mut().zip(d3.iter()) {
+                for j in 0..D {
+                    for k in 0..D {
+                        *c1 += 0.5 * d3i[j][k] * quad[j][k];
+                    }
+                }
+            }
+        }
+    }
+
+    // shifted is the same expansion about a point s away from ours.
+    fn shifted(&self, s: &[f64; D]) -> Local<D> {
+        let mut local = *self;
+        for i in 0..D {
+            for j in 0..D {
+                local.c1[i] += self.c2[i][j] * s[j];
+                for k in 0..D {
+                    local.c1[i] += 0.5 * self.c3[i][j][k] * s[j] * s[k];
+                    local.c2[i][j] += self.c3[i][j][k] * s[k];
+                }
+            }
+        }
+        local
+    }
+
+    fn add(&mut self, other: &Local<D>) {
+        for i in 0..D {
+            self.c1[i] += other.c1[i];
+            for j in 0..D {
+                self.c2[i][j] += other.c2[i][j];
+                for k in 0..D {
+                    self.c3[i][j][k] += other.c3[i][j][k];
+                }
+            }
+        }
+    }
+}
+
+// accelerations works out the acceleration of every body with the FMM,
+// returned in the same order as the bodies. The tree has to be up to
+// date. Bodies that aren't held by a leaf (ones outside the root, or
+// sitting exactly on top of another) fall back to walking the tree,
+// and so does everything if params.force_law isn't the 1/r^2 one the
+// expansions are worked out for, there are Coulomb forces to deal
+// with, since the expansions only hold the masses, or the box is
+// periodic.
+pub fn accelerations<const D: usize>(
+    bodies: &[Body<D>],
+    tree: &Tree<D>,
+    params: &Params
+) -> Vec<[f64; D]> {
+    if !params.force_law.is_newtonian(D) || params.forces.coulomb()
+        || params.boundary == Boundary::Periodic
+    {
+        return bodies.iter()
+            .map(|body| body.get_total_acc(tree, params))
+            .collect();
+    }
+
+    let regions = &tree.regions;
+    let mut locals = vec![Local::<D>::zero(); regions.len()];
+    // the pull of the leaves each leaf met directly
+    let mut direct = vec![[0.0; D]; regions.len()];
+
+    // First, every pair of (target, source) regions. Two regions are
+    // far enough apart when theta times the distance between their
+    // coms is more than both their bmax (see Region::bmax) added up,
+    // which is the same kind of test as the walk's bmax criterion. If
+    // they aren't, we split the bigger one and look at the pairs its
+    // children make instead. A region paired with itself has to deal
+    // with every pair of its children, itself included.
+    let mut stack = match tree.root() {
+        None => vec![],
+        Some(_) => vec![(0, 0)]
+    };
+
+    while let Some((a, b)) = stack.pop() {
+        let (ra, rb) = (&regions[a], &regions[b]);
+        let (com_a, com_b) = match (&ra.com, &rb.com) {
+            (Some(ca), Some(cb)) => (ca, cb),
+            // empty leaves, nothing to do
+            _ => continue
+        };
+
+        if a == b {
+            if let Some(first) = ra.children {
+                for i in first..first + Region::<D>::NUM_CHILDREN {
+                    for j in first..first + Region::<D>::NUM_CHILDREN {
+                        stack.push((i, j));
+                    }
+                }
+            }
+            continue;
+        }
+
+        if ra.children.is_none() && rb.children.is_none() {
+            direct[a] = com_a.update_direct_accel(direct[a], com_b, params);
+            continue;
+        }
+
+        let r = com_a.vec_rel(com_b);
+        let dist = com_a.sq_magnitude(&r).sqrt();
+        if ra.bmax() + rb.bmax() < params.theta * dist {
+            let quad = if params.multipole_order >= 2 {
+                tree.quads.get(b)
+            } else {
+                None
+            };
+            // vec_rel points from a to b, and we want b to a
+            let mut r_ab = [0.0; D];
+            for k in 0..D {
+                r_ab[k] = -r[k];
+            }
+            locals[a].add_source(&r_ab, com_b.mass, quad);
+            continue;
+        }
+
+        // open up the bigger of the two
+        let split_a = match (ra.children, rb.children) {
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            _ => ra.half_length >= rb.half_length
+        };
+        if split_a {
+            let first = ra.children.unwrap();
+            stack.extend((first..first + Region::<D>::NUM_CHILDREN).map(|i| (i, b)));
+        } else {
+            let first = rb.children.unwrap();
+            stack.extend((first..first + Region::<D>::NUM_CHILDREN).map(|j| (a, j)));
+        }
+    }
+
+    // Then push the local expansions down the tree. Children always
+    // come after their parent in the arena, so one pass in order sees
+    // every parent finished before its children.
+    let mut acc = vec![None; bodies.len()];
+    for p in 0..regions.len() {
+        let reg = &regions[p];
+        let com = match reg.com {
+            None => continue,
+            Some(ref com) => com
+        };
+
+        match reg.children {
+            Some(first) => {
+                for c in first..first + Region::<D>::NUM_CHILDREN {
+                    if let Some(ref child_com) = regions[c].com {
+                        let s = com.vec_rel(child_com);
+                        let shifted = locals[p].shifted(&s);
+                        locals[c].add(&shifted);
+                    }
+                }
+            },
+            None => {
+                if let Some(i) = reg.body {
+                    // the body sits right on the leaf's com
+                    let mut ai = direct[p];
+                    for (a, c) in ai.iter_mut().zip(locals[p].c1.iter()) {
+                        *a += params.g * c;
+                    }
+                    acc[i] = Some(ai);
+                }
+            }
+        }
+    }
+
+    acc.into_iter()
+        .enumerate()
+        .map(|(i, ai)| match ai {
+            Some(ai) => ai,
+            None => bodies[i].get_total_acc(tree, params)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simulation::{Simulation, Solver};
+    use physics::analysis;
+    use testing::random_bodies;
+
+    #[test]
+    fn test_fmm_force_error() {
+        let mut params = Params {
+            min_len: 1e-9,
+            num_threads: 1,
+            ..Params::default()
+        };
+
+        let bodies = random_bodies(1000, &params);
+
+        // the FMM should be about as accurate as the tree walk at the
+        // same theta (both use quadrupoles), and get worse as theta
+        // grows: for these bodies the rms errors come out at
+        //
+        //     theta 0.3:   1.8e-4 (walk 1.9e-4)
+        //     theta 0.5:   1.4e-3 (walk 1.7e-3)
+        //     theta 0.7:   6.9e-3 (walk 7.9e-3)
+        let mut last = 0.0;
+        for &theta in [0.3, 0.5, 0.7].iter() {
+            params.theta = theta;
+            params.solver = Solver::Tree;
+            let walk = analysis::force_error(&bodies, &params).0;
+            params.solver = Solver::Fmm;
+            let fmm = analysis::force_error(&bodies, &params).0;
+
+            assert!(fmm < 1.5 * walk);
+            assert!(fmm > last);
+            last = fmm;
+        }
+    }
+
+    #[test]
+    fn test_fmm_in_simulation() {
+        // a step with either solver should move the bodies almost the
+        // same way
+        let mut params = Params {
+            min_len: 1e-9,
+            theta: 0.3,
+            ..Params::default()
+        };
+        let bodies = random_bodies(500, &params);
+
+        let mut tree_sim = Simulation::new(params.clone());
+        params.solver = Solver::Fmm;
+        let mut fmm_sim = Simulation::new(params);
+        tree_sim.push_bodies(&bodies);
+        fmm_sim.push_bodies(&bodies);
+
+        for _ in 0..5 {
+            tree_sim.step();
+            fmm_sim.step();
+        }
+        assert_eq!(tree_sim.bodies().len(), bodies.len());
+        assert_eq!(fmm_sim.bodies().len(), bodies.len());
+        for ((b1, b2), b0) in tree_sim.bodies().iter().zip(fmm_sim.bodies()).zip(&bodies) {
+            assert!(b1.squared_dist_to(b2) < 1e-4 * b1.squared_dist_to(b0));
+        }
+    }
+
+}
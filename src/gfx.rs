@@ -1,45 +1,33 @@
-extern crate piston;
-extern crate graphics;
-extern crate glutin_window;
-extern crate opengl_graphics;
-
-use piston::window::WindowSettings;
-use piston::event_loop::*;
 use piston::input::*;
-use glutin_window::GlutinWindow as Window;
-use opengl_graphics::{ GlGraphics, OpenGL };
+use opengl_graphics::GlGraphics;
 
 use super::tree::*;
-use super::data::TREE_POINTER;
-
-use std::sync::{Arc, Mutex};
+use super::simulation::Simulation;
 
-pub struct Frame {
+// The viewer draws the first two coordinates of every mass, so higher
+// dimensional simulations show up projected onto that plane.
+pub struct Frame<const D: usize> {
     pub gl: GlGraphics, // OpenGL backend for drawing
-    pub tree: Region // the tree we're gonna be drawing
+    pub sim: Simulation<D> // the simulation we're gonna be drawing
 }
 
-pub use data::{ MIN_LEN, MAX_LEN, DIMS };
-
 pub const screen_scale: f64 = 350.0;
 pub const screen_offset: f64 = 400.0;
 
-impl Region {
+impl<const D: usize> Region<D> {
 
 
-    fn normalize_coords(&self) -> Vec<f64> {
+    fn normalize_coords(&self, max_len: f64) -> [f64; D] {
 
-        match self.com.clone() {
+        match self.com {
 
-            None => vec![-1.0; DIMS],
-            Some(com) => {
-                let mut pos_vec = com.lock().unwrap().pos_vec.clone();
-                let original = pos_vec.clone();
-                for i in 0..DIMS {
-                    pos_vec[i] *= screen_scale / MAX_LEN;
+            None => [-1.0; D],
+            Some(ref com) => {
+                let mut pos_vec = com.pos_vec;
+                for i in 0..D {
+                    pos_vec[i] *= screen_scale / max_len;
                     pos_vec[i] += screen_offset;
                 }
-                // println!("original: {:?}, normalized: {:?}\n\n\n", original, pos_vec);
                 pos_vec
             }
 
@@ -47,14 +35,14 @@ impl Region {
 
     }
 
-    fn normalize_region_coords(&self) -> Vec<f64> {
+    fn normalize_region_coords(&self, max_len: f64) -> [f64; D] {
 
-        let mut coord_vec = self.coord_vec.clone();
+        let mut coord_vec = self.coord_vec;
 
-        for i in 0..DIMS {
-            coord_vec[i] *= screen_scale / MAX_LEN;
+        for i in 0..D {
+            coord_vec[i] *= screen_scale / max_len;
             coord_vec[i] += screen_offset;
-            coord_vec[i] -= self.half_length * (screen_scale / MAX_LEN);
+            coord_vec[i] -= self.half_length * (screen_scale / max_len);
         }
 
         coord_vec
@@ -64,9 +52,9 @@ impl Region {
 }
 
 
-impl Frame {
+impl<const D: usize> Frame<D> {
 
-    pub fn render(&mut self, reg_option: Option<&Region>, args: &RenderArgs) {
+    pub fn render(&mut self, args: &RenderArgs) {
         use graphics::*;
 
         const WHITE: [f32; 4] = [1.0, 1.0, 1.0, 0.05];
@@ -77,189 +65,89 @@ impl Frame {
         const RED: [f32; 4] = [1.0, 0.0, 0.0, 1.0];
         const YELLOW: [f32; 4] = [1.0, 1.0, 0.0, 1.0];
 
-        //main should pass render() a None option
-        //if that happens, call render on the tree
-        match reg_option {
-
-            None => {
-                let tree = TREE_POINTER.lock().unwrap().tree.clone();
+        // screen coordinates are scaled so the root region fills the
+        // window
+        let max_len = self.sim.params.max_len;
+        let tree = &self.sim.tree;
 
-                self.gl.draw(args.viewport(), |c, gl| {
-                    // Clear the screen.
-                    //clear(BLACK, gl);
-                });
+        self.gl.draw(args.viewport(), |c, gl| {
+            // Clear the screen.
+            //clear(BLACK, gl);
 
-                // self.gl.draw(args.viewport(), |c, gl| {
-                //     let coords = [screen_offset, screen_offset];
-                //     let square = rectangle::square(0.0, 0.0, 4.0);
-                //     let transform = c.transform.trans(coords[0], coords[1]).rot_rad(0.0);
-                //     rectangle(RED, square, transform, gl);
-                // });
+            // The regions all sit in one arena, so we can just run
+            // through it instead of recursing.
+            for reg in tree.regions.iter() {
 
-                //todo: replace this with drawing a red square at the master tree's com
-                //println!("none option passed to render; mass: {:#?}", tree.com.mass);
-                self.render(Some(& tree), args)
+                match reg.children {
 
-            },
-            Some(reg) => {
+                    None => {
+                        let coords = reg.normalize_coords(max_len);
 
-                match reg.clone().reg_vec {
+                        if coords[0] == -1.0 {
+                            continue;
+                        }
 
-                    None => {
-                        self.gl.draw(args.viewport(), |c, gl| {
-                            let coords = reg.clone().normalize_coords();
-
-                            if coords[0] == -1.0 {
-
-                                return
-
-                            } else {
-
-                                let transform =
-                                    c.transform
-                                    .trans(coords[0], coords[1])
-                                    .rot_rad(0.0);
-
-                                match reg.com.clone() {
-                                    None => (),
-                                    Some (com) => {
-                                        if com.lock().unwrap().clone().mass < 9000.0 {
-                                            let square = rectangle::square(0.0, 0.0, 2.0);
-                                            rectangle(WHITE, square, transform, gl);
-                                        } else {
-                                            let square = rectangle::square(0.0, 0.0, 4.0);
-                                            rectangle(YELLOW, square, transform, gl);
-                                        }
-                                    }
+                        let transform =
+                            c.transform
+                            .trans(coords[0], coords[1])
+                            .rot_rad(0.0);
+
+                        match reg.com {
+                            None => (),
+                            Some (ref com) => {
+                                if com.mass < 9000.0 {
+                                    let square = rectangle::square(0.0, 0.0, 2.0);
+                                    rectangle(WHITE, square, transform, gl);
+                                } else {
+                                    let square = rectangle::square(0.0, 0.0, 4.0);
+                                    rectangle(YELLOW, square, transform, gl);
                                 }
+                            }
+                        }
 
-                                // Optional drawing of green squares representing regions with children
+                        // Optional drawing of green squares representing regions with children
 
-                                // let coords = reg.normalize_region_coords();
+                        // let coords = reg.normalize_region_coords(max_len);
 
-                                // let square = rectangle::square(0.0, 0.0, 2.0*reg.half_length * (screen_scale / MAX_LEN));
-                                // let transform = c.transform.trans(coords[0], coords[1]).rot_rad(0.0);
-                                // rectangle(GREEN, square, transform, gl);
-                            }
-                        });
+                        // let square = rectangle::square(0.0, 0.0, 2.0*reg.half_length * (screen_scale / max_len));
+                        // let transform = c.transform.trans(coords[0], coords[1]).rot_rad(0.0);
+                        // rectangle(GREEN, square, transform, gl);
                     },
 
-                    Some(child_vec) => {
-                        //
+                    Some(_) => {
                         // Optional drawing of blue squares representing current regions
 
-                        // self.gl.draw(args.viewport(), |c, gl| {
-                        //     //draw red squares
-                        //     let coords = reg.clone().normalize_region_coords();
-                        //     let square = rectangle::square(0.0, 0.0, 2.0*reg.half_length * (screen_scale) / MAX_LEN);
-                        //     let transform = c.transform.trans(coords[0], coords[1]).rot_rad(0.0);
-                        //     rectangle(BLUE, square, transform, gl);
-                        // });
-                        self.gl.draw(args.viewport(), |c, gl| {
-                            match reg.com.clone() {
-                                None => (),
-                                Some(com) => {
-                                    let mut mass = com.lock().unwrap().mass;
-                                    if mass > 0.0 {
-
-                                        let coords = reg.clone().normalize_coords();
-
-                                        if coords[0] == -1.0 {
-                                        } else {
-                                            let square = rectangle::square(0.0, 0.0, 1.0);
-
-                                            // let transform =
-                                            //     c.transform
-                                            //     .trans(coords[0], coords[1])
-                                            //     .rot_rad(0.0);
-
-                                            // rectangle(RED, square, transform, gl);
-
-                                        }
-                                    };
-                                }
-                            }
-                        });
-                        for child in child_vec.iter() {
-                            self.render(
-                                Some(& *child.lock().unwrap()),
-                                args
-                            );
-                        }
+                        // let coords = reg.normalize_region_coords(max_len);
+                        // let square = rectangle::square(0.0, 0.0, 2.0*reg.half_length * (screen_scale) / max_len);
+                        // let transform = c.transform.trans(coords[0], coords[1]).rot_rad(0.0);
+                        // rectangle(BLUE, square, transform, gl);
                     }
                 }
             }
-        }
+        });
     }
 
-    pub fn print_masses(&mut self, reg_option: Option<&Region>) -> String {
-        //recurse into the subregions
-        // let reg_option = self.tree.reg_vec.clone();
+    pub fn print_masses(&self) -> String {
         let mut output = String::new();
-        match reg_option {
-
-            None => {
-                let tree = TREE_POINTER.try_lock().unwrap().tree.clone();
-                // println!("let tree");
-                output = self.print_masses(Some(&tree));
-                output.push_str(&format!("\n"));
-                output
-            },
 
-
-            Some(reg) => {
-                // println!("entered Some arm");
-                match reg.reg_vec.clone() {
-
-                    None => {
-                        match reg.com.clone() {
-                            None => output,
-                            Some(our_reg) => {
-                                let mass = our_reg.try_lock().unwrap().clone();
-
-                                if mass.mass == 100000.01 {
-                                    output.push_str(&format!("\t{:#?}", mass.mass));
-                                    output.push_str(&format!("\t{:#?}", mass.pos_vec[0]));
-                                    output.push_str(&format!("\t{:#?}", mass.pos_vec[1]));
-                                    output.push_str(&format!("\t{:#?}", mass.vel_vec[0]));
-                                    output.push_str(&format!("\t{:#?}", mass.vel_vec[1]));
-                                }
-                                // println!("{}", output);
-                                output
-                            }
-                        }
-                    },
-
-                    Some(child_vec) => {
-
-                        for child in child_vec.iter() {
-                            //let mut clone = output.clone();
-                            output.push_str(
-                                &self.print_masses(
-                                    Some(& *child.lock().unwrap()),
-                                )
-                            );//
-                        }
-                        output
-                    }
-                }
+        for mass in self.sim.bodies().iter() {
+            if mass.mass == 100000.01 {
+                output.push_str(&format!("\t{:#?}", mass.mass));
+                output.push_str(&format!("\t{:#?}", mass.pos_vec[0]));
+                output.push_str(&format!("\t{:#?}", mass.pos_vec[1]));
+                output.push_str(&format!("\t{:#?}", mass.vel_vec[0]));
+                output.push_str(&format!("\t{:#?}", mass.vel_vec[1]));
             }
         }
+
+        output.push_str(&format!("\n"));
+        output
     }
 
     pub fn update(&mut self, args: &UpdateArgs) {
-        // self.tree = TREE_POINTER.lock().unwrap().tree.clone();
-        // self.tree.update();
-        // TREE_POINTER.lock().unwrap().tree = self.tree.clone();
-
         // let mut output = String::new();
-        // output =  self.print_masses(None, output);
+        // output =  self.print_masses();
         // println!("{}", output);
-        self.tree.deep_update_vel();
-        TREE_POINTER.lock().unwrap().tree = self.tree.clone();
-        self.tree.deep_update_pos();
-        self.tree.add_queue = TREE_POINTER.lock().unwrap().tree.add_queue.clone();
-        TREE_POINTER.lock().unwrap().tree = self.tree.clone();
-        self.tree.update();
+        self.sim.step();
     }
 }
@@ -0,0 +1,480 @@
+// Integrators advance a simulation by one timestep. They all drive the
+// same force evaluation: the tree gets rebuilt from the bodies'
+// positions and walked once per body (see Simulation::update and
+// Simulation::accelerations_at); what differs is how often that
+// happens per step and how the results get combined.
+//
+// Every integrator can count on sim.tree and sim.acc being up to date
+// with the bodies' positions when step is called, and has to leave
+// them that way, which normally just means finishing with a call to
+// sim.update(). Simulation::step takes care of advancing sim.time.
+
+use super::simulation::Simulation;
+use super::data::MAX_BLOCK_LEVELS;
+
+pub trait Integrator {
+    fn step<const D: usize>(&self, sim: &mut Simulation<D>, dt: f64);
+}
+
+// SymplecticEuler kicks every velocity by a whole step, then drifts
+// every position with the new velocities. First order, one force
+// evaluation per step. This is how the simulation used to step.
+pub struct SymplecticEuler;
+
+impl Integrator for SymplecticEuler {
+    fn step<const D: usize>(&self, sim: &mut Simulation<D>, dt: f64) {
+        sim.kick(dt);
+        sim.drift(dt);
+        sim.update();
+    }
+}
+
+// Leapfrog is kick-drift-kick: half a kick, a whole drift, rebuild,
+// then the other half kick. Second order and symplectic, and still
+// one force evaluation per step, since the accelerations the second
+// kick uses get reused by the first kick of the next step.
+pub struct Leapfrog;
+
+impl Integrator for Leapfrog {
+    fn step<const D: usize>(&self, sim: &mut Simulation<D>, dt: f64) {
+        sim.kick(0.5 * dt);
+        sim.drift(dt);
+        sim.update();
+        sim.kick(0.5 * dt);
+    }
+}
+
+// Rk4 is the classical fourth order Runge-Kutta method. It isn't
+// symplectic, so energy errors build up over long runs, but it's
+// very accurate over short ones. Four force evaluations per step;
+// the three at trial positions don't drop or merge any bodies.
+pub struct Rk4;
+
+impl Integrator for Rk4 {
+    fn step<const D: usize>(&self, sim: &mut Simulation<D>, dt: f64) {
+        let x0 = sim.bodies.iter().map(|b| b.pos_vec).collect::<Vec<[f64; D]>>();
+        let v0 = sim.bodies.iter().map(|b| b.vel_vec).collect::<Vec<[f64; D]>>();
+
+        // k1 is just the state we start from
+        let a1 = sim.acc.clone();
+        let v1 = v0.clone();
+
+        let x2 = offset(&x0, &v1, 0.5 * dt);
+        let v2 = offset(&v0, &a1, 0.5 * dt);
+        let a2 = sim.accelerations_at(&x2);
+
+        let x3 = offset(&x0, &v2, 0.5 * dt);
+        let v3 = offset(&v0, &a2, 0.5 * dt);
+        let a3 = sim.accelerations_at(&x3);
+
+        let x4 = offset(&x0, &v3, dt);
+        let v4 = offset(&v0, &a3, dt);
+        let a4 = sim.accelerations_at(&x4);
+
+        for (i, body) in sim.bodies.iter_mut().enumerate() {
+            for k in 0..D {
+                body.pos_vec[k] = x0[i][k]
+                    + dt / 6.0 * (v1[i][k] + 2.0 * v2[i][k] + 2.0 * v3[i][k] + v4[i][k]);
+                body.vel_vec[k] = v0[i][k]
+                    + dt / 6.0 * (a1[i][k] + 2.0 * a2[i][k] + 2.0 * a3[i][k] + a4[i][k]);
+            }
+        }
+        sim.update();
+    }
+}
+
+// offset gives x + dx * h for every pair of vectors.
+fn offset<const D: usize>(x: &[[f64; D]], dx: &[[f64; D]], h: f64) -> Vec<[f64; D]> {
+    x.iter()
+        .zip(dx)
+        .map(|(xi, dxi)| {
+            let mut out = *xi;
+            for k in 0..D {
+                out[k] += dxi[k] * h;
+            }
+            out
+        })
+        .collect()
+}
+
+// Yoshida is Yoshida's fourth order symplectic integrator, built as a
+// "triple jump" of three leapfrog steps of w1 dt, w0 dt and w1 dt.
+// The middle one goes backwards in time. Three force evaluations per
+// step.
+pub struct Yoshida;
+
+impl Integrator for Yoshida {
+    fn step<const D: usize>(&self, sim: &mut Simulation<D>, dt: f64) {
+        let cbrt2 = 2.0f64.cbrt();
+        let w1 = 1.0 / (2.0 - cbrt2);
+        let w0 = -cbrt2 / (2.0 - cbrt2);
+
+        Leapfrog.step(sim, w1 * dt);
+        Leapfrog.step(sim, w0 * dt);
+        Leapfrog.step(sim, w1 * dt);
+    }
+}
+
+// Block gives every body its own power-of-two fraction of the step,
+// dt / 2^level with level between 0 and params.block_levels, so that
+// a few bodies in a tight orbit don't force a tiny step on everything
+// else. Each body takes kick-drift-kick leapfrog steps of its own
+// size; the step gets cut into 2^block_levels substeps, and on each
+// one only the bodies whose own step ends there ("active" bodies) get
+// their forces recomputed.
+//
+// Everything drifts on every substep, but the tree only gets a full
+// rebuild at the end of the whole step. In between it gets a partial
+// one (see Tree::reinsert): only the bodies that have drifted out of
+// their leaves get moved to the right ones, and then the centers of
+// mass get refreshed, which is much cheaper than starting over when
+// most bodies stay put.
+//
+// After its closing kick, a body picks its next level from the ratio
+// of its acceleration to its jerk, dt_i = block_eta |a| / |da/dt|,
+// with the jerk estimated from its last two accelerations. A body can
+// always move to a finer level, but only moves one level coarser at a
+// time, and only when that coarser step would start at the current
+// substep, so that steps stay nested inside each other.
+pub struct Block;
+
+impl Integrator for Block {
+    fn step<const D: usize>(&self, sim: &mut Simulation<D>, dt: f64) {
+        let max_level = sim.params.block_levels.min(MAX_BLOCK_LEVELS);
+        let substeps = 1usize << max_level;
+        let dt_min = dt / substeps as f64;
+
+        // bodies we don't know anything about yet start on the
+        // smallest step
+        if sim.levels.len() != sim.bodies.len() {
+            sim.levels = vec![max_level; sim.bodies.len()];
+        }
+        for level in sim.levels.iter_mut() {
+            *level = (*level).min(max_level);
+        }
+
+        for s in 0..substeps {
+            // opening half kicks for the bodies starting a step
+            for i in 0..sim.bodies.len() {
+                let level = sim.levels[i];
+                if s % (substeps >> level) == 0 {
+                    let dt_i = dt / (1usize << level) as f64;
+                    let ai = sim.acc[i];
+                    sim.bodies[i].kick(&ai, 0.5 * dt_i);
+                }
+            }
+
+            sim.drift(dt_min);
+            let t = s + 1;
+
+            let active = if t == substeps {
+                // everyone's step ends here
+                sim.rebuild();
+                (0..sim.bodies.len()).collect::<Vec<usize>>()
+            } else {
+                sim.tree.reinsert(&sim.bodies, &sim.params);
+                (0..sim.bodies.len())
+                    .filter(|&i| t % (substeps >> sim.levels[i]) == 0)
+                    .collect::<Vec<usize>>()
+            };
+
+            let new_acc = sim.accelerations_of(&active);
+
+            // closing half kicks, and the next level for each body
+            for (&i, ai) in active.iter().zip(new_acc) {
+                let level = sim.levels[i];
+                let dt_i = dt / (1usize << level) as f64;
+                sim.bodies[i].kick(&ai, 0.5 * dt_i);
+
+                let mut a2 = 0.0;
+                let mut j2 = 0.0;
+                for (a, a_old) in ai.iter().zip(sim.acc[i].iter()) {
+                    a2 += a * a;
+                    j2 += ((a - a_old) / dt_i).powi(2);
+                }
+                sim.acc[i] = ai;
+
+                let wanted = if j2 > 0.0 {
+                    sim.params.block_eta * (a2 / j2).sqrt()
+                } else {
+                    dt
+                };
+
+                // the coarsest level whose step is no bigger than
+                // the one we want
+                let mut new_level = 0;
+                while new_level < max_level && dt / ((1usize << new_level) as f64) > wanted {
+                    new_level += 1;
+                }
+
+                sim.levels[i] = if new_level >= level {
+                    new_level
+                } else if t % (substeps >> (level - 1)) == 0 {
+                    level - 1
+                } else {
+                    level
+                };
+            }
+        }
+    }
+}
+
+// Scheme picks one of the integrators above at runtime, e.g. from the
+// `integrator` key of a config file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scheme {
+    SymplecticEuler,
+    Leapfrog,
+    Rk4,
+    Yoshida,
+    Block
+}
+
+pub const SCHEMES: [Scheme; 5] = [
+    Scheme::SymplecticEuler,
+    Scheme::Leapfrog,
+    Scheme::Rk4,
+    Scheme::Yoshida,
+    Scheme::Block
+];
+
+impl Scheme {
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Scheme::SymplecticEuler => "euler",
+            Scheme::Leapfrog => "leapfrog",
+            Scheme::Rk4 => "rk4",
+            Scheme::Yoshida => "yoshida",
+            Scheme::Block => "block"
+        }
+    }
+}
+
+// A Scheme steps with whichever integrator it names.
+impl Integrator for Scheme {
+    fn step<const D: usize>(&self, sim: &mut Simulation<D>, dt: f64) {
+        match *self {
+            Scheme::SymplecticEuler => SymplecticEuler.step(sim, dt),
+            Scheme::Leapfrog => Leapfrog.step(sim, dt),
+            Scheme::Rk4 => Rk4.step(sim, dt),
+            Scheme::Yoshida => Yoshida.step(sim, dt),
+            Scheme::Block => Block.step(sim, dt)
+        }
+    }
+}
+
+named_enum!(Scheme, SCHEMES, "integrator");
+
+// Timestep picks how Simulation::step chooses the size of each step.
+// With anything but Fixed, params.dt is only the largest step we'll
+// take.
+//
+// Accel takes timestep_eta * sqrt(softening / |a|), minimised over
+// every body, so that nothing moves more than a fraction of the
+// softening length in one go with its current acceleration.
+//
+// Energy takes a step, measures how much the total energy changed,
+// and if that's more than energy_tolerance (relative) puts everything
+// back and tries again with half the step. Steps that come in well
+// under the tolerance let the next one try twice the size. Merging
+// or losing bodies changes the energy too, so runs where that happens
+// a lot will see more rejected steps.
+//
+// Either way the step never goes below dt_min.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Timestep {
+    Fixed,
+    Accel,
+    Energy
+}
+
+pub const TIMESTEPS: [Timestep; 3] = [
+    Timestep::Fixed,
+    Timestep::Accel,
+    Timestep::Energy
+];
+
+impl Timestep {
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Timestep::Fixed => "fixed",
+            Timestep::Accel => "accel",
+            Timestep::Energy => "energy"
+        }
+    }
+}
+
+named_enum!(Timestep, TIMESTEPS, "timestep");
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::Params;
+    use physics::analysis;
+    use tree::Body;
+    use testing::body_at;
+
+    // Run two equal masses on a circular orbit for one period with
+    // the passed scheme, returning the worst relative energy error
+    // and how far the first mass ends up from where it started.
+    fn orbit(scheme: Scheme, dt: f64) -> (f64, f64) {
+        let params = Params {
+            dt,
+            integrator: scheme,
+            ..Params::default()
+        };
+
+        let (m, a) = (1000.0, 100.0);
+        let v = (params.g * m / (4.0 * a)).sqrt();
+        let period = 2.0 * ::std::f64::consts::PI * a / v;
+
+        let mut sim = Simulation::new(params);
+        sim.push_body(Body { pos_vec: [-a, 0.0], vel_vec: [0.0, -v], mass: m, charge: 0.0, radius: 0.0 });
+        sim.push_body(Body { pos_vec: [a, 0.0], vel_vec: [0.0, v], mass: m, charge: 0.0, radius: 0.0 });
+        sim.update();
+
+        let e0 = analysis::total_energy(sim.bodies(), &sim.params);
+        let mut max_err: f64 = 0.0;
+        let steps = (period / dt).round() as usize;
+        for _ in 0..steps {
+            sim.step();
+            let e = analysis::total_energy(sim.bodies(), &sim.params);
+            max_err = max_err.max(((e - e0) / e0).abs());
+        }
+
+        let end = sim.bodies()[0].pos_vec;
+        let miss = ((end[0] + a).powi(2) + end[1].powi(2)).sqrt();
+        (max_err, miss)
+    }
+
+    // a tight binary with a ring of light test particles far out
+    fn binary_with_ring(scheme: Scheme, dt: f64) -> Simulation<2> {
+        let params = Params {
+            dt,
+            integrator: scheme,
+            block_levels: 5,
+            ..Params::default()
+        };
+
+        let (m, a) = (1000.0, 5.0);
+        let v = (params.g * m / (4.0 * a)).sqrt();
+        let (r, theta) = (400.0, 0.785);
+        let v_ring = (params.g * 2.0 * m / r).sqrt();
+
+        let mut sim = Simulation::new(params);
+        sim.push_body(Body { pos_vec: [-a, 0.0], vel_vec: [0.0, -v], mass: m, charge: 0.0, radius: 0.0 });
+        sim.push_body(Body { pos_vec: [a, 0.0], vel_vec: [0.0, v], mass: m, charge: 0.0, radius: 0.0 });
+        for k in 0..4 {
+            let (sin, cos) = (k as f64 * theta).sin_cos();
+            sim.push_body(Body {
+                pos_vec: [r * cos, r * sin],
+                vel_vec: [-v_ring * sin, v_ring * cos],
+                mass: 1e-3,
+                charge: 0.0,
+                radius: 0.0
+            });
+        }
+        sim.update();
+        sim
+    }
+
+    #[test]
+    fn test_block_timesteps() {
+        let dt = 0.002;
+        let mut block = binary_with_ring(Scheme::Block, dt);
+        let mut fine = binary_with_ring(Scheme::Leapfrog, dt / 32.0);
+
+        for _ in 0..20 {
+            block.step();
+        }
+        for _ in 0..640 {
+            fine.step();
+        }
+        assert!((block.time - fine.time).abs() < 1e-12);
+
+        // the binary needs the smallest steps, the ring doesn't
+        assert_eq!(&block.levels[..2], &[5, 5]);
+        for &level in block.levels[2..].iter() {
+            assert_eq!(level, 0);
+        }
+
+        // and we end up where taking the smallest step everywhere
+        // would have put us
+        for (b, f) in block.bodies().iter().zip(fine.bodies()) {
+            for k in 0..2 {
+                assert!((b.pos_vec[k] - f.pos_vec[k]).abs() < 1e-3);
+            }
+        }
+    }
+
+    #[test]
+    fn test_block_crossing_cells() {
+        // a heavy body that starts out in the same cell as a small
+        // cluster, fast enough to cross into the other half of the root
+        // in the first substep, where a light probe feels it. With just one level,
+        // and every body on it, a block step is two leapfrog steps of
+        // half the size, each of which builds a fresh tree, so the two
+        // only agree if the partial rebuild between the substeps put
+        // the heavy body in the cell it has moved into.
+        let run = |scheme: Scheme, dt: f64, steps: usize| {
+            let params = Params {
+                dt,
+                integrator: scheme,
+                block_levels: 1,
+                block_eta: 1e-9,
+                num_threads: 1,
+                ..Params::default()
+            };
+            let mut sim = Simulation::new(params);
+            for k in 0..4 {
+                let (sin, cos) = (k as f64 * 1.57).sin_cos();
+                sim.push_body(body_at([-300.0 + 10.0 * cos, 100.0 + 10.0 * sin], 0.01));
+            }
+            let mut fast = body_at([-280.0, 90.0], 1.0);
+            fast.vel_vec = [960.0, 0.0];
+            sim.push_body(fast);
+            sim.push_body(body_at([250.0, 120.0], 1e-6));
+            sim.update();
+            for _ in 0..steps {
+                sim.step();
+            }
+            assert_eq!(sim.bodies().len(), 6);
+            sim.bodies()[5].vel_vec
+        };
+
+        let block = run(Scheme::Block, 1.0, 1);
+        let leapfrog = run(Scheme::Leapfrog, 0.5, 2);
+        let dv = (block[0] - leapfrog[0]).hypot(block[1] - leapfrog[1]);
+        assert!(dv < 1e-6 * leapfrog[0].hypot(leapfrog[1]), "{:?} {:?}", block, leapfrog);
+    }
+
+    #[test]
+    fn test_scheme_names() {
+        for scheme in SCHEMES.iter() {
+            assert_eq!(scheme.name().parse::<Scheme>(), Ok(*scheme));
+        }
+        assert!("verlet".parse::<Scheme>().is_err());
+    }
+
+    #[test]
+    fn test_orders_of_accuracy() {
+        // The orbit takes pi seconds. At this step size every scheme
+        // gets it roughly right, and the higher order ones are much
+        // closer.
+        let dt = 0.02;
+        let (euler_err, euler_miss) = orbit(Scheme::SymplecticEuler, dt);
+        let (leapfrog_err, leapfrog_miss) = orbit(Scheme::Leapfrog, dt);
+        let (rk4_err, rk4_miss) = orbit(Scheme::Rk4, dt);
+        let (yoshida_err, yoshida_miss) = orbit(Scheme::Yoshida, dt);
+
+        assert!(leapfrog_err < euler_err);
+        assert!(rk4_err < leapfrog_err);
+        assert!(yoshida_err < leapfrog_err);
+
+        assert!(leapfrog_miss < euler_miss);
+        assert!(rk4_miss < leapfrog_miss);
+        assert!(yoshida_miss < leapfrog_miss);
+    }
+}
@@ -0,0 +1,377 @@
+// A ForceLaw says how strongly two masses pull on each other at a
+// given distance. The tree walks, the FMM and the energy diagnostics
+// only ever ask the law for the pull and the potential between pairs,
+// so swapping in different physics doesn't touch any of the traversal
+// code; it's just `force_law = yukawa` (say) in the params.
+//
+// Newtonian is the usual 1/r^2 gravity whatever the dimension, which is
+// what we've always simulated, softened by params.kernel.
+//
+// Gauss is the law Gauss's theorem gives in D dimensions, where the
+// flux of a mass's field spreads out over the surface of a sphere,
+// which goes as r^(D-1): the pull is G m / r^(D-1), so 1/r in 2D and
+// the same as Newtonian in 3D.
+//
+// Yukawa is gravity screened over params.screening_length, with
+// potential -G m e^(-r/lambda) / r. Well inside lambda it's Newtonian,
+// and well outside it dies off exponentially.
+//
+// PowerLaw pulls as G m / r^n for n = params.force_exponent.
+//
+// Anything else that implements ForceLaw can be plugged in too, with
+// Law::custom, without touching the tree walk or anything else here:
+//
+//     params.force_law = Law::custom(MyLaw { ... });
+//
+// Apart from Newtonian (and Gauss in 3D, which is the same thing),
+// softening is Plummer-like: the law gets evaluated at
+// sqrt(r^2 + eps^2) for eps = params.softening, whichever softening
+// kernel is picked. Without one, masses closer than min_len still
+// don't feel each other. The multipole corrections and the FMM both
+// expand 1/r, so they only get used when is_newtonian says the law
+// really is 1/r^2; otherwise regions just pull as a point mass at their
+// com.
+
+use super::config::Params;
+use super::physics::Kernel;
+
+use std::fmt;
+use std::sync::Arc;
+
+// Every method gets told the number of dimensions, dims, rather than
+// being generic over it, so that a ForceLaw can sit behind a pointer
+// (see Law::Custom). It has to be Send and Sync, since the force
+// passes share their Params between threads.
+pub trait ForceLaw: fmt::Debug + Send + Sync {
+    // force_factor is what the displacement vector r to a mass has to
+    // be multiplied by to get its pull on us, for gm = G * mass.
+    fn force_factor(&self, r: f64, gm: f64, dims: usize, params: &Params) -> f64;
+
+    // potential is the potential (per unit mass) a distance r from the
+    // mass, going to 0 far away whenever that's possible.
+    fn potential(&self, r: f64, gm: f64, dims: usize, params: &Params) -> f64;
+
+    // is_newtonian is whether this law is plain 1/r^2 gravity in dims
+    // dimensions, which the multipole expansions assume.
+    fn is_newtonian(&self, _dims: usize) -> bool {
+        false
+    }
+}
+
+// softened is the distance we evaluate the laws other than Newtonian
+// gravity at, for two masses r apart.
+fn softened(r: f64, params: &Params) -> f64 {
+    if params.kernel == Kernel::None {
+        r
+    } else {
+        (r * r + params.softening * params.softening).sqrt()
+    }
+}
+
+#[derive(Debug)]
+pub struct Newtonian;
+
+impl ForceLaw for Newtonian {
+    fn force_factor(&self, r: f64, gm: f64, _dims: usize, params: &Params) -> f64 {
+        params.kernel.force_factor(r, gm, params.softening)
+    }
+
+    fn potential(&self, r: f64, gm: f64, _dims: usize, params: &Params) -> f64 {
+        params.kernel.potential(r, gm, params.softening)
+    }
+
+    fn is_newtonian(&self, _dims: usize) -> bool {
+        true
+    }
+}
+
+#[derive(Debug)]
+pub struct Gauss;
+
+impl ForceLaw for Gauss {
+    fn force_factor(&self, r: f64, gm: f64, dims: usize, params: &Params) -> f64 {
+        if dims == 3 {
+            return Newtonian.force_factor(r, gm, dims, params);
+        }
+        let s = softened(r, params);
+        gm / s.powi(dims as i32)
+    }
+
+    // the potential goes as r^(2-D), except in 2D where it's
+    // logarithmic (and zero at r = 1, since it can't vanish far away)
+    fn potential(&self, r: f64, gm: f64, dims: usize, params: &Params) -> f64 {
+        if dims == 3 {
+            return Newtonian.potential(r, gm, dims, params);
+        }
+        let s = softened(r, params);
+        if dims == 2 {
+            gm * s.ln()
+        } else {
+            -gm / ((dims as f64 - 2.0) * s.powi(dims as i32 - 2))
+        }
+    }
+
+    fn is_newtonian(&self, dims: usize) -> bool {
+        dims == 3
+    }
+}
+
+#[derive(Debug)]
+pub struct Yukawa {
+    pub length: f64
+}
+
+impl ForceLaw for Yukawa {
+    fn force_factor(&self, r: f64, gm: f64, _dims: usize, params: &Params) -> f64 {
+        let s = softened(r, params);
+        gm * (-s / self.length).exp() * (1.0 / s + 1.0 / self.length) / (s * s)
+    }
+
+    fn potential(&self, r: f64, gm: f64, _dims: usize, params: &Params) -> f64 {
+        let s = softened(r, params);
+        -gm * (-s / self.length).exp() / s
+    }
+}
+
+#[derive(Debug)]
+pub struct PowerLaw {
+    pub exponent: f64
+}
+
+impl ForceLaw for PowerLaw {
+    fn force_factor(&self, r: f64, gm: f64, _dims: usize, params: &Params) -> f64 {
+        let s = softened(r, params);
+        gm / s.powf(self.exponent + 1.0)
+    }
+
+    // like Gauss, logarithmic for a 1/r pull
+    fn potential(&self, r: f64, gm: f64, _dims: usize, params: &Params) -> f64 {
+        let s = softened(r, params);
+        let n = self.exponent;
+        if n == 1.0 {
+            gm * s.ln()
+        } else {
+            -gm / ((n - 1.0) * s.powf(n - 1.0))
+        }
+    }
+}
+
+// Law names one of the force laws above, for picking one at runtime.
+// The Yukawa and power law take their parameters from the Params
+// they're handed. Custom holds any other ForceLaw; it can't be picked
+// by name, only set from code.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Law {
+    Newtonian,
+    Gauss,
+    Yukawa,
+    PowerLaw,
+    Custom(Custom)
+}
+
+// Custom is a shared pointer to a ForceLaw from outside this module.
+// Two of them are only equal if they point at the same law.
+#[derive(Clone, Debug)]
+pub struct Custom(pub Arc<dyn ForceLaw>);
+
+impl PartialEq for Custom {
+    fn eq(&self, other: &Custom) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+pub const LAWS: [Law; 4] = [
+    Law::Newtonian,
+    Law::Gauss,
+    Law::Yukawa,
+    Law::PowerLaw
+];
+
+impl Law {
+
+    // custom wraps any ForceLaw up as a Law, to go in Params.
+    pub fn custom<L: ForceLaw + 'static>(law: L) -> Law {
+        Law::Custom(Custom(Arc::new(law)))
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Law::Newtonian => "newtonian",
+            Law::Gauss => "gauss",
+            Law::Yukawa => "yukawa",
+            Law::PowerLaw => "power",
+            Law::Custom(_) => "custom"
+        }
+    }
+}
+
+// A Law pulls the way the law it names does.
+impl ForceLaw for Law {
+    fn force_factor(&self, r: f64, gm: f64, dims: usize, params: &Params) -> f64 {
+        match *self {
+            Law::Newtonian => Newtonian.force_factor(r, gm, dims, params),
+            Law::Gauss => Gauss.force_factor(r, gm, dims, params),
+            Law::Yukawa => Yukawa { length: params.screening_length }
+                .force_factor(r, gm, dims, params),
+            Law::PowerLaw => PowerLaw { exponent: params.force_exponent }
+                .force_factor(r, gm, dims, params),
+            Law::Custom(Custom(ref law)) => law.force_factor(r, gm, dims, params)
+        }
+    }
+
+    fn potential(&self, r: f64, gm: f64, dims: usize, params: &Params) -> f64 {
+        match *self {
+            Law::Newtonian => Newtonian.potential(r, gm, dims, params),
+            Law::Gauss => Gauss.potential(r, gm, dims, params),
+            Law::Yukawa => Yukawa { length: params.screening_length }
+                .potential(r, gm, dims, params),
+            Law::PowerLaw => PowerLaw { exponent: params.force_exponent }
+                .potential(r, gm, dims, params),
+            Law::Custom(Custom(ref law)) => law.potential(r, gm, dims, params)
+        }
+    }
+
+    fn is_newtonian(&self, dims: usize) -> bool {
+        match *self {
+            Law::Newtonian => Newtonian.is_newtonian(dims),
+            Law::Gauss => Gauss.is_newtonian(dims),
+            Law::Custom(Custom(ref law)) => law.is_newtonian(dims),
+            _ => false
+        }
+    }
+}
+
+named_enum!(Law, LAWS, "force law");
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tree::Body;
+    use physics::analysis;
+    use testing::random_bodies;
+
+    // the pull should be minus the gradient of the potential
+    fn check_consistent<const D: usize>(law: &Law, params: &Params) {
+        let gm = 3.0;
+        for &r in [0.5, 1.0, 2.0, 7.0].iter() {
+            let h = 1e-5 * r;
+            let slope = (law.potential(r + h, gm, D, params)
+                         - law.potential(r - h, gm, D, params)) / (2.0 * h);
+            let pull = law.force_factor(r, gm, D, params) * r;
+            assert!((slope - pull).abs() < 1e-6 * pull.abs(), "{} in {}D at {}", law, D, r);
+        }
+    }
+
+    #[test]
+    fn test_potentials_match_forces() {
+        let mut params = Params {
+            screening_length: 3.0,
+            force_exponent: 2.5,
+            ..Params::default()
+        };
+        for &kernel in [Kernel::None, Kernel::Plummer].iter() {
+            params.kernel = kernel;
+            for law in LAWS.iter() {
+                check_consistent::<1>(law, &params);
+                check_consistent::<2>(law, &params);
+                check_consistent::<3>(law, &params);
+                check_consistent::<4>(law, &params);
+            }
+        }
+    }
+
+    #[test]
+    fn test_gauss_flux() {
+        // the pull times the area of a sphere, r^(D-1), is the same
+        // at every radius
+        let params = Params::default();
+        let flux1 = |r: f64| Law::Gauss.force_factor(r, 1.0, 1, &params) * r;
+        let flux2 = |r: f64| Law::Gauss.force_factor(r, 1.0, 2, &params) * r * r;
+        let flux4 = |r: f64| Law::Gauss.force_factor(r, 1.0, 4, &params) * r.powi(4);
+        for &r in [0.5, 3.0, 40.0].iter() {
+            assert!((flux1(r) - 1.0).abs() < 1e-12);
+            assert!((flux2(r) - 1.0).abs() < 1e-12);
+            assert!((flux4(r) - 1.0).abs() < 1e-12);
+        }
+
+        // in 3D it's just Newtonian gravity, and the multipoles apply
+        assert_eq!(Law::Gauss.force_factor(2.0, 1.0, 3, &params),
+                   Law::Newtonian.force_factor(2.0, 1.0, 3, &params));
+        assert!(Law::Gauss.is_newtonian(3));
+        assert!(!Law::Gauss.is_newtonian(2));
+    }
+
+    #[test]
+    fn test_yukawa_screening() {
+        let params = Params {
+            screening_length: 100.0,
+            ..Params::default()
+        };
+        let newton = Law::Newtonian.force_factor(1.0, 1.0, 3, &params);
+        let yukawa = Law::Yukawa.force_factor(1.0, 1.0, 3, &params);
+        assert!((yukawa - newton).abs() < 1e-4 * newton);
+
+        // far past the screening length there's next to nothing left
+        let far = Law::Yukawa.force_factor(2000.0, 1.0, 3, &params);
+        assert!(far < 1e-6 * Law::Newtonian.force_factor(2000.0, 1.0, 3, &params));
+    }
+
+    #[test]
+    fn test_law_in_accel() {
+        // a mass 4 away in 2D pulls 1/r with Gauss, 1/r^2 with Newton
+        let mut params = Params::default();
+        let mass = Body { pos_vec: [4.0, 0.0], vel_vec: [0.0; 2], mass: 1.0, charge: 0.0, radius: 0.0 };
+        let body = Body { pos_vec: [0.0, 0.0], vel_vec: [0.0; 2], mass: 1.0, charge: 0.0, radius: 0.0 };
+
+        let newton = body.get_classical_accel(&mass, &params)[0];
+        params.force_law = Law::Gauss;
+        let gauss = body.get_classical_accel(&mass, &params)[0];
+        assert!((newton - params.g / 16.0).abs() < 1e-9 * newton);
+        assert!((gauss - params.g / 4.0).abs() < 1e-9 * gauss);
+        assert_eq!(body.get_classical_potential(&mass, &params), params.g * 4.0f64.ln());
+    }
+
+    #[test]
+    fn test_law_names() {
+        for law in LAWS.iter() {
+            assert_eq!(law.name().parse::<Law>(), Ok(law.clone()));
+        }
+        assert!("mond".parse::<Law>().is_err());
+    }
+
+    // Spring pulls like a spring with stiffness gm, which isn't one of
+    // ours, so it can only get to the walk through Law::custom.
+    #[derive(Debug)]
+    struct Spring;
+
+    impl ForceLaw for Spring {
+        fn force_factor(&self, _r: f64, gm: f64, _dims: usize, _params: &Params) -> f64 {
+            gm
+        }
+
+        fn potential(&self, r: f64, gm: f64, _dims: usize, _params: &Params) -> f64 {
+            0.5 * gm * r * r
+        }
+    }
+
+    #[test]
+    fn test_custom_law() {
+        let mut params = Params {
+            num_threads: 1,
+            force_law: Law::custom(Spring),
+            ..Params::default()
+        };
+        assert_eq!(params.force_law.name(), "custom");
+        assert_eq!(params.force_law, params.force_law.clone());
+        assert!(params.force_law != Law::custom(Spring));
+        check_consistent::<3>(&params.force_law, &params);
+
+        // a spring's pull is linear in the distance, so a region's
+        // mass at its com pulls exactly like all of its bodies do, and
+        // the walk should match the direct sum even at a wide angle
+        let bodies = random_bodies(200, &params);
+        params.theta = 0.9;
+        let (rms, max) = analysis::force_error(&bodies, &params);
+        assert!(max < 1e-9, "rms {} max {}", rms, max);
+    }
+}
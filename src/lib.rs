@@ -0,0 +1,72 @@
+// barnes-rust as a library. Everything needed to build and step a
+// simulation lives here; the piston viewer in gfx is only compiled
+// when the "viewer" feature is turned on, so headless users don't
+// have to pull in any of the graphics crates.
+
+extern crate num_cpus;
+
+// graphics
+#[cfg(feature = "viewer")]
+extern crate piston;
+#[cfg(feature = "viewer")]
+extern crate graphics;
+#[cfg(feature = "viewer")]
+extern crate opengl_graphics;
+
+// named_enum! gives an enum that picks between a few named options
+// (with a name() for each variant, and a const list of all of them)
+// a Display that prints the name and a FromStr that parses it back,
+// so that it can be set from a config file. what is how the errors
+// refer to it.
+macro_rules! named_enum {
+    ($t:ident, $all:ident, $what:expr) => {
+        impl ::std::fmt::Display for $t {
+            fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+                write!(f, "{}", self.name())
+            }
+        }
+
+        impl ::std::str::FromStr for $t {
+            type Err = String;
+
+            fn from_str(s: &str) -> Result<$t, String> {
+                $all.iter().find(|x| x.name() == s).cloned().ok_or_else(|| format!(
+                    "unknown {} {:?}, expected one of {}", $what, s,
+                    $all.iter().map(|x| x.name()).collect::<Vec<&str>>().join(", ")
+                ))
+            }
+        }
+    }
+}
+
+// define all the modules our code is in
+pub mod data;
+pub mod config;
+pub mod tree;
+pub mod physics;
+pub mod law;
+pub mod fmm;
+pub mod ewald;
+pub mod collision;
+pub mod integrator;
+pub mod simulation;
+pub mod snapshot;
+pub mod batch;
+#[cfg(feature = "viewer")]
+pub mod gfx;
+#[cfg(test)]
+mod testing;
+
+// the public surface of the crate. Anything not re-exported here
+// should be reached through its module.
+pub use data::{generate, DIMS};
+pub use config::Params;
+pub use tree::{Body, Region, Tree};
+pub use physics::{analysis, Forces, Kernel, Opening, PostNewtonian};
+pub use integrator::{Integrator, Scheme, Timestep};
+pub use law::{ForceLaw, Law};
+pub use simulation::{Boundary, Escape, Simulation, Solver};
+pub use collision::Collisions;
+pub use batch::{Batch, Stop};
+#[cfg(feature = "viewer")]
+pub use gfx::Frame;
@@ -1,53 +1,247 @@
-// #[macro_use]
-// extern crate itertools;
+// The barnes-rust command line. There are four subcommands:
+//
+//     barnes-rust run [--scenario NAME] [--bodies N] [--input FILE]
+//                     [--viewer] [--steps N | --until T]
+//                     [--snapshot-every K] [--diagnostics-every K]
+//                     [--out DIR]
+//     barnes-rust generate SCENARIO [--bodies N] [--out FILE]
+//     barnes-rust analyze SNAPSHOT...
+//     barnes-rust force-error SNAPSHOT...
+//
+// run steps a simulation, either headless (writing diagnostics and
+// snapshots into --out) or in the piston viewer, which needs the
+// "viewer" feature. Initial conditions come from one of the gt_
+// generators or from a snapshot file written by generate.
+// force-error checks the forces the solver and opening criterion set
+// in the params give on the bodies in each snapshot against summing
+// over every pair directly.
+//
+// Every subcommand also takes [--dims D] [--config FILE]
+// [--<param> VALUE ...]. --dims picks between the 2D and 3D
+// simulations built into the binary; it defaults to whatever a
+// snapshot being read says, or to 2. Any other flag,
+// --<param>, sets a field of Params (dashes or underscores both
+// work), e.g. `--theta 0.3 --dt 0.0001`. Overrides given on the
+// command line always win over the config file.
 
-// #[macro_use] tells Rust to also import defined macros from the
-// crate we're looking at.
-
-// lazy_static allows us to generate static global variables at
-// runtime. This is incredibly useful, as it allows us to generalize
-// our simulation algorithm to higher dimensions, because we can
-// generate our MULTIPLIERS vector at runtime.
-#[macro_use]
-extern crate lazy_static;
+extern crate barnes_rust;
 
 // graphics
+#[cfg(feature = "viewer")]
 extern crate piston;
-extern crate graphics;
+#[cfg(feature = "viewer")]
 extern crate glutin_window;
+#[cfg(feature = "viewer")]
 extern crate opengl_graphics;
 
-use piston::window::WindowSettings;
-use piston::event_loop::*;
-use piston::input::*;
-use glutin_window::GlutinWindow as Window;
-use opengl_graphics::{ GlGraphics, OpenGL };
+use barnes_rust::*;
+use barnes_rust::snapshot;
 
-// This allows us to make mutable calls to our lazy_static generated
-// stuffs! Particularly useful when we're holding some global mutable
-// data that all threads need to be able to access.
-use std::sync::Mutex;
+use std::env;
+use std::path::PathBuf;
+use std::process;
 
-// define all the modules our code is in
-mod data;
-mod tree;
-mod physics;
-mod gfx;
+const USAGE: &str = "\
+usage: barnes-rust run [--scenario NAME] [--bodies N] [--input FILE] [--viewer]
+                       [--steps N | --until T] [--snapshot-every K]
+                       [--diagnostics-every K] [--out DIR]
+       barnes-rust generate SCENARIO [--bodies N] [--out FILE]
+       barnes-rust analyze SNAPSHOT...
+       barnes-rust force-error SNAPSHOT...
 
-// import all needed parts of the simulation into our current scope
-pub use data::*;
-pub use tree::*;
-pub use physics::*;
-pub use gfx::*;
+every subcommand also takes [--dims 2|3] [--config FILE] [--<param> VALUE ...]";
 
-use std::fs::File;
-use std::io::prelude::*;
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
 
-fn main() {
-    let mut file = File::create("output.txt").unwrap();
-    //write the parameters being used to the file
-    file.write_fmt(format_args!("G: {}, DT: {}\n", physics::G, DT));
+fn fail(msg: String) -> ! {
+    eprintln!("{}", msg);
+    process::exit(1);
+}
+
+// Args holds the command line after the subcommand, split into
+// positional arguments and `--flag value` pairs. Switches are flags
+// that don't take a value.
+struct Args {
+    positional: Vec<String>,
+    flags: Vec<(String, String)>,
+    switches: Vec<String>
+}
+
+const SWITCHES: [&str; 1] = ["viewer"];
 
+impl Args {
+
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Args {
+        let mut parsed = Args {
+            positional: Vec::new(),
+            flags: Vec::new(),
+            switches: Vec::new()
+        };
+
+        while let Some(arg) = args.next() {
+            if !arg.starts_with("--") {
+                parsed.positional.push(arg);
+                continue;
+            }
+
+            let name = arg[2..].replace("-", "_");
+            if SWITCHES.contains(&name.as_str()) {
+                parsed.switches.push(name);
+            } else {
+                match args.next() {
+                    Some(value) => parsed.flags.push((name, value)),
+                    None => fail(format!("{} needs a value", arg))
+                }
+            }
+        }
+        parsed
+    }
+
+    // get returns the value of a flag without removing it.
+    fn get(&self, name: &str) -> Option<String> {
+        self.flags.iter()
+            .rev()
+            .find(|&(n, _)| n == name)
+            .map(|(_, value)| value.clone())
+    }
+
+    // take removes a flag and returns its value, if it was given.
+    fn take(&mut self, name: &str) -> Option<String> {
+        let i = self.flags.iter().rposition(|(n, _)| n == name);
+        i.map(|i| self.flags.remove(i).1)
+    }
+
+    fn take_parsed<T: std::str::FromStr>(&mut self, name: &str) -> Option<T> {
+        self.take(name).map(|value| match value.parse::<T>() {
+            Ok(v) => v,
+            Err(_) => fail(format!("bad value {:?} for --{}", value, name))
+        })
+    }
+
+    fn switch(&self, name: &str) -> bool {
+        self.switches.iter().any(|s| s == name)
+    }
+
+    // params builds the run parameters from --config and whatever
+    // flags are left over once the subcommand has taken its own.
+    fn params(&mut self) -> Params {
+        let mut params = Params::default();
+
+        if let Some(path) = self.take("config") {
+            if let Err(e) = params.load_file(&path) {
+                fail(format!("couldn't read {}: {}", path, e));
+            }
+        }
+
+        for (key, value) in self.flags.iter() {
+            if let Err(e) = params.set(key, value) {
+                fail(e);
+            }
+        }
+        self.flags.clear();
+        params
+    }
+}
+
+// with_dims calls the passed function with D set to dims, which has
+// to be one of the dimensionalities we compile in.
+macro_rules! with_dims {
+    ($dims:expr, $f:ident ( $($arg:expr),* )) => {
+        match $dims {
+            2 => $f::<2>($($arg),*),
+            3 => $f::<3>($($arg),*),
+            d => fail(format!("can't simulate {} dimensions, only 2 or 3", d))
+        }
+    }
+}
+
+// snapshot_dims is the dimensionality recorded in the header of the
+// snapshot at path, or DIMS if it doesn't say.
+fn snapshot_dims(path: &str) -> usize {
+    match snapshot::read_dims(path) {
+        Ok(dims) => dims.unwrap_or(DIMS),
+        Err(e) => fail(format!("couldn't read {}: {}", path, e))
+    }
+}
+
+// Fill a fresh simulation with either the bodies in --input or the
+// generator named by --scenario.
+fn initial_conditions<const D: usize>(args: &mut Args) -> Simulation<D> {
+    let input = args.take("input");
+    let scenario = args.take("scenario")
+        .unwrap_or("rutherford_scattering".to_string());
+    let num_bodies = args.take_parsed("bodies").unwrap_or(100);
+
+    let mut sim = Simulation::new(args.params());
+
+    match input {
+        Some(path) => match snapshot::read_snapshot(&path) {
+            Ok((time, bodies)) => {
+                sim.push_bodies(&bodies);
+                sim.time = time;
+            },
+            Err(e) => fail(format!("couldn't read {}: {}", path, e))
+        },
+        None => {
+            if let Err(e) = generate::gt_by_name(&mut sim, &scenario, num_bodies) {
+                fail(e);
+            }
+        }
+    }
+    sim
+}
+
+fn run(mut args: Args) {
+    let dims = match args.take_parsed("dims") {
+        Some(dims) => dims,
+        None => args.get("input").map_or(DIMS, |path| snapshot_dims(&path))
+    };
+    with_dims!(dims, run_in(args))
+}
+
+fn run_in<const D: usize>(mut args: Args) {
+    let viewer = args.switch("viewer");
+
+    let mut batch = Batch::new(Stop::Steps(1000), PathBuf::from("out"));
+    if let Some(n) = args.take_parsed("steps") {
+        batch.stop = Stop::Steps(n);
+    }
+    if let Some(t) = args.take_parsed("until") {
+        batch.stop = Stop::Time(t);
+    }
+    if let Some(k) = args.take_parsed("snapshot_every") {
+        batch.snapshot_every = k;
+    }
+    if let Some(k) = args.take_parsed("diagnostics_every") {
+        batch.diagnostics_every = k;
+    }
+    if let Some(dir) = args.take("out") {
+        batch.out_dir = PathBuf::from(dir);
+    }
+
+    let mut sim = initial_conditions::<D>(&mut args);
+
+    if viewer {
+        run_viewer(sim);
+        return;
+    }
+
+    match batch.run(&mut sim) {
+        Ok(steps) => println!("done: {} steps, t = {}", steps, sim.time),
+        Err(e) => fail(format!("error writing output: {}", e))
+    }
+}
+
+#[cfg(feature = "viewer")]
+fn run_viewer<const D: usize>(mut sim: Simulation<D>) {
+    use piston::window::WindowSettings;
+    use piston::event_loop::*;
+    use piston::input::*;
+    use glutin_window::GlutinWindow as Window;
+    use opengl_graphics::{ GlGraphics, OpenGL };
 
     // Change this to OpenGL::V2_1 if not working.
     let opengl = OpenGL::V3_2;
@@ -62,69 +256,143 @@ fn main() {
         .build()
         .unwrap();
 
-    // use data::rand::SeedableRng;
-    // let seed: &[_] = &[1, 2, 3, 4];
-    // let seeder = SeedableRng::from_seed(seed);
-
-    // generate the main tree. First argument gives the number of
-    // masses we want to simulate, second argument passes the random
-    // generation function the rng object we've just seeded. Seeding
-    // is generally good while we're still in the testing phase, since
-    // it gives us reproducible results
-    let num_bodies = 100;
-
-    // generate::gt_all_ranges(300);
-    // generate::gt_rutherford_scattering(100);
-    //generate::gt_all_ranges(num_bodies);
-    //generate::gt_two_body();
-    //generate::gt_binary_system();
-    generate::gt_rutherford_scattering(num_bodies);
-    //generate::gt_binary_scattering(num_bodies);
+    sim.update();
 
     let mut frame = Frame {
         gl: GlGraphics::new(opengl),
-        tree: TREE_POINTER.lock().unwrap().tree.clone()
+        sim
     };
 
-    println!("done generating");
-    // for vec in MULTIPLIERS.lock().unwrap().clone().iter_mut() {
-    //     println!("splitting multiplier: {:#?}", vec);
-    // }
-
     let mut events = Events::new(EventSettings::new());
 
-    let mut counter = 0;
-
-    frame.tree.update();
-    TREE_POINTER.lock().unwrap().tree = frame.tree.clone();
-
     while let Some(e) = events.next(&mut window) {
 
-        // make sure the tree is set up correctly before
-        // trying to render or update anything
-        // but actually let's not do this because what
-        // really matters now is frame.tree
-        //let mut tree = TREE_POINTER.lock().unwrap().tree.clone();
-        //tree.update();
-        //TREE_POINTER.lock().unwrap().tree = tree;
-
         if let Some(r) = e.render_args() {
-            // println!("calling render from main");
-            frame.render(None, &r);
-            //let mut output = frame.print_masses(None);
-            //file.write_fmt(format_args!("{}", output));
-            // println!("called render from main");
+            frame.render(&r);
         }
 
         if let Some(u) = e.update_args() {
-            // let frame.tree = TREE_POINTER.lock().unwrap().tree.clone();
-            // TREE_POINTER.lock().unwrap().tree = frame.tree;
-            // println!("calling update from main");
             frame.update(&u);
-            println!("called update from main");
         }
 
     }
 
     println!("done.");
 }
+
+#[cfg(not(feature = "viewer"))]
+fn run_viewer<const D: usize>(_sim: Simulation<D>) {
+    fail("the viewer isn't available; rebuild with `--features viewer`".to_string());
+}
+
+fn generate(mut args: Args) {
+    let dims = args.take_parsed("dims").unwrap_or(DIMS);
+    with_dims!(dims, generate_in(args))
+}
+
+fn generate_in<const D: usize>(mut args: Args) {
+    if args.positional.len() != 1 {
+        usage();
+    }
+    let scenario = args.positional.remove(0);
+    let num_bodies = args.take_parsed("bodies").unwrap_or(100);
+    let out = args.take("out").unwrap_or(format!("{}.txt", scenario));
+
+    let mut sim = Simulation::<D>::new(args.params());
+    if let Err(e) = generate::gt_by_name(&mut sim, &scenario, num_bodies) {
+        fail(e);
+    }
+    sim.update();
+
+    let bodies = sim.bodies();
+    match snapshot::write_snapshot(&out, sim.time, bodies) {
+        Ok(()) => println!("wrote {} bodies to {}", bodies.len(), out),
+        Err(e) => fail(format!("couldn't write {}: {}", out, e))
+    }
+}
+
+fn analyze(mut args: Args) {
+    if args.positional.is_empty() {
+        usage();
+    }
+    let forced_dims = args.take_parsed("dims");
+    let params = args.params();
+
+    println!("# file\ttime\tbodies\tkinetic\tpotential\ttotal\tmean_radius\tmomentum");
+    for path in args.positional.iter() {
+        let dims = forced_dims.unwrap_or_else(|| snapshot_dims(path));
+        with_dims!(dims, analyze_file(path, &params));
+    }
+}
+
+// analyze_file prints the row of diagnostics for one snapshot.
+fn analyze_file<const D: usize>(path: &str, params: &Params) {
+    let (time, bodies) = match snapshot::read_snapshot::<_, D>(path) {
+        Ok(snapshot) => snapshot,
+        Err(e) => fail(format!("couldn't read {}: {}", path, e))
+    };
+
+    let kinetic = analysis::kinetic_energy(&bodies);
+    let potential = analysis::potential_energy(&bodies, params);
+    let radii = analysis::radial_distribution(&bodies);
+    let mean_radius = if radii.is_empty() {
+        0.0
+    } else {
+        radii.iter().fold(0.0, |sum, r| sum + r) / (radii.len() as f64)
+    };
+    let momentum = analysis::total_momentum(&bodies)
+        .iter()
+        .map(|p| p.to_string())
+        .collect::<Vec<String>>()
+        .join(" ");
+
+    println!(
+        "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+        path, time, bodies.len(), kinetic, potential,
+        kinetic + potential, mean_radius, momentum
+    );
+}
+
+fn force_error(mut args: Args) {
+    if args.positional.is_empty() {
+        usage();
+    }
+    let forced_dims = args.take_parsed("dims");
+    let params = args.params();
+
+    println!("# file\tbodies\tsolver\ttheta\trms\tmax");
+    for path in args.positional.iter() {
+        let dims = forced_dims.unwrap_or_else(|| snapshot_dims(path));
+        with_dims!(dims, force_error_file(path, &params));
+    }
+}
+
+// force_error_file prints the rms and max relative force error for one
+// snapshot.
+fn force_error_file<const D: usize>(path: &str, params: &Params) {
+    let (_, bodies) = match snapshot::read_snapshot::<_, D>(path) {
+        Ok(snapshot) => snapshot,
+        Err(e) => fail(format!("couldn't read {}: {}", path, e))
+    };
+
+    let (rms, max) = analysis::force_error(&bodies, params);
+    println!(
+        "{}\t{}\t{}\t{}\t{}\t{}",
+        path, bodies.len(), params.solver, params.theta, rms, max
+    );
+}
+
+fn main() {
+    let mut argv = env::args().skip(1);
+    let command = argv.next().unwrap_or_else(|| usage());
+    let args = Args::parse(argv);
+
+    match command.as_str() {
+        "run" => run(args),
+        "generate" => generate(args),
+        "analyze" => analyze(args),
+        "force-error" => force_error(args),
+        "help" | "--help" | "-h" => println!("{}", USAGE),
+        _ => usage()
+    }
+}
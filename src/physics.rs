@@ -5,18 +5,267 @@
 pub use super::tree::*;
 pub use super::data::*;
 
-// Fetch global statics from the main function
-pub use super::data::{DIMS, TREE_POINTER, DT, THETA};
-
+pub use super::data::DIMS;
+use super::config::Params;
+use super::law::{ForceLaw, Law};
+use super::simulation::Boundary;
+use super::ewald;
+
+// G is only the default for Params::g; the force routines all read
+// the value from the Params they're handed.
 // let const G: f64 = (6.674 / (1_000_000_000_00.0));
 //note: for analysis calculations, G = 16000
 pub const G: f64 = 16000.0;
-use std::sync::{Arc, Mutex};
 
-impl Body {
+// Kernel is the softening applied to the gravity between two masses,
+// which keeps close encounters from blowing up.
+//
+// None is plain Newtonian gravity, except that masses closer than
+// min_len don't feel each other at all. That cutoff makes the force
+// jump, so energy isn't conserved through close encounters.
+//
+// Plummer treats every mass as a Plummer sphere of radius softening:
+// the potential is -G m / sqrt(r^2 + eps^2) everywhere.
+//
+// Spline is the cubic spline kernel Gadget uses. The mass is spread
+// out over a sphere of radius h = 2.8 * softening (so the potential
+// at r = 0 matches a Plummer sphere of radius softening), and beyond
+// h gravity is exactly Newtonian.
+//
+// All three only ever give a pair of masses at exactly the same spot
+// no force and no potential, which is how a body's own leaf drops
+// out of the tree walks.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kernel {
+    None,
+    Plummer,
+    Spline
+}
+
+pub const KERNELS: [Kernel; 3] = [
+    Kernel::None,
+    Kernel::Plummer,
+    Kernel::Spline
+];
+
+// the radius of the spline kernel, in units of the softening length
+pub const SPLINE_RADIUS: f64 = 2.8;
+
+impl Kernel {
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Kernel::None => "none",
+            Kernel::Plummer => "plummer",
+            Kernel::Spline => "spline"
+        }
+    }
+
+    // force_factor is what the displacement vector r to a mass has to
+    // be multiplied by to get its pull, for gm = G * mass. Without
+    // softening that's gm / r^3.
+    pub fn force_factor(&self, r: f64, gm: f64, eps: f64) -> f64 {
+        match *self {
+            Kernel::None => gm / (r * r * r),
+            Kernel::Plummer => gm / (r * r + eps * eps).powf(1.5),
+            Kernel::Spline => {
+                let h = SPLINE_RADIUS * eps;
+                let u = r / h;
+                let h3 = h * h * h;
+                if u < 0.5 {
+                    gm / h3 * (32.0 / 3.0 + u * u * (32.0 * u - 38.4))
+                } else if u < 1.0 {
+                    gm / h3 * (64.0 / 3.0 - 48.0 * u + 38.4 * u * u
+                               - 32.0 / 3.0 * u * u * u - 1.0 / (15.0 * u * u * u))
+                } else {
+                    gm / (r * r * r)
+                }
+            }
+        }
+    }
+
+    // potential is the potential (per unit mass) at distance r from a
+    // mass, for gm = G * mass.
+    pub fn potential(&self, r: f64, gm: f64, eps: f64) -> f64 {
+        match *self {
+            Kernel::None => -gm / r,
+            Kernel::Plummer => -gm / (r * r + eps * eps).sqrt(),
+            Kernel::Spline => {
+                let h = SPLINE_RADIUS * eps;
+                let u = r / h;
+                let (u2, u3) = (u * u, u * u * u);
+                if u < 0.5 {
+                    gm / h * (16.0 / 3.0 * u2 - 48.0 / 5.0 * u2 * u2
+                              + 32.0 / 5.0 * u3 * u2 - 14.0 / 5.0)
+                } else if u < 1.0 {
+                    gm / h * (1.0 / (15.0 * u) + 32.0 / 3.0 * u2 - 16.0 * u3
+                              + 48.0 / 5.0 * u2 * u2 - 32.0 / 15.0 * u3 * u2 - 16.0 / 5.0)
+                } else {
+                    -gm / r
+                }
+            }
+        }
+    }
+}
+
+named_enum!(Kernel, KERNELS, "kernel");
+
+// Opening is the test the tree walks use to decide whether a region is
+// far enough away to take as a whole, or has to be opened up. For a
+// region of side l = 2 * half_length and a body at distance d from
+// its centre, or r from its com:
+//
+// Geometric is the classic Barnes-Hut test l / d <= theta, measured
+// to the middle of the region whatever the masses inside are doing.
+//
+// Com is the same thing measured to the com, l / r <= theta.
+//
+// Bmax is Barnes' refinement, bmax / r <= theta, where bmax is how far
+// the furthest corner of the region is from its com. A region whose
+// com sits off in one corner has to be further away to get accepted.
+//
+// SalmonWarren accepts the region once r is past
+//
+//     bmax / 2 + sqrt(bmax^2 / 4 + sqrt(3 G B2 / err))
+//
+// where B2 = sum m y^2 over the masses in it (the trace of its
+// quadrupole), which bounds the error of leaving out everything past
+// the monopole by err.
+//
+// Relative is Gadget's test, accepting the region when its
+// quadrupole-sized error G M l^2 / r^4 is less than err (and we're
+// not right up against it).
+//
+// The last two both take err = opening_tolerance * |a|, where |a| is
+// the size of the body's acceleration at the last step, so they bound
+// the error relative to the body's total acceleration. Without one
+// (on the very first step) they fall back to the geometric test.
+//
+// None of them ever accept a region that the body itself is inside,
+// however wide theta is.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Opening {
+    Geometric,
+    Com,
+    Bmax,
+    SalmonWarren,
+    Relative
+}
+
+pub const OPENINGS: [Opening; 5] = [
+    Opening::Geometric,
+    Opening::Com,
+    Opening::Bmax,
+    Opening::SalmonWarren,
+    Opening::Relative
+];
+
+impl Opening {
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Opening::Geometric => "geometric",
+            Opening::Com => "com",
+            Opening::Bmax => "bmax",
+            Opening::SalmonWarren => "salmon_warren",
+            Opening::Relative => "relative"
+        }
+    }
+}
+
+named_enum!(Opening, OPENINGS, "opening criterion");
+
+// Forces says which interactions the bodies feel: Gravity between
+// their masses, Coulomb forces between their charges (with coupling
+// params.coulomb_k, so like charges repel), or Both. Coulomb forces
+// follow params.force_law and params.kernel just like gravity does,
+// only with the sign flipped, and a body with no mass doesn't get
+// pushed around by them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Forces {
+    Gravity,
+    Coulomb,
+    Both
+}
+
+pub const FORCES: [Forces; 3] = [
+    Forces::Gravity,
+    Forces::Coulomb,
+    Forces::Both
+];
+
+impl Forces {
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Forces::Gravity => "gravity",
+            Forces::Coulomb => "coulomb",
+            Forces::Both => "both"
+        }
+    }
+
+    pub fn gravity(&self) -> bool {
+        *self != Forces::Coulomb
+    }
+
+    pub fn coulomb(&self) -> bool {
+        *self != Forces::Gravity
+    }
+}
+
+named_enum!(Forces, FORCES, "forces");
+
+// PostNewtonian picks which general relativistic corrections get
+// added to the gravity between pairs of bodies the walks resolve one
+// at a time (never for whole regions): OnePn is the first order
+// correction, which makes orbits precess; RadiationReaction is the
+// 2.5PN term, which drains energy out of orbits the way gravitational
+// waves would, so binaries spiral in; Both is both. They're the terms
+// for an isolated pair in harmonic coordinates (Blanchet, Living
+// Reviews in Relativity 17, 2), in powers of 1 / params.speed_of_light,
+// and only make sense in 3D with Newtonian gravity, although nothing
+// stops you asking for them elsewhere.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PostNewtonian {
+    None,
+    OnePn,
+    RadiationReaction,
+    Both
+}
+
+pub const POST_NEWTONIANS: [PostNewtonian; 4] = [
+    PostNewtonian::None,
+    PostNewtonian::OnePn,
+    PostNewtonian::RadiationReaction,
+    PostNewtonian::Both
+];
+
+impl PostNewtonian {
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            PostNewtonian::None => "none",
+            PostNewtonian::OnePn => "1pn",
+            PostNewtonian::RadiationReaction => "2.5pn",
+            PostNewtonian::Both => "both"
+        }
+    }
+
+    pub fn one_pn(&self) -> bool {
+        *self == PostNewtonian::OnePn || *self == PostNewtonian::Both
+    }
+
+    pub fn radiation_reaction(&self) -> bool {
+        *self == PostNewtonian::RadiationReaction || *self == PostNewtonian::Both
+    }
+}
+
+named_enum!(PostNewtonian, POST_NEWTONIANS, "post-newtonian terms");
+
+impl<const D: usize> Body<D> {
 
-    // We need r^2 in Newton's law of gravity (TODO: apply small GR
-    // perturbation), and it's faster to have separately defined
+    // We need r^2 in Newton's law of gravity (post-Newtonian
+    // corrections are in get_pn_accel), and it's faster to have separately defined
     // methods for this and finding the magnitude of the displacement
     // vector between the two bodies, because of the way we're
     // constructing net accelerations.
@@ -25,31 +274,32 @@ impl Body {
     // and iterates through pairs of coordinates in the calling Body's
     // position and the passed mass's position to return r^2.
 
-    pub fn squared_dist_to(&self, mass: &Body) -> f64 {
+    pub fn squared_dist_to(&self, mass: &Body<D>) -> f64 {
         // println!("called squared_dist_to");
         self.pos_vec
             .iter()
             .zip(&mass.pos_vec)
-            .fold(0.0,(|sum,(qi, pi)| sum + (qi - pi).powi(2)))
+            .fold(0.0,|sum,(qi, pi)| sum + (qi - pi).powi(2))
     }
 
-    pub fn node_sq_dist_to(&self, node: &Region) -> f64 {
+    pub fn node_sq_dist_to(&self, node: &Region<D>) -> f64 {
         // println!("called node_sq_dist_to");
         // println!("woooo {:#?}, {:#?}", &node.coord_vec, self.pos_vec);
         self.pos_vec
             .iter()
             .zip(&node.coord_vec)
-            .fold(0.0,(|sum,(qi, pi)| sum + (qi - pi).powi(2)))
+            .fold(0.0,|sum,(qi, pi)| sum + (qi - pi).powi(2))
     }
 
     // vec_rel gets the displacement vector between the calling mass
     // and some other passed Body.
-    pub fn vec_rel(&self, mass: &Body) -> Vec<f64> {
+    pub fn vec_rel(&self, mass: &Body<D>) -> [f64; D] {
         // println!("called vec_rel");
-        self.pos_vec.iter()
-            .zip(&mass.pos_vec)
-            .map(|(pi, mi)| mi - pi)
-            .collect::<Vec<f64>>()
+        let mut rel = [0.0; D];
+        for (ri, (pi, mi)) in rel.iter_mut().zip(self.pos_vec.iter().zip(&mass.pos_vec)) {
+            *ri = mi - pi;
+        }
+        rel
     }
 
     // sq_magnitude should really probably just be its own function,
@@ -60,408 +310,613 @@ impl Body {
     // functionally equivalent (but this is slower), because we don't
     // always need to find the displacement vector.
 
-    pub fn sq_magnitude(&self, vec: &Vec<f64>) -> f64 {
+    pub fn sq_magnitude(&self, vec: &[f64; D]) -> f64 {
         vec.iter().fold(0.0, |sum, vi| sum + vi.powi(2))
     }
 
     // is_far calculates a distance metric between the calling mass
-    // (which really should only ever be the com of a leaf node in the
-    // tree) and a passed region.
+    // and a passed region, telling us whether the region is far
+    // enough away that we can treat it as a single mass sitting at
+    // its com, going by params.opening. a_old is the size of our
+    // acceleration at the last step, for the criteria that need it,
+    // or 0 if we don't know it. The region is the one at index n in
+    // tree.
+    pub fn is_far_with(&self, tree: &Tree<D>, n: usize, params: &Params, a_old: f64) -> bool {
+        let node = &tree.regions[n];
+        if node.contains(self) {
+            return false;
+        }
 
-    pub fn is_far(&self, node_arc: Arc<Mutex<Region>>) -> bool {
-        // println!("called is_far");
+        let com = match node.com {
+            Some(ref com) => com,
+            None => return true
+        };
+        let r = self.squared_dist_to(com).sqrt();
+
+        match params.opening {
+            Opening::Geometric => self.is_far(node, params),
+            Opening::Com => 2.0 * node.half_length <= params.theta * r,
+            Opening::Bmax => node.bmax() <= params.theta * r,
+            Opening::SalmonWarren | Opening::Relative if a_old <= 0.0 => {
+                self.is_far(node, params)
+            },
+            Opening::SalmonWarren => {
+                let err = params.opening_tolerance * a_old;
+                let b2 = tree.quads.get(n).map_or(0.0, |quad| (0..D).fold(0.0, |sum, k| sum + quad[k][k]));
+                let bmax = node.bmax();
+                let r_crit = 0.5 * bmax
+                    + (0.25 * bmax * bmax + (3.0 * params.g * b2 / err).sqrt()).sqrt();
+                r > r_crit
+            },
+            Opening::Relative => {
+                // like Gadget, we also open anything we're close to the
+                // edge of, i.e. inside of once it's stretched by 20%,
+                // where a lopsided region can fool the test
+                let near = self.pos_vec.iter()
+                    .zip(node.coord_vec.iter())
+                    .all(|(xk, ck)| (xk - ck).abs() < 1.2 * node.half_length);
+                let err = params.opening_tolerance * a_old;
+                let l = 2.0 * node.half_length;
+                !near && params.g * com.mass * l * l <= err * r * r * r * r
+            }
+        }
+    }
+
+    // is_far is the classic geometric test, l / d <= theta for the
+    // distance d to the middle of the region.
+    pub fn is_far(&self, node: &Region<D>, params: &Params) -> bool {
         // this makes me think we should store full-length instead of
         // half-length FIXME
-        // FIXME: make sure this doesn't allow infinite loops;
-        // i.e. that node.com will only be none if there's stuff
-        // in the region_vec or add_queue.
-        // println!("wheee {:#?}", self.node_sq_dist_to(&node));
-        //Note: nodes are now guaranteed to have valid com when this is called
-        let node = node_arc.try_lock().unwrap();
-        ( 2.0 * node.half_length / self.node_sq_dist_to(&node).sqrt())
-        // ( 2.0 * node.half_length / self.squared_dist_to(&node.com.clone().unwrap()))
-            <= THETA
+        ( 2.0 * node.half_length / self.node_sq_dist_to(node).sqrt())
+            <= params.theta
     }
 
-    pub fn get_classical_accel(&self, mass: &Body) -> Vec<f64> {
+    // get_classical_accel is the acceleration of the calling body due
+    // to the passed one, from whichever of gravity and Coulomb forces
+    // params.forces turns on.
+    pub fn get_classical_accel(&self, mass: &Body<D>, params: &Params) -> [f64; D] {
+        let mut accel = if params.forces.gravity() {
+            self.get_gravity_accel(mass, params)
+        } else {
+            [0.0; D]
+        };
+
+        if params.forces.coulomb() {
+            let coulomb = self.get_coulomb_accel(mass, params);
+            for (ai, ci) in accel.iter_mut().zip(coulomb.iter()) {
+                *ai += ci;
+            }
+        }
+        accel
+    }
 
-        // this doesn't appear to work
-        // if (self as *const _ == mass as *const _) {
-        //     println!("\n\n\n FOUND SELF \n\n\n");
-        // };
+    // get_coulomb_accel is the push the passed body's charge gives the
+    // calling one's: the same law as gravity with G m swapped for
+    // coulomb_k q, and scaled by -q / m for our own charge and mass.
+    pub fn get_coulomb_accel(&self, mass: &Body<D>, params: &Params) -> [f64; D] {
+        if mass.charge == 0.0 || self.charge == 0.0 || self.mass == 0.0 {
+            return [0.0; D];
+        }
+
+        let mut rel = self.vec_rel(mass);
+        let r = self.sq_magnitude(&rel).sqrt();
+        if r == 0.0 || (r <= params.min_len && params.kernel == Kernel::None) {
+            return [0.0; D];
+        }
+
+        let kq = params.coulomb_k * mass.charge;
+        let factor = params.force_law.force_factor(r, kq, D, params) * (-self.charge / self.mass);
+        for ri in rel.iter_mut() {
+            *ri *= factor;
+        }
+        rel
+    }
+
+    // get_gravity_accel is the pull of the passed body's mass on the
+    // calling one.
+    pub fn get_gravity_accel(&self, mass: &Body<D>, params: &Params) -> [f64; D] {
 
         //if the other body has no mass, just return 0
         if mass.mass == 0.0 {
-            return vec![0.0; DIMS];
+            return [0.0; D];
         }
 
-        // println!("called get_classical_accel");
         let rel = self.vec_rel(mass);
-        // println!("{:?}", rel);
         let sq_mag = self.sq_magnitude(&rel);
-        // println!("{}, {:#?}", sq_mag, rel);
-        let acc = mass.mass * G / sq_mag;
-        // println!("{:?}", acc);
+        let acc = mass.mass * params.g / sq_mag;
         let r = sq_mag.sqrt();
 
         //if the distance is small, just return 0
         //note that floats are weird, so the same mass
         //could have a nonzero distance to itself
-        if r <= MIN_LEN {
-            // println!("{:?}", r);
-            return vec![0.0; DIMS];
+        if r <= params.min_len && params.kernel == Kernel::None {
+            return [0.0; D];
         }
 
-        let result = rel.iter()
-                        .map(|ri| (ri/r) * acc)
-                        .collect::<Vec<f64>>();
-        //println!("acceleration: {:#?}", result);
-        result
+        let mut accel = rel;
+        if params.kernel == Kernel::None && params.force_law == Law::Newtonian {
+            for ai in accel.iter_mut() {
+                *ai = (*ai/r) * acc;
+            }
+        } else if r > 0.0 {
+            let factor = params.force_law.force_factor(r, mass.mass * params.g, D, params);
+            for ai in accel.iter_mut() {
+                *ai *= factor;
+            }
+        }
+        accel
     }
 
-    pub fn get_classical_potential(&self, mass: &Body) -> Vec<f64> {
-        // use super::G;
-        let rel = self.vec_rel(&mass);
-        let sq_mag = self.sq_magnitude(&rel);
-        // println!("{}, {:#?}", sq_mag, rel);
-        let r = sq_mag.sqrt();
-        let pot = mass.mass * G / r;
+    // get_classical_potential gives the potential (per unit mass) at
+    // the calling body due to the passed mass, under params.force_law
+    // and softened by params.kernel. The Coulomb part depends on our
+    // own charge too, which is why it's per unit of our mass; times
+    // our mass, it's the potential energy of the pair.
+    pub fn get_classical_potential(&self, mass: &Body<D>, params: &Params) -> f64 {
+        let r = self.squared_dist_to(mass).sqrt();
 
         //if the distance is 0, just return 0
-        if(r == 0.0) {
-            return vec![0.0; DIMS];
+        if r == 0.0 {
+            return 0.0;
         }
 
-        rel.iter()
-            .map(|ri| ri * pot/r)
-            .collect::<Vec<f64>>()
+        let mut phi = 0.0;
+        if params.forces.gravity() {
+            phi += params.force_law.potential(r, mass.mass * params.g, D, params);
+        }
+        if params.forces.coulomb() && mass.charge != 0.0 && self.charge != 0.0 && self.mass != 0.0 {
+            let kq = params.coulomb_k * mass.charge;
+            phi -= self.charge / self.mass * params.force_law.potential(r, kq, D, params);
+        }
+        phi
     }
 
-    pub fn update_accel(&self, acc: Vec<f64>, mass_arc: Arc<Mutex<Body>>) -> Vec<f64> {
-        // println!("called update_accel");
-        let mass = mass_arc.try_lock().unwrap();
-        acc.iter()
-            .zip(self.get_classical_accel(&mass))
-            .map(|(acc_self, acc_other)| acc_self + acc_other)
-            .collect::<Vec<f64>>()
+    pub fn update_accel(&self, acc: [f64; D], mass: &Body<D>, params: &Params) -> [f64; D] {
+        let mut acc = acc;
+        for (acc_self, acc_other) in acc.iter_mut().zip(self.get_classical_accel(mass, params).iter()) {
+            *acc_self += acc_other;
+        }
+        acc
     }
 
-    pub fn get_total_acc(&mut self, node_arc: Arc<Mutex<Region>>) -> Vec<f64> {
-        //println!("called get_total_acc");
-        let mut acc = vec![0.0; DIMS];
-        let mut match_me =
-            node_arc
-            .try_lock()
-            .unwrap()
-            .reg_vec.clone();
-        match match_me {
-            //if this is a leaf, find the acceleration between us and its com
-            None => {
-                match node_arc.try_lock().unwrap().com.clone() {
-                    None => {
-                        acc
-                    },
-                    Some(com_arc) => {
-                        // println!("matched some on subarm of None");
-
-                        let com = com_arc.try_lock().unwrap().clone();
-                        let total_acc = self.update_accel(acc.clone(), Arc::new(Mutex::new(com)));
-                        //println!("acceleration component: {:#?}", total_acc); // this is never called on singularities
-                        // acc = acc.iter()
-                        //     .zip(total_acc.iter())
-                        //     .map(|(u,v)| u+v)
-                        //     .collect::<Vec<f64>>();
-
-
-                        total_acc
-                    }
-                }
-            },
-            //if this node has children, find the acceleration from each of them
-            Some(_) => {
-                // println!("matched Some on first arm of get_totall_acc");
-                // println!("try_locked node_arc and entered the match btry_lock. Matched on Some");
-                // println!("has reg_vec");
-                let match_me_too = node_arc.try_lock().unwrap().com.clone();
-                match match_me_too {
-
-                    None => {
-                        //we really shouldn't get here
-                        node_arc.try_lock().unwrap().update_com();
-                        self.get_total_acc(Arc::clone(&node_arc))
-                    },
-
-                    Some(ref com_arc) => {
-                        // println!("matched Some on subarm of Some");
-                        if self.is_far(Arc::clone(&node_arc)) {
-                           // println!("was far");
-                            let total_acc = self.update_accel(vec![0.0; DIMS], Arc::clone(com_arc));
-                            //println!("acceleration component: {:#?}", total_acc);
-                            // this is always 0 when stuff doesn't move, for some reason
-                            // acc = acc
-                            //     .iter()
-                            //     .zip(total_acc.iter())
-                            //     .map(|(u,v)| u+v).collect::<Vec<f64>>();
-                            if com_arc.try_lock().unwrap().clone().mass > 0.0 {
-                                //println!("{:#?}, {:#?}", total_acc, com_arc.try_lock().unwrap().clone());
-                            }
-                            total_acc
-                        } else {
-                            //println!("wasn't far from: {:#?}", com_arc.try_lock().unwrap().clone());
-                            for mut child in match_me.unwrap().iter() {
-                                let total_acc = self.get_total_acc(Arc::clone(child));
-                                // println!("acceleration component: {:#?}", total_acc);
-                                acc = acc.iter().zip(total_acc
-                                        .iter()).map(|(u,v)| u+v).collect::<Vec<f64>>();
-                                //println!("{:#?}: ", acc);
-                            }
-                            acc
-                        }
-                    }
-                }
+    // update_direct_accel is update_accel for a pair of bodies that we
+    // resolve directly rather than as part of a region, which also
+    // get the post-Newtonian corrections params.post_newtonian asks
+    // for. The passed body has to have its real velocity.
+    pub fn update_direct_accel(&self, acc: [f64; D], mass: &Body<D>, params: &Params) -> [f64; D] {
+        let mut acc = self.update_accel(acc, mass, params);
+        if params.post_newtonian != PostNewtonian::None && params.forces.gravity() {
+            for (ai, pi) in acc.iter_mut().zip(self.get_pn_accel(mass, params).iter()) {
+                *ai += pi;
             }
         }
+        acc
     }
 
-    pub fn update_vel(&mut self) {
-        // println!("called update_vel");
-        //TODO: we shouldn't have to be cloning vel_vec, so let's find a better way
-        //TODO: tree should be a reference so we don't have to copy it every time
-        // println!("updating vel");
-        // println!("old velocity component: {:#?}", self.vel_vec[0]);
-        let mut tree = TREE_POINTER.try_lock().unwrap().tree.clone();
-        // for child in tree.reg_vec.clone().unwrap() {
-        //     let new_child = child.try_lock().unwrap().clone();
-        //     // println!("{:#?}", child);
-        //     let new_child = Arc::new(Mutex::new(new_child));
-        //     let mut vel_vec = self.vel_vec.clone();
+    // get_pn_accel is the post-Newtonian correction to the pull of
+    // the passed body on the calling one (see PostNewtonian). With n
+    // the unit vector from it to us, v = v1 - v2 our relative velocity
+    // and m1 our mass,
+    //
+    //     a_1PN = [5 G^2 m1 m2 / r^3 + 4 G^2 m2^2 / r^3
+    //              + G m2 / r^2 (3/2 (n.v2)^2 - v1^2 + 4 v1.v2 - 2 v2^2)] n / c^2
+    //           + G m2 / r^2 (4 n.v1 - 3 n.v2) v / c^2
+    //
+    //     a_2.5PN = [208/15 G^3 m1 m2^2 / r^4 - 24/5 G^3 m1^2 m2 / r^4
+    //                + 12/5 G^2 m1 m2 v^2 / r^3] (n.v) n / c^5
+    //             + [8/5 G^3 m1^2 m2 / r^4 - 32/5 G^3 m1 m2^2 / r^4
+    //                - 4/5 G^2 m1 m2 v^2 / r^3] v / c^5
+    pub fn get_pn_accel(&self, mass: &Body<D>, params: &Params) -> [f64; D] {
+        let mut acc = [0.0; D];
+        if mass.mass == 0.0 {
+            return acc;
+        }
 
-        //     let mut vel_vec = vel_vec.iter_mut().zip(
-        //         self.get_total_acc(new_child))
-        //     .map(|(vi, ai)| *vi + ai * DT).collect::<Vec<f64>>();
-        //     self.vel_vec = vel_vec;
-        // }
+        let r = self.squared_dist_to(mass).sqrt();
+        if r == 0.0 || (r <= params.min_len && params.kernel == Kernel::None) {
+            return acc;
+        }
 
-        //println!("\n\ntrying to update acceleration...");
+        let mut n = [0.0; D];
+        let mut v = [0.0; D];
+        for k in 0..D {
+            n[k] = (self.pos_vec[k] - mass.pos_vec[k]) / r;
+            v[k] = self.vel_vec[k] - mass.vel_vec[k];
+        }
+        let dot = |a: &[f64; D], b: &[f64; D]| (0..D).fold(0.0, |sum, k| sum + a[k] * b[k]);
+
+        let (gm1, gm2) = (params.g * self.mass, params.g * mass.mass);
+        let (r2, r3, r4) = (r * r, r * r * r, r * r * r * r);
+        let c = params.speed_of_light;
+        let (c2, c5) = (c * c, c * c * c * c * c);
+
+        let (v1, v2) = (&self.vel_vec, &mass.vel_vec);
+        let (n_v, n_v1, n_v2) = (dot(&n, &v), dot(&n, v1), dot(&n, v2));
+        let v_sq = dot(&v, &v);
+
+        let (mut along_n, mut along_v) = (0.0, 0.0);
+        if params.post_newtonian.one_pn() {
+            along_n += (5.0 * gm1 * gm2 / r3 + 4.0 * gm2 * gm2 / r3
+                        + gm2 / r2 * (1.5 * n_v2 * n_v2 - dot(v1, v1)
+                                      + 4.0 * dot(v1, v2) - 2.0 * dot(v2, v2))) / c2;
+            along_v += gm2 / r2 * (4.0 * n_v1 - 3.0 * n_v2) / c2;
+        }
+        if params.post_newtonian.radiation_reaction() {
+            along_n += (208.0 / 15.0 * gm1 * gm2 * gm2 / r4 - 24.0 / 5.0 * gm1 * gm1 * gm2 / r4
+                        + 12.0 / 5.0 * gm1 * gm2 * v_sq / r3) * n_v / c5;
+            along_v += (8.0 / 5.0 * gm1 * gm1 * gm2 / r4 - 32.0 / 5.0 * gm1 * gm2 * gm2 / r4
+                        - 4.0 / 5.0 * gm1 * gm2 * v_sq / r3) / c5;
+        }
 
-        self.vel_vec = tree.reg_vec.clone().unwrap().iter().fold(
-                self.vel_vec.clone(), |vel, child| vel.iter().zip(
-                    self.get_total_acc(child.clone())
-                    ).map(|(vi, ai)| vi + ai * DT).collect::<Vec<f64>>()
-        )
+        for k in 0..D {
+            acc[k] = along_n * n[k] + along_v * v[k];
+        }
+        acc
+    }
 
-        // println!("new velocity component: {:#?}", self.vel_vec[0]);
+    // nearest_image is the copy of the calling body, in a periodic box,
+    // that's nearest to the passed one, i.e. within half a box of it
+    // along every axis. That's None outside of a periodic box, or when
+    // we're already the nearest copy.
+    pub fn nearest_image(&self, mass: &Body<D>, params: &Params) -> Option<Body<D>> {
+        if params.boundary != Boundary::Periodic {
+            return None;
+        }
+        let l = 2.0 * params.max_len;
+        let mut image: Option<Body<D>> = None;
+        for k in 0..D {
+            let d = self.pos_vec[k] - mass.pos_vec[k];
+            if d.abs() > params.max_len {
+                image.get_or_insert_with(|| self.clone()).pos_vec[k] -= l * (d / l).round();
+            }
+        }
+        image
     }
 
-    //TODO: make update_pos use functional programming
-    pub fn update_pos(&mut self) {
-        // println!("called update_pos");
-        for (pi, vi) in self.pos_vec.iter_mut().zip( self.vel_vec.clone() ) {
-            *pi += vi*DT;
+    // update_ewald_accel adds the pull of every periodic copy of the
+    // passed body other than the nearest, which we have to be, to acc
+    // (see ewald). That's only worked out for Newtonian gravity in 3D,
+    // and for anything else acc is left as it was.
+    pub fn update_ewald_accel(&self, acc: [f64; D], mass: &Body<D>, params: &Params) -> [f64; D] {
+        if !self.has_ewald(params) {
+            return acc;
+        }
+        let (d, _) = self.multipole_offset(mass);
+        let mut acc = acc;
+        let box_len = 2.0 * params.max_len;
+        for (ai, ei) in acc.iter_mut().zip(ewald::accel(&d, params.g * mass.mass, box_len).iter()) {
+            *ai += ei;
         }
+        acc
+    }
 
-        // TODO update the normalized coordinates too
+    // get_ewald_potential is the same for the potential. Like
+    // get_classical_potential, a body doesn't count its own copies.
+    pub fn get_ewald_potential(&self, mass: &Body<D>, params: &Params) -> f64 {
+        let (d, r2) = self.multipole_offset(mass);
+        if !self.has_ewald(params) || r2 == 0.0 {
+            return 0.0;
+        }
+        ewald::potential(&d, params.g * mass.mass, 2.0 * params.max_len)
+    }
 
+    fn has_ewald(&self, params: &Params) -> bool {
+        D == 3 && params.boundary == Boundary::Periodic
+            && params.forces.gravity() && params.force_law.is_newtonian(D)
     }
-}
 
-impl Region {
-
-    // Recursively update the accelerations and velocities of masses
-    pub fn deep_update_vel(&mut self) {
-        // println!("called deep_update_vel");
-        match self.reg_vec.clone() {
-            //if we're at the leaf node, call update_vel if we have a mass
-            None => {
-                // println!("matched None in first arm of update_vel");
-                match self.com.clone() {
-                    None => {
-                        // println!("matched None on subarm");
-                        ()
-                    },
-                    Some(com_arc) => {
-                        // println!("matched Some on subarm");
-                        let mut com_clone = com_arc.try_lock().unwrap().clone();
-                        com_clone.update_vel();
-                        self.com = Some(Arc::new(Mutex::new(com_clone)));
-                        // drop(com_arc);
-                        //TODO: find out if it's actually necessary to re-wrap this
-                    }
-                }
-            },
-            //if we have children, call recursively
-            Some(ref mut reg_vec) => {
-                // println!("matched Some in first arm of deep_update_vel");
-                let mut temp: Vec<Arc<Mutex<Region>>> = Vec::new();
-                for child in reg_vec {
-                    let mut child_clone = child.try_lock().unwrap().clone();
-                    child_clone.deep_update_vel();
-                    temp.push(Arc::new(Mutex::new(child_clone)));
-                    // drop(child);
-                }
-                self.reg_vec = Some(temp);
-            }
-        }
+    // get_total_acc walks the tree from the root. Leaves and regions
+    // that are far enough away contribute the acceleration due to
+    // their com; anything else gets opened up and its children are
+    // looked at instead. The calling body's own leaf contributes
+    // nothing, since get_classical_accel drops masses at distance 0.
+    pub fn get_total_acc(&self, tree: &Tree<D>, params: &Params) -> [f64; D] {
+        self.get_total_acc_with(tree, params, 0.0)
     }
 
-    // Recursively update the positions of masses
-    pub fn deep_update_pos(&mut self) {
-        // println!("deep updating pos");
-        match self.reg_vec.clone() {
-            //if we're at the leaf node, call update_pos if we have a mass
-            None => {
-                // println!("matched None in first arm of deep_update_pos");
-                match self.com.clone() {
-                    None => {
-                        // println!("matched None in subarm");
-                        ()
-                    },
-                    Some(com) => {
-                        // println!("matched Some in subarm");
-                        // self.update_com();
-                        com.try_lock().unwrap().update_pos();
-                        // self.com = Some(com);
-                        self.update_com();
+    // get_total_acc_with is get_total_acc for a body whose acceleration
+    // at the last step had size a_old, which the relative opening
+    // criteria need (see Opening).
+    //
+    // In a periodic box, every region gets looked at from the copy of
+    // us nearest its com, and the regions we accept pull with all
+    // their copies (see update_ewald_accel). The region we're in still
+    // always gets opened, whichever copy of us is nearest.
+    pub fn get_total_acc_with(&self, tree: &Tree<D>, params: &Params, a_old: f64) -> [f64; D] {
+        let mut acc = [0.0; D];
+        let mut stack = match tree.root() {
+            None => return acc,
+            Some(_) => vec![0]
+        };
+
+        while let Some(n) = stack.pop() {
+            let node = &tree.regions[n];
+            match node.com {
+                // empty leaf, nothing to do
+                None => continue,
+                Some(ref com) => {
+                    let image = self.nearest_image(com, params);
+                    let me = image.as_ref().unwrap_or(self);
+                    if node.children.is_none()
+                        || ((image.is_none() || !node.contains(self)) && me.is_far_with(tree, n, params, a_old))
+                    {
+                        acc = if node.children.is_none() {
+                            me.update_direct_accel(acc, com, params)
+                        } else {
+                            me.update_accel(acc, com, params)
+                        };
+                        if params.forces.coulomb() {
+                            for part in [&node.plus, &node.minus].iter() {
+                                if let Some(ref charge) = **part {
+                                    acc = me.update_accel(acc, charge, params);
+                                }
+                            }
+                        }
+                        if node.children.is_some() && params.multipole_order >= 2
+                            && params.forces.gravity() && params.force_law.is_newtonian(D)
+                        {
+                            let correction = me.multipole_accel(tree, n, com, params);
+                            for (ai, ci) in acc.iter_mut().zip(correction.iter()) {
+                                *ai += ci;
+                            }
+                        }
+                        acc = me.update_ewald_accel(acc, com, params);
+                    } else if let Some(first) = node.children {
+                        stack.extend(first..first + Region::<D>::NUM_CHILDREN);
                     }
                 }
-            },
-            //if we have children, call recursively
-            Some(ref mut reg_vec) => {
-                let mut temp = vec![];
-                for mut child in reg_vec {
-                    child.try_lock().unwrap().deep_update_pos();
-                    // temp.push(Arc::clone(&child));
-                    temp.push(child.clone());
-                }
-                self.reg_vec = Some(temp);
-                self.update_com();
             }
-        };
+        }
+        acc
     }
 
-    pub fn update_com(&mut self) {
-        //println!("called update_com");
+    // multipole_accel is the correction to the pull of a region we've
+    // accepted, on top of treating it as a point mass at its com (com
+    // here): the quadrupole term, plus the octupole term if
+    // params.multipole_order is 3 or more. They come from expanding
+    //
+    //     1/|d - y| = 1/r + (d.y)/r^3 + (3 (d.y)^2 - r^2 y^2)/(2 r^5)
+    //               + (5 (d.y)^3 - 3 r^2 y^2 (d.y))/(2 r^7) + ...
+    //
+    // for d the displacement from the com to us, and summing over the
+    // masses in the region (see Region for the moments). The dipole
+    // term vanishes about the com. The corrections aren't softened,
+    // which is fine since regions only get accepted when they're far
+    // away. The region is the one at index n in tree, and whichever
+    // moments the tree didn't work out get left out.
+    pub fn multipole_accel(&self, tree: &Tree<D>, n: usize, com: &Body<D>, params: &Params) -> [f64; D] {
+        let mut acc = [0.0; D];
+        let (d, r2) = self.multipole_offset(com);
+        let quad = match tree.quads.get(n) {
+            Some(quad) if r2 != 0.0 => quad,
+            _ => return acc
+        };
+        let r = r2.sqrt();
+        let (r5, r7) = (r2 * r2 * r, r2 * r2 * r2 * r);
+
+        // quadrupole: grad of (3 dQd - r^2 tr Q) / (2 r^5)
+        let mut qd = [0.0; D];
+        let mut trace = 0.0;
+        for j in 0..D {
+            trace += quad[j][j];
+            for k in 0..D {
+                qd[j] += quad[j][k] * d[k];
+            }
+        }
+        let dqd = (0..D).fold(0.0, |sum, j| sum + d[j] * qd[j]);
 
-        // we check whether we have child regions to determine whether
-        // or not we're in a leaf node. If we are, we should just
-        // update the com (assuming there is one)
-        // otherwise, update based on the coms of the children
+        for j in 0..D {
+            acc[j] = 3.0 * qd[j] / r5 - 7.5 * dqd * d[j] / r7 + 1.5 * trace * d[j] / r5;
+        }
 
-        match self.reg_vec {
+        // octupole: grad of (5 O(d,d,d) - 3 r^2 d.v) / (2 r^7), where
+        // v_j = O_jkk
+        if let (true, Some(oct)) = (params.multipole_order >= 3, tree.octs.get(n)) {
+            let r9 = r7 * r2;
+            let mut w = [0.0; D];
+            let mut v = [0.0; D];
+            for j in 0..D {
+                for k in 0..D {
+                    v[j] += oct[j][k][k];
+                    for l in 0..D {
+                        w[l] += oct[j][k][l] * d[j] * d[k];
+                    }
+                }
+            }
+            let oddd = (0..D).fold(0.0, |sum, l| sum + w[l] * d[l]);
+            let dv = (0..D).fold(0.0, |sum, j| sum + d[j] * v[j]);
 
-            None => {
+            for j in 0..D {
+                acc[j] += 7.5 * w[j] / r7 - 17.5 * oddd * d[j] / r9
+                    - 1.5 * v[j] / r5 + 7.5 * dv * d[j] / r7;
+            }
+        }
 
-                // None means we're in a leaf node (or we have masses
-                // waiting to be injected, which shouldn't happen).
-                // Once we're confident we'll never call on an empty
-                // add queue, we can probably unwrap straight away ---
-                // although match operations _are_ cheap...
+        for aj in acc.iter_mut() {
+            *aj *= params.g;
+        }
+        acc
+    }
 
-                match self.com.clone() {
+    // multipole_potential is the same correction for the potential.
+    pub fn multipole_potential(&self, tree: &Tree<D>, n: usize, com: &Body<D>, params: &Params) -> f64 {
+        let (d, r2) = self.multipole_offset(com);
+        let quad = match tree.quads.get(n) {
+            Some(quad) if r2 != 0.0 => quad,
+            _ => return 0.0
+        };
+        let r = r2.sqrt();
+        let (r5, r7) = (r2 * r2 * r, r2 * r2 * r2 * r);
+
+        let mut dqd = 0.0;
+        let mut trace = 0.0;
+        for j in 0..D {
+            trace += quad[j][j];
+            for k in 0..D {
+                dqd += d[j] * quad[j][k] * d[k];
+            }
+        }
+        let mut phi = (3.0 * dqd - r2 * trace) / (2.0 * r5);
+
+        if let (true, Some(oct)) = (params.multipole_order >= 3, tree.octs.get(n)) {
+            let mut oddd = 0.0;
+            let mut dv = 0.0;
+            for j in 0..D {
+                for k in 0..D {
+                    dv += d[j] * oct[j][k][k];
+                    for l in 0..D {
+                        oddd += oct[j][k][l] * d[j] * d[k] * d[l];
+                    }
+                }
+            }
+            phi += (5.0 * oddd - 3.0 * r2 * dv) / (2.0 * r7);
+        }
 
-                    None => println!("superfluous (?) call to update_com.
-                        change this line in physics.rs to panic! and use backtrace to see where."),
+        -params.g * phi
+    }
 
-                    Some(com_arc) => {
+    // the displacement from com to the calling body, and its square
+    fn multipole_offset(&self, com: &Body<D>) -> ([f64; D], f64) {
+        let mut d = [0.0; D];
+        for (dk, (xk, ck)) in d.iter_mut().zip(self.pos_vec.iter().zip(com.pos_vec.iter())) {
+            *dk = xk - ck;
+        }
+        let r2 = self.sq_magnitude(&d);
+        (d, r2)
+    }
 
-                        // Double check to make sure we don't have any
-                        // masses waiting to be added to the region,
-                        // as that'd mess up the com we calculate.
+    // get_direct_acc is the exact acceleration of the calling body due
+    // to every mass in bodies, summed one at a time without the tree.
+    // Passing in the calling body itself is fine, since it's at
+    // distance 0 from itself and so drops out. In a periodic box it's
+    // the nearest copy of every mass, plus the rest through Ewald
+    // summation.
+    pub fn get_direct_acc(&self, bodies: &[Body<D>], params: &Params) -> [f64; D] {
+        let mut acc = [0.0; D];
+        for mass in bodies.iter() {
+            let image = self.nearest_image(mass, params);
+            let me = image.as_ref().unwrap_or(self);
+            acc = me.update_direct_accel(acc, mass, params);
+            acc = me.update_ewald_accel(acc, mass, params);
+        }
+        acc
+    }
 
-                        match self.add_queue {
-                            None => (),
-                            Some(_) => panic!("cannot update com with masses waiting to be queued!")
-                        };
+    // get_pair_potential is get_classical_potential in a periodic box:
+    // the potential of every copy of the passed body. It's the same as
+    // get_classical_potential anywhere else.
+    pub fn get_pair_potential(&self, mass: &Body<D>, params: &Params) -> f64 {
+        let image = self.nearest_image(mass, params);
+        let me = image.as_ref().unwrap_or(self);
+        me.get_classical_potential(mass, params) + me.get_ewald_potential(mass, params)
+    }
 
-                        // check to see if this region still contains com
-                        // if it doesn't, remove com and push it to the global tree
-                        if !self.contains(Arc::clone(&com_arc)) {
-                            //println!("push to global");
-                            Region::push_body_global(Arc::clone(&com_arc));
-                            self.com = None;
-                        } // else {
-                            // println!("does contain");
-                        // }
-                    },
-                }
-            },
+    // get_total_potential is get_total_acc for the potential: the
+    // gravitational potential (per unit mass) at the calling body due
+    // to everything in the tree, with far away regions lumped
+    // together the same way.
+    pub fn get_total_potential(&self, tree: &Tree<D>, params: &Params) -> f64 {
+        let mut phi = 0.0;
+        let mut stack = match tree.root() {
+            None => return phi,
+            Some(_) => vec![0]
+        };
 
-            Some(ref mut reg_vec) => {
-                // println!("I see dead children");
-                let mut num = vec![0.0; DIMS as usize];
-                let mut den = 0.0;
-
-                for child in reg_vec.iter() {
-                    let mut match_me = &child.try_lock().unwrap().com;
-                    // println!("{:#?}", match_me);
-                    match match_me {
-                        &None => continue,
-                        &Some(ref com_arc) => {
-                            // drop(match_me);
-                            let mut com = com_arc.try_lock().unwrap();
-                            den += com.mass;
-                            //TODO: we shouldn't have to be cloning pos_vec
-                            num = num
-                                .iter()
-                                .zip(com.pos_vec.clone())
-                                .map(|(pi, pv)| pi + pv * com.mass)
-                                .collect::<Vec<f64>>();
-                        },
+        while let Some(n) = stack.pop() {
+            let node = &tree.regions[n];
+            match node.com {
+                None => continue,
+                Some(ref com) => {
+                    let image = self.nearest_image(com, params);
+                    let me = image.as_ref().unwrap_or(self);
+                    if node.children.is_none()
+                        || ((image.is_none() || !node.contains(self)) && me.is_far_with(tree, n, params, 0.0))
+                    {
+                        phi += me.get_classical_potential(com, params);
+                        if params.forces.coulomb() {
+                            for part in [&node.plus, &node.minus].iter() {
+                                if let Some(ref charge) = **part {
+                                    phi += me.get_classical_potential(charge, params);
+                                }
+                            }
+                        }
+                        if node.children.is_some() && params.multipole_order >= 2
+                            && params.forces.gravity() && params.force_law.is_newtonian(D)
+                        {
+                            phi += me.multipole_potential(tree, n, com, params);
+                        }
+                        phi += me.get_ewald_potential(com, params);
+                    } else if let Some(first) = node.children {
+                        stack.extend(first..first + Region::<D>::NUM_CHILDREN);
                     }
                 }
-                //if we didn't add any masses, make sure we're not dividing by 0
-                if den != 0.0 {
-                    // println!("fix divide by 0");
-                    num = num
-                        .iter()
-                        .map(|n| n / den)
-                        .collect::<Vec<f64>>();
-                    // println!("new num is {:#?}", num);
-                } else {
-                    num = self.coord_vec.clone()
-                }
-
-                self.com = Some(
-                    Arc::new(Mutex::new(Body {
-                    pos_vec: num,
-                    vel_vec: vec![0.0; DIMS],
-                    mass: den
-                }))
-                );
             }
         }
+        phi
+    }
+
+    // update_vel kicks the calling body using the acceleration from
+    // the passed tree, which should have been built from the bodies'
+    // positions before any velocities in the step were touched.
+    pub fn update_vel(&mut self, tree: &Tree<D>, params: &Params) {
+        let acc = self.get_total_acc(tree, params);
+        self.kick(&acc, params.dt);
+    }
+
+    // kick changes the calling body's velocity by acc * dt, for when
+    // the acceleration has already been worked out.
+    pub fn kick(&mut self, acc: &[f64; D], dt: f64) {
+        for (vi, ai) in self.vel_vec.iter_mut().zip(acc) {
+            *vi += ai * dt;
+        }
+    }
+
+    pub fn update_pos(&mut self, dt: f64) {
+        for (pi, vi) in self.pos_vec.iter_mut().zip(&self.vel_vec) {
+            *pi += vi*dt;
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use simulation::Solver;
+    use testing::random_bodies;
 
     #[test]
     fn test_dist_sq() {
         let m1 = Body {
-            pos_vec: vec![1.0, 0.0, 0.0],
-            vel_vec: vec![0.0, 0.0, 0.0],
-            mass: 0.0
+            pos_vec: [1.0, 0.0, 0.0],
+            vel_vec: [0.0, 0.0, 0.0],
+            mass: 0.0,
+            charge: 0.0,
+            radius: 0.0
         };
 
         let m2 = Body {
-            pos_vec: vec![0.0, 0.0, 0.0],
-            vel_vec: vec![0.0, 0.0, 0.0],
-            mass: 0.0
+            pos_vec: [0.0, 0.0, 0.0],
+            vel_vec: [0.0, 0.0, 0.0],
+            mass: 0.0,
+            charge: 0.0,
+            radius: 0.0
         };
 
         let m3 = Body {
 
-            pos_vec: vec![-3.0, 0.0, 0.0],
-            vel_vec: vec![0.0, 0.0, 0.0],
-            mass: 0.0
+            pos_vec: [-3.0, 0.0, 0.0],
+            vel_vec: [0.0, 0.0, 0.0],
+            mass: 0.0,
+            charge: 0.0,
+            radius: 0.0
         };
 
         let m4 = Body {
-            pos_vec: vec![0.0, 4.0, 0.0],
-            vel_vec: vec![0.0, 0.0, 0.0],
-            mass: 0.0
+            pos_vec: [0.0, 4.0, 0.0],
+            vel_vec: [0.0, 0.0, 0.0],
+            mass: 0.0,
+            charge: 0.0,
+            radius: 0.0
         };
 
         assert_eq!(m1.squared_dist_to(&m2), 1.0);
@@ -471,45 +926,57 @@ mod tests {
     #[test]
     fn test_vec_rel() {
         let m1 = Body {
-            pos_vec: vec![1.0; DIMS],
-            vel_vec: vec![0.0; DIMS],
-            mass: 0.0
+            pos_vec: [1.0; DIMS],
+            vel_vec: [0.0; DIMS],
+            mass: 0.0,
+            charge: 0.0,
+            radius: 0.0
         };
 
         let m2 = Body {
-            pos_vec: vec![0.0; DIMS],
-            vel_vec: vec![0.0; DIMS],
-            mass: 0.0
+            pos_vec: [0.0; DIMS],
+            vel_vec: [0.0; DIMS],
+            mass: 0.0,
+            charge: 0.0,
+            radius: 0.0
         };
 
-        assert_eq!(m1.vec_rel(&m2), vec![-1.0; DIMS]);
+        assert_eq!(m1.vec_rel(&m2), [-1.0; DIMS]);
         // assert_eq!(m3.vec_rel(&m4), vec![7.0].extend(vec![0.0; DIMS-1]));
     }
 
     #[test]
     fn test_sq_mag() {
         let m1 = Body {
-            pos_vec: vec![1.0, 0.0, 0.0],
-            vel_vec: vec![0.0, 0.0, 0.0],
-            mass: 0.0
+            pos_vec: [1.0, 0.0, 0.0],
+            vel_vec: [0.0, 0.0, 0.0],
+            mass: 0.0,
+            charge: 0.0,
+            radius: 0.0
         };
 
         let m2 = Body {
-            pos_vec: vec![0.0, 0.0, 0.0],
-            vel_vec: vec![0.0, 0.0, 0.0],
-            mass: 0.0
+            pos_vec: [0.0, 0.0, 0.0],
+            vel_vec: [0.0, 0.0, 0.0],
+            mass: 0.0,
+            charge: 0.0,
+            radius: 0.0
         };
 
         let m3 = Body {
-            pos_vec: vec![-3.0, 0.0, 0.0],
-            vel_vec: vec![0.0, 0.0, 0.0],
-            mass: 0.0
+            pos_vec: [-3.0, 0.0, 0.0],
+            vel_vec: [0.0, 0.0, 0.0],
+            mass: 0.0,
+            charge: 0.0,
+            radius: 0.0
         };
 
         let m4 = Body {
-            pos_vec: vec![0.0, 4.0, 0.0],
-            vel_vec: vec![0.0, 0.0, 0.0],
-            mass: 0.0
+            pos_vec: [0.0, 4.0, 0.0],
+            vel_vec: [0.0, 0.0, 0.0],
+            mass: 0.0,
+            charge: 0.0,
+            radius: 0.0
         };
         // println!("m1 rel m2 {:?}", m1.vec_rel(&m2));
 
@@ -517,89 +984,404 @@ mod tests {
         assert_eq!(m3.sq_magnitude(&m3.vec_rel(&m4)), 25.0);
     }
 
+    fn check_is_far<const D: usize>() {
+        let x = (4.0/(D as f64)).sqrt();
+
+        let body = Body {
+            pos_vec: [x; D],
+            vel_vec: [0.0; D],
+            mass: 0.0,
+            charge: 0.0,
+            radius: 0.0
+        };
+
+        let mut node = Region::new([0.0; D], 0.5 * Params::default().theta);
+        node.com = Some(Body {
+            pos_vec: [0.0; D],
+            vel_vec: [0.0; D],
+            mass: 0.0,
+            charge: 0.0,
+            radius: 0.0
+        });
+        assert!(body.is_far(&node, &Params::default()));
+    }
+
     #[test]
     fn test_is_far() {
-        for dims in 1..9 {
-            let x = (4.0/(dims as f64)).sqrt();
+        check_is_far::<1>();
+        check_is_far::<2>();
+        check_is_far::<3>();
+        check_is_far::<4>();
+        check_is_far::<5>();
+        check_is_far::<6>();
+        check_is_far::<7>();
+        check_is_far::<8>();
+    }
+
+    #[test]
+    fn test_get_classical_accel() {
+        let body1 = Body {
+            pos_vec: [1.0; 1],
+            vel_vec: [0.0; 1],
+            mass: 1.0,
+            charge: 0.0,
+            radius: 0.0
+        };
+
+        let body2 = Body {
+            pos_vec: [0.0; 1],
+            vel_vec: [0.0; 1],
+            mass: 1.0,
+            charge: 0.0,
+            radius: 0.0
+        };
+
+        assert_eq!(
+            body1.sq_magnitude(
+                &body1.get_classical_accel(&body2, &Params::default())).sqrt(),
+            G
+        );
+    }
+
+    fn check_update_accel<const D: usize>() {
+        let body1 = Body {
+            pos_vec: [1.0; D],
+            vel_vec: [0.0; D],
+            mass: 1.0,
+            charge: 0.0,
+            radius: 0.0
+        };
+
+        let body2 = Body {
+            pos_vec: [0.0; D],
+            vel_vec: [0.0; D],
+            mass: 1.0,
+            charge: 0.0,
+            radius: 0.0
+        };
 
-            let body = Body {
-                pos_vec: vec![x; dims],
-                vel_vec: vec![0.0; dims],
-                mass: 0.0
-            };
+        let acc = [0.0; D];
+        let entry = -G / (D as f64).sqrt() / (D as f64);
+        assert_eq!(body1.update_accel(acc, &body2, &Params::default()), [entry; D]);
+    }
 
-            let node = Arc::new(Mutex::new(Region {
+    #[test]
+    fn test_update_accel() {
+        // past here, floating point error begins to add up.
+        check_update_accel::<1>();
+        check_update_accel::<2>();
+        check_update_accel::<3>();
+        check_update_accel::<4>();
+    }
 
-                reg_vec: None,
-                coord_vec: vec![0.0; dims],
-                half_length: 0.5 * THETA,
-                add_queue: None,
-                com:
-                Some(
-                    Arc::new(Mutex::new(
-                    Body {
-                        pos_vec: vec![0.0; dims],
-                        vel_vec: vec![0.0; dims],
-                        mass: 0.0
-                    }))
-                )
+    #[test]
+    fn test_energies() {
+        let m1 = Body {
+            pos_vec: [1.0; DIMS],
+            vel_vec: [2.0; DIMS],
+            mass: 2.0,
+            charge: 0.0,
+            radius: 0.0
+        };
 
-            }));
-            assert!(body.is_far(node));
+        let m2 = Body {
+            pos_vec: [-1.0; DIMS],
+            vel_vec: [0.0; DIMS],
+            mass: 3.0,
+            charge: 0.0,
+            radius: 0.0
+        };
+
+        let masses = vec![m1, m2];
+        let r = (4.0 * DIMS as f64).sqrt();
+
+        assert_eq!(analysis::kinetic_energy(&masses), 4.0 * DIMS as f64);
+        assert_eq!(analysis::potential_energy(&masses, &Params::default()), -6.0 * G / r);
+        assert_eq!(analysis::total_momentum(&masses), [4.0; DIMS]);
+        assert_eq!(analysis::center_of_mass(&masses).pos_vec, [-0.2; DIMS]);
+    }
+
+    #[test]
+    fn test_kernels() {
+        let (gm, eps) = (3.0, 0.5);
+
+        for kernel in KERNELS.iter() {
+            assert_eq!(kernel.name().parse::<Kernel>(), Ok(*kernel));
+
+            // Newtonian far away (the spline exactly so)
+            let r = 100.0;
+            let f = kernel.force_factor(r, gm, eps);
+            assert!((f * r * r * r / gm - 1.0).abs() < 1e-4);
+            assert!((kernel.potential(r, gm, eps) * r / gm + 1.0).abs() < 1e-4);
+
+            // the force is minus the gradient of the potential
+            let mut r = 0.05;
+            while r < 3.0 {
+                let dr = 1e-6;
+                let grad = (kernel.potential(r + dr, gm, eps)
+                            - kernel.potential(r - dr, gm, eps)) / (2.0 * dr);
+                let f = kernel.force_factor(r, gm, eps) * r;
+                assert!((grad - f).abs() < 1e-5 * f.abs().max(1.0), "{} at r = {}", kernel, r);
+                r += 0.0371;
+            }
         }
+
+        // both softened kernels are finite at r = 0, and agree there
+        let plummer = Kernel::Plummer.potential(0.0, gm, eps);
+        let spline = Kernel::Spline.potential(0.0, gm, eps);
+        assert_eq!(plummer, -gm / eps);
+        assert!((spline - plummer).abs() < 1e-12);
+
+        // the spline is exactly Newtonian from h on
+        let h = SPLINE_RADIUS * eps;
+        assert_eq!(Kernel::Spline.force_factor(h * 1.01, gm, eps), gm / (h * 1.01).powi(3));
+        assert!("gaussian".parse::<Kernel>().is_err());
     }
 
     #[test]
-    fn test_get_classical_accel() {
+    fn test_softened_accel_is_continuous() {
+        // without softening the pull of a mass vanishes at min_len;
+        // with it, it just keeps going smoothly to 0
+        let params = Params {
+            kernel: Kernel::Plummer,
+            softening: 0.1,
+            ..Params::default()
+        };
 
-        for dims in 1..2 {
-            let body1 = Body {
-                pos_vec: vec![1.0; dims],
-                vel_vec: vec![0.0; dims],
-                mass: 1.0,
-            };
+        let mass = Body { pos_vec: [0.0; 2], vel_vec: [0.0; 2], mass: 1.0, charge: 0.0, radius: 0.0 };
+        let pull = |x: f64| {
+            let body = Body { pos_vec: [x, 0.0], vel_vec: [0.0; 2], mass: 1.0, charge: 0.0, radius: 0.0 };
+            body.get_classical_accel(&mass, &params)[0]
+        };
 
-            let body2 = Body {
-                pos_vec: vec![0.0; dims],
-                vel_vec: vec![0.0; dims],
-                mass: 1.0
-            };
+        let (below, above) = (pull(0.999 * params.min_len), pull(1.001 * params.min_len));
+        assert!(below < 0.0);
+        assert!((below - above).abs() < 1e-2 * above.abs());
+        assert_eq!(pull(0.0), 0.0);
+    }
 
-            assert_eq!(
-                body1.sq_magnitude(
-                    &body1.get_classical_accel(&body2)).sqrt(),
-                ( G / (dims as f64))
-            );
+    #[test]
+    fn test_multipole_force_error() {
+        let mut params = Params {
+            min_len: 1e-9,
+            num_threads: 1,
+            ..Params::default()
+        };
+        let bodies = random_bodies(1000, &params);
+
+        // for these bodies the rms errors come out at (monopole,
+        // quadrupole, octupole)
+        //
+        //     theta 0.3:   1.6e-3   1.9e-4   4.3e-5
+        //     theta 0.5:   8.9e-3   1.7e-3   7.6e-4
+        //     theta 0.7:   2.6e-2   7.9e-3   4.2e-3
+        //     theta 1.0:   7.1e-2   3.5e-2   2.7e-2
+        //
+        // so each extra order helps at every theta, and a wider opening
+        // angle always costs accuracy
+        let mut last_rms = [0.0; 3];
+        for &theta in [0.3, 0.5, 0.7, 1.0].iter() {
+            params.theta = theta;
+            let mut rms = [0.0; 3];
+            let mut max = [0.0; 3];
+            for order in 1..4 {
+                params.multipole_order = order;
+                let (r, m) = analysis::force_error(&bodies, &params);
+                rms[order - 1] = r;
+                max[order - 1] = m;
+            }
+
+            assert!(rms[1] < rms[0] && rms[2] < rms[1]);
+            assert!(max[1] < max[0]);
+            for order in 0..3 {
+                assert!(rms[order] > last_rms[order]);
+            }
+            last_rms = rms;
         }
     }
 
     #[test]
-    fn test_update_accel() {
-        // past here, floating point error begins to add up.
-        for dims in 1..5 {
+    fn test_opening_never_accepts_own_region() {
+        // a region with all its mass piled up in one corner, and a body
+        // just inside the opposite one: far from the com, but still
+        // inside
+        let mut node = Region::new([0.0, 0.0], 1.0);
+        node.children = Some(1);
+        node.com = Some(Body { pos_vec: [-0.99, -0.99], vel_vec: [0.0; 2], mass: 1.0, charge: 0.0, radius: 0.0 });
+        let mut tree = Tree::new();
+        tree.regions.push(node);
+        let body = Body { pos_vec: [0.99, 0.99], vel_vec: [0.0; 2], mass: 1.0, charge: 0.0, radius: 0.0 };
+
+        let mut params = Params {
+            theta: 2.0,
+            opening_tolerance: 1e6,
+            ..Params::default()
+        };
+        for &opening in OPENINGS.iter() {
+            params.opening = opening;
+            assert!(!body.is_far_with(&tree, 0, &params, 1e9), "{}", opening);
+        }
 
-            let body1 = Body {
-                pos_vec: vec![1.0; dims],
-                vel_vec: vec![0.0; dims],
-                mass: 1.0
-            };
+        // and the same for every region around every body in a tree,
+        // however loose the criteria get
+        let mut params = Params {
+            min_len: 1e-9,
+            num_threads: 1,
+            ..Params::default()
+        };
+        let bodies = random_bodies(200, &params);
+        let mut tree = Tree::new();
+        tree.build(&bodies, [0.0; 3], params.max_len, &params);
+
+        params.theta = 100.0;
+        params.opening_tolerance = 1e6;
+        for &opening in OPENINGS.iter() {
+            params.opening = opening;
+            for body in bodies.iter() {
+                for (n, node) in tree.regions.iter().enumerate() {
+                    if node.children.is_some() && node.contains(body) {
+                        assert!(!body.is_far_with(&tree, n, &params, 1e9), "{}", opening);
+                    }
+                }
+            }
+        }
+    }
 
-            let body2 = Arc::new(Mutex::new(Body {
-                pos_vec: vec![0.0; dims],
-                vel_vec: vec![0.0; dims],
-                mass: 1.0
-            }));
+    #[test]
+    fn test_opening_force_error() {
+        let mut params = Params {
+            min_len: 1e-9,
+            num_threads: 1,
+            multipole_order: 1,
+            ..Params::default()
+        };
+        let bodies = random_bodies(1000, &params);
+
+        // with just monopoles at theta = 0.5 and opening_tolerance =
+        // 0.005, the (rms, max) errors come out at
+        //
+        //     geometric        8.9e-3   9.6e-2
+        //     com              8.2e-3   8.0e-2
+        //     bmax             7.4e-3   9.9e-2
+        //     salmon_warren    2.7e-3   1.0e-2
+        //     relative         4.0e-3   1.2e-2
+        //
+        // the last two bound the error on every body, so their worst
+        // case is much better
+        for &opening in OPENINGS.iter() {
+            params.opening = opening;
+            let (rms, max) = analysis::force_error(&bodies, &params);
+            assert!(rms < 1e-2, "{}", opening);
+            match opening {
+                Opening::SalmonWarren | Opening::Relative => assert!(max < 2e-2, "{}", opening),
+                _ => assert!(max < 0.2, "{}", opening)
+            }
 
-            let acc = vec![0.0; dims];
-            let entry = -1.0 * (G) / (dims as f64).sqrt() / (dims as f64);
-            assert_eq!(body1.update_accel(acc, body2), vec![entry; dims]);
+            // and tightening them up always helps
+            params.theta *= 0.5;
+            params.opening_tolerance *= 0.1;
+            assert!(analysis::force_error(&bodies, &params).0 < 0.5 * rms, "{}", opening);
+            params.theta = THETA;
+            params.opening_tolerance = OPENING_TOLERANCE;
+        }
+    }
 
+    #[test]
+    fn test_opening_names() {
+        for opening in OPENINGS.iter() {
+            assert_eq!(opening.name().parse::<Opening>(), Ok(*opening));
         }
+        assert!("gadget".parse::<Opening>().is_err());
+    }
+
+    #[test]
+    fn test_coulomb_forces() {
+        let mut params = Params {
+            forces: Forces::Coulomb,
+            ..Params::default()
+        };
+        let at = |x: f64, mass: f64, charge: f64| {
+            Body { pos_vec: [x, 0.0], vel_vec: [0.0; 2], mass, charge, radius: 0.0 }
+        };
+
+        // like charges repel, opposite ones attract, with k q1 q2 / r^2
+        let body = at(0.0, 2.0, 3.0);
+        let push = body.get_classical_accel(&at(4.0, 1.0, 5.0), &params)[0];
+        let pull = body.get_classical_accel(&at(4.0, 1.0, -5.0), &params)[0];
+        let expected = params.coulomb_k * 3.0 * 5.0 / (2.0 * 16.0);
+        assert!((push + expected).abs() < 1e-12 * expected);
+        assert!((pull - expected).abs() < 1e-12 * expected);
+
+        // and neutral bodies feel nothing at all
+        assert_eq!(at(0.0, 2.0, 0.0).get_classical_accel(&at(4.0, 1.0, 5.0), &params), [0.0; 2]);
+
+        // times our mass, the potential is the energy of the pair
+        let phi = body.get_classical_potential(&at(4.0, 1.0, 5.0), &params);
+        assert!((2.0 * phi - params.coulomb_k * 15.0 / 4.0).abs() < 1e-9);
+
+        // with both, the pull of gravity gets added on
+        params.forces = Forces::Both;
+        let both = body.get_classical_accel(&at(4.0, 1.0, 5.0), &params)[0];
+        assert!((both - (params.g / 16.0 - expected)).abs() < 1e-9);
+        assert!("magnetism".parse::<Forces>().is_err());
+    }
+
+    #[test]
+    fn test_coulomb_force_error() {
+        // a neutral mix of charges, walked with the separate positive
+        // and negative centers of every region
+        let mut params = Params {
+            min_len: 1e-9,
+            num_threads: 1,
+            forces: Forces::Coulomb,
+            ..Params::default()
+        };
+        let mut bodies = random_bodies(1000, &params);
+        for (i, body) in bodies.iter_mut().enumerate() {
+            body.charge = if i % 2 == 0 { body.mass } else { -body.mass };
+        }
+
+        let (rms, max) = analysis::force_error(&bodies, &params);
+        assert!(rms < 0.05 && max < 0.5);
+
+        params.theta = 0.25;
+        let (tight, _) = analysis::force_error(&bodies, &params);
+        assert!(tight < 0.5 * rms);
     }
 
     #[test]
     fn test_get_total_acc() {
+        let mut params = Params {
+            min_len: 1e-9,
+            num_threads: 1,
+            ..Params::default()
+        };
+        let bodies = random_bodies(300, &params);
+
+        let mut tree = Tree::new();
+        tree.build(&bodies, [0.0; 3], params.max_len, &params);
+
+        // with theta = 0 no region is ever far enough away, so the walk
+        // goes all the way down to every leaf and is exact
+        params.theta = 0.0;
+        for body in bodies.iter() {
+            let walk = body.get_total_acc(&tree, &params);
+            let direct = body.get_direct_acc(&bodies, &params);
+            let mut diff = [0.0; 3];
+            for k in 0..3 {
+                diff[k] = walk[k] - direct[k];
+            }
+            assert!(body.sq_magnitude(&diff) < 1e-20 * body.sq_magnitude(&direct));
+        }
 
+        // at the default theta it's close, and force_error says so
+        params.theta = THETA;
+        let (rms, max) = analysis::force_error(&bodies, &params);
+        assert!(rms > 0.0 && rms < 1e-2);
+        assert!(max >= rms && max < 0.1);
+
+        params.solver = Solver::Direct;
+        assert_eq!(analysis::force_error(&bodies, &params), (0.0, 0.0));
     }
 
     #[test]
@@ -619,58 +1401,144 @@ mod tests {
 
 }
 
-mod analysis {
+// analysis holds the diagnostics we run over a set of bodies. They
+// take plain slices of bodies rather than a Simulation so that they
+// work just as well on masses read back from a snapshot.
+pub mod analysis {
     use super::*;
+    use super::super::fmm;
+    use super::super::simulation::{root_region, Solver};
+
+    // Get the distribution of the radii of particles in the
+    // simulation. This assumes a force center at the origin. It
+    // would be easy to modify to give the distances from some other
+    // point, but this is probably unnecessary.
+    pub fn radial_distribution<const D: usize>(masses: &[Body<D>]) -> Vec<f64> {
+        masses.iter()
+            .map(|m| m.sq_magnitude(&m.pos_vec).sqrt())
+            .collect::<Vec<f64>>()
+    }
 
-    /*
-    Function to get the distribution of the radii of particles
-    in the simulation.
-    This assumes a force center at the origin.
-    It would be easy to
-modify to give the distances from some other point,
-    but this is probably unnecessary.
-    */
-    fn radial_distribution() {
-        let tree = TREE_POINTER.try_lock().unwrap().tree.clone();
-        let masses = tree.list_masses();
-        let distances = masses.iter().map(|m| m.sq_magnitude(&m.pos_vec));
+    // Finds the total (nonrelativistic) kinetic energy of particles.
+    pub fn kinetic_energy<const D: usize>(masses: &[Body<D>]) -> f64 {
+        masses.iter()
+            .map(|m| 0.5 * m.mass * m.sq_magnitude(&m.vel_vec))
+            .fold(0.0, |sum, e| sum + e)
+    }
 
-        //then we can print/graph the distance distributions
+    // Find the exact gravitational potential energy, summing over
+    // every pair once. Note that this doesn't make the same
+    // approximation as the acceleration calculations.
+    // TODO: add an option to calculate with this approximation, both
+    // for faster calculation and more consistent results
+    pub fn potential_energy<const D: usize>(masses: &[Body<D>], params: &Params) -> f64 {
+        let mut total = 0.0;
+        for (i, m1) in masses.iter().enumerate() {
+            for m2 in masses[i+1..].iter() {
+                total += m1.mass * m1.get_pair_potential(m2, params);
+            }
+        }
+        total
+    }
 
+    pub fn total_energy<const D: usize>(masses: &[Body<D>], params: &Params) -> f64 {
+        kinetic_energy(masses) + potential_energy(masses, params)
     }
 
-    /*
-    Finds the total (nonrelativistic) kinetic energy of particles.
-    */
-    fn kinetic_energy() {
-        let mut tree = TREE_POINTER.try_lock().unwrap().tree.clone();
-        let masses = tree.list_masses();
-        let energies = masses.into_iter().map(|m|
-                            0.5*m.mass * m.sq_magnitude(&m.vel_vec))
-                            .collect::<Vec<f64>>();
+    pub fn total_momentum<const D: usize>(masses: &[Body<D>]) -> [f64; D] {
+        let mut momentum = [0.0; D];
+        for m in masses.iter() {
+            for (pi, vi) in momentum.iter_mut().zip(&m.vel_vec) {
+                *pi += m.mass * vi;
+            }
+        }
+        momentum
+    }
+
+    // direct_accelerations is the exact acceleration of every mass due
+    // to all the others, which takes O(N^2) time.
+    pub fn direct_accelerations<const D: usize>(masses: &[Body<D>], params: &Params) -> Vec<[f64; D]> {
+        masses.iter()
+            .map(|m| m.get_direct_acc(masses, params))
+            .collect::<Vec<[f64; D]>>()
+    }
 
-        //we could do something with the energy distribution, but for now we'll
-        //just print the total kinetic energy
+    // force_error checks how far the accelerations params.solver gives
+    // (from a tree built over masses in the root a Simulation would
+    // use) are from the exact ones, returning the rms and
+    // the largest of the relative errors |a - a_exact| / |a_exact|
+    // over every mass. The relative opening criteria get the exact
+    // accelerations as their estimates of the old ones. Masses that
+    // feel no force at all get skipped.
+    pub fn force_error<const D: usize>(masses: &[Body<D>], params: &Params) -> (f64, f64) {
+        let exact = direct_accelerations(masses, params);
+
+        let root = root_region(masses, params);
+        let mut tree = Tree::new();
+        tree.build(masses, root.coord_vec, root.half_length, params);
+        let approx = match params.solver {
+            Solver::Fmm => fmm::accelerations(masses, &tree, params),
+            Solver::Direct => exact.clone(),
+            Solver::Tree => masses.iter()
+                .zip(exact.iter())
+                .map(|(m, a)| m.get_total_acc_with(&tree, params, m.sq_magnitude(a).sqrt()))
+                .collect::<Vec<[f64; D]>>()
+        };
 
-        let total_energy = energies.iter()
-                                .fold(0.0,|m,sum| m + sum);
+        let (mut sum_sq, mut max, mut count) = (0.0, 0.0f64, 0);
+        for (m, (a, a_exact)) in masses.iter().zip(approx.iter().zip(exact.iter())) {
+            let norm = m.sq_magnitude(a_exact);
+            if norm == 0.0 {
+                continue;
+            }
+            let mut diff = [0.0; D];
+            for k in 0..D {
+                diff[k] = a[k] - a_exact[k];
+            }
+            let err = (m.sq_magnitude(&diff) / norm).sqrt();
+            sum_sq += err * err;
+            max = max.max(err);
+            count += 1;
+        }
 
+        if count == 0 {
+            return (0.0, 0.0);
+        }
+        ((sum_sq / count as f64).sqrt(), max)
     }
 
-    /*
-    Function to find the exact gravitational potential energy.
-    Note that this doesn't make the same approximation as
-    the acceleration calculations.
-    TODO: add an option to calculate with this approximation, both for faster
-        calculation and more consistent results
-    */
-    fn potential_energy() {
-        let mut tree = TREE_POINTER.try_lock().unwrap().tree.clone();
-        let masses = tree.list_masses();
+    // center_of_mass lumps every mass into a single body sitting at
+    // the center of mass and moving with the center of mass velocity,
+    // carrying the total charge.
+    pub fn center_of_mass<const D: usize>(masses: &[Body<D>]) -> Body<D> {
+        let mut pos = [0.0; D];
+        let mut vel = [0.0; D];
+        let mut den = 0.0;
+        let mut charge = 0.0;
+
+        for m in masses.iter() {
+            den += m.mass;
+            charge += m.charge;
+            for i in 0..D {
+                pos[i] += m.pos_vec[i] * m.mass;
+                vel[i] += m.vel_vec[i] * m.mass;
+            }
+        }
 
-        let potential_energies = masses.iter()
-                                    .zip(masses.iter())
-                                    .map(|(m1, m2)| m1.get_classical_potential(m2));
-    }
+        //if we didn't add any masses, make sure we're not dividing by 0
+        if den != 0.0 {
+            for i in 0..D {
+                pos[i] /= den;
+                vel[i] /= den;
+            }
+        }
 
+        Body {
+            pos_vec: pos,
+            vel_vec: vel,
+            mass: den,
+            charge,
+            radius: 0.0
+        }
+    }
 }
@@ -0,0 +1,1617 @@
+// A Simulation owns everything a single run needs: the root region of
+// the tree (which in turn holds the body set), the parameters, and the
+// elapsed simulation time. Nothing in here touches global state, so
+// several simulations can live side by side in one process.
+
+use super::tree::*;
+use super::config::Params;
+use super::physics::analysis;
+use super::integrator::{Integrator, Timestep};
+use super::fmm;
+use super::collision::{self, Collisions};
+
+use std::thread;
+
+// Solver picks how a Simulation works out the forces on the bodies:
+// Tree walks the tree once for every body (Barnes-Hut), Fmm uses the
+// fast multipole method (see fmm), and Direct sums over every pair of
+// bodies exactly, which is O(N^2) but what the other two get checked
+// against.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Solver {
+    Tree,
+    Fmm,
+    Direct
+}
+
+pub const SOLVERS: [Solver; 3] = [
+    Solver::Tree,
+    Solver::Fmm,
+    Solver::Direct
+];
+
+impl Solver {
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Solver::Tree => "tree",
+            Solver::Fmm => "fmm",
+            Solver::Direct => "direct"
+        }
+    }
+}
+
+named_enum!(Solver, SOLVERS, "solver");
+
+// Boundary says what happens at the edges of the root region, the box
+// of half-length params.max_len about the origin (unless it's fitted
+// to the bodies; see Root): with Delete, bodies that leave it are
+// dropped (and recorded in Simulation::escapes), and with Reflect
+// they bounce back in off its walls. With Expand the root grows
+// instead, doubling in size towards any body that has left it until
+// everything fits again, so nothing gets lost that we didn't mean to
+// lose. And with Periodic the box repeats forever in every direction,
+// so bodies leaving through one side come back in through the other,
+// and everything feels the nearest copy of everything else (plus, for
+// gravity in 3D, every other copy through Ewald summation; see
+// ewald).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Boundary {
+    Delete,
+    Reflect,
+    Expand,
+    Periodic
+}
+
+pub const BOUNDARIES: [Boundary; 4] = [
+    Boundary::Delete,
+    Boundary::Reflect,
+    Boundary::Expand,
+    Boundary::Periodic
+];
+
+impl Boundary {
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Boundary::Delete => "delete",
+            Boundary::Reflect => "reflect",
+            Boundary::Expand => "expand",
+            Boundary::Periodic => "periodic"
+        }
+    }
+}
+
+named_enum!(Boundary, BOUNDARIES, "boundary");
+
+// Root says where the root region of the tree goes. Fixed is the box
+// of half-length params.max_len about the origin, which bodies can
+// leave (see Boundary). The others fit the root to the bodies every
+// time the tree gets rebuilt, as the smallest cube that holds them
+// all, about either the middle of their bounding box (Bounds) or their
+// centre of mass (Com). That keeps the tree as shallow as it can be,
+// and works for bodies however far from the origin they are or spread
+// out they get, without having to pick max_len to suit. Nothing ever
+// leaves a fitted root, so the boundary doesn't come into it, except
+// that a periodic box is always the fixed one. Bounds is the default,
+// so that nobody has to tune max_len unless they want a wall.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Root {
+    Fixed,
+    Bounds,
+    Com
+}
+
+pub const ROOTS: [Root; 3] = [
+    Root::Fixed,
+    Root::Bounds,
+    Root::Com
+];
+
+impl Root {
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Root::Fixed => "fixed",
+            Root::Bounds => "bounds",
+            Root::Com => "com"
+        }
+    }
+}
+
+named_enum!(Root, ROOTS, "root");
+
+// An Escape is a body that left the root region and got dropped from
+// the simulation, as it was when it went. time is the end of the step
+// it left during, since that's the first time it's outside.
+#[derive(Clone, Debug)]
+pub struct Escape<const D: usize> {
+    pub time: f64,
+    pub body: Body<D>
+}
+
+#[derive(Clone, Debug)]
+pub struct Simulation<const D: usize> {
+    // the body arena; leaves of the tree index into this
+    pub bodies: Vec<Body<D>>,
+    pub tree: Tree<D>,
+    // the acceleration of every body, as of the last update
+    pub acc: Vec<[f64; D]>,
+    // the block timestep level of every body, for integrators that
+    // give bodies their own steps (empty otherwise)
+    pub levels: Vec<usize>,
+    pub params: Params,
+    pub time: f64,
+    // the size of the last step taken (0 before the first one)
+    pub last_dt: f64,
+    // the step the energy timestep will try next
+    pub trial_dt: f64,
+    // the energy that's gone missing in collisions so far, so that
+    // the kinetic and potential energy of the bodies plus this stays
+    // put
+    pub lost_energy: f64,
+    // how many collisions (mergers or bounces) there were during the
+    // last step
+    pub collisions: usize,
+    // every body that has left the root region so far, oldest first
+    pub escapes: Vec<Escape<D>>
+}
+
+impl<const D: usize> Simulation<D> {
+
+    // new creates an empty simulation whose root region is centered
+    // at the origin, with half-length params.max_len (which, with
+    // Boundary::Expand, is only where it starts out), unless
+    // params.root fits it to the bodies instead.
+    pub fn new(params: Params) -> Simulation<D> {
+        Simulation {
+            bodies: Vec::new(),
+            tree: Tree::new(),
+            acc: Vec::new(),
+            levels: Vec::new(),
+            params,
+            time: 0.0,
+            last_dt: 0.0,
+            trial_dt: 0.0,
+            lost_energy: 0.0,
+            collisions: 0,
+            escapes: Vec::new()
+        }
+    }
+
+    // Add a body to the simulation. It won't show up in the tree
+    // until the next call to update.
+    pub fn push_body(&mut self, body: Body<D>) {
+        self.bodies.push(body);
+    }
+
+    // Add copies of a whole set of bodies, e.g. ones read back from a
+    // snapshot.
+    pub fn push_bodies(&mut self, bodies: &[Body<D>]) {
+        self.bodies.extend_from_slice(bodies);
+    }
+
+    // Rebuild the tree from the current positions and recompute every
+    // body's acceleration, returning the number of masses in the
+    // tree. Masses that have left the root region are dropped,
+    // reflected or wrapped back into it, or the root grows to fit them,
+    // depending on params.boundary, and masses that end up
+    // closer together than the tree will resolve are merged.
+    pub fn update(&mut self) -> usize {
+        self.rebuild();
+        self.acc = self.accelerations();
+        self.bodies.len()
+    }
+
+    // rebuild is update without the force pass: it drops and merges
+    // masses and rebuilds the tree, leaving acc holding each
+    // remaining body's old acceleration.
+    pub fn rebuild(&mut self) {
+        let root = self.root();
+        match self.params.boundary {
+            Boundary::Reflect => {
+                for body in self.bodies.iter_mut() {
+                    reflect(body, &root);
+                }
+            },
+            Boundary::Periodic => {
+                for body in self.bodies.iter_mut() {
+                    body.pos_vec = wrap(body.pos_vec, &self.params);
+                }
+            },
+            Boundary::Delete | Boundary::Expand => ()
+        }
+
+        // with Delete this is everything that's left the root, and with
+        // the others only bodies that have flown off to infinity
+        let inside = self.bodies.iter()
+            .map(|body| root.contains(body))
+            .collect::<Vec<bool>>();
+        for (body, &inside) in self.bodies.iter().zip(&inside) {
+            if !inside {
+                self.escapes.push(Escape { time: self.time, body: body.clone() });
+            }
+        }
+        self.retain_bodies(&inside);
+
+        loop {
+            let merges = self.tree.build(
+                &self.bodies, root.coord_vec, root.half_length, &self.params
+            );
+            if merges.is_empty() {
+                break;
+            }
+            self.merge(merges);
+        }
+    }
+
+    // collide deals with every pair of bodies that touch, the way
+    // params.collisions says to, and counts them up in collisions. It
+    // runs at the end of each step rather than in update, since
+    // partway through a step (after the drift of a leapfrog, say) the
+    // velocities are half a kick behind the positions, which gets the
+    // energy lost in a collision wrong.
+    fn collide(&mut self) {
+        self.collisions = 0;
+        match self.params.collisions {
+            Collisions::None => (),
+            Collisions::Merge => self.collide_merging(),
+            Collisions::Bounce => self.collide_bouncing()
+        }
+    }
+
+    // collide_merging merges touching bodies, and recomputes the
+    // accelerations if anything changed.
+    fn collide_merging(&mut self) {
+        loop {
+            let merges = self.mergers();
+            if merges.is_empty() {
+                break;
+            }
+            // a merged body is bigger than either of the two it came
+            // from, so it can end up touching something else, and we
+            // go around again until nothing does
+            self.collisions += merges.len();
+            self.merge(merges);
+            self.rebuild();
+        }
+        if self.collisions > 0 {
+            self.acc = self.accelerations();
+        }
+    }
+
+    // collide_bouncing bounces every pair of touching bodies that are
+    // heading into each other off each other, in order. Nobody moves,
+    // so the tree and accelerations stay as they were.
+    fn collide_bouncing(&mut self) {
+        let e = self.params.restitution;
+        for (i, j) in collision::touching(&self.bodies, &self.tree) {
+            let (head, tail) = self.bodies.split_at_mut(j);
+            if let Some(lost) = collision::bounce(&mut head[i], &mut tail[0], e) {
+                self.lost_energy += lost;
+                self.collisions += 1;
+            }
+        }
+    }
+
+    // mergers pairs up touching bodies into (kept, absorbed) pairs for
+    // merge, keeping the heavier of the two. A body only takes part in
+    // one merger at a time; any others it's in get picked up once the
+    // tree has been rebuilt.
+    fn mergers(&self) -> Vec<(usize, usize)> {
+        let mut taken = vec![false; self.bodies.len()];
+        let mut merges = Vec::new();
+        for (i, j) in collision::touching(&self.bodies, &self.tree) {
+            if taken[i] || taken[j] {
+                continue;
+            }
+            taken[i] = true;
+            taken[j] = true;
+            if self.bodies[j].mass > self.bodies[i].mass {
+                merges.push((j, i));
+            } else {
+                merges.push((i, j));
+            }
+        }
+        merges
+    }
+
+    // retain_bodies keeps only the bodies whose entry in keep is true,
+    // along with their entries in acc and levels.
+    fn retain_bodies(&mut self, keep: &[bool]) {
+        let n = self.bodies.len();
+
+        let mut i = 0;
+        self.bodies.retain(|_| {
+            i += 1;
+            keep[i - 1]
+        });
+        if self.acc.len() == n {
+            let mut i = 0;
+            self.acc.retain(|_| {
+                i += 1;
+                keep[i - 1]
+            });
+        }
+        if self.levels.len() == n {
+            let mut i = 0;
+            self.levels.retain(|_| {
+                i += 1;
+                keep[i - 1]
+            });
+        }
+    }
+
+    // merge combines each (kept, absorbed) pair of bodies into the
+    // kept one, conserving mass and momentum, then drops the absorbed
+    // bodies. Indices into the body arena are invalid afterwards, so
+    // the tree has to be rebuilt.
+    //
+    // The pair loses the kinetic energy of their motion relative to
+    // each other, 1/2 m1 m2 / (m1 + m2) v^2, along with the potential
+    // energy between them, and that goes into lost_energy.
+    fn merge(&mut self, merges: Vec<(usize, usize)>) {
+        let mut absorbed = vec![false; self.bodies.len()];
+
+        for (kept, gone) in merges {
+            let other = self.bodies[gone].clone();
+            self.lost_energy += merger_energy(&self.bodies[kept], &other, &self.params);
+
+            let body = &mut self.bodies[kept];
+            let den = body.mass + other.mass;
+
+            //if we didn't add any masses, make sure we're not dividing by 0
+            if den != 0.0 {
+                for i in 0..D {
+                    body.pos_vec[i] = (body.pos_vec[i] * body.mass + other.pos_vec[i] * other.mass) / den;
+                    body.vel_vec[i] = (body.vel_vec[i] * body.mass + other.vel_vec[i] * other.mass) / den;
+                }
+            }
+            body.mass = den;
+            body.charge += other.charge;
+            body.radius = collision::merged_radius::<D>(body.radius, other.radius);
+            absorbed[gone] = true;
+
+            // the merged body needs the smaller of the two steps
+            if self.levels.len() == self.bodies.len() {
+                self.levels[kept] = self.levels[kept].max(self.levels[gone]);
+            }
+        }
+
+        let keep = absorbed.iter().map(|&a| !a).collect::<Vec<bool>>();
+        self.retain_bodies(&keep);
+    }
+
+    // accelerations works out the acceleration of every body from the
+    // current tree, in the same order as the bodies, with whichever
+    // solver params.solver names.
+    //
+    // The tree and direct solvers work every body out on its own. The
+    // bodies get split into params.num_threads contiguous chunks with
+    // one worker thread each. Every body's sum is independent of the
+    // others, so the result doesn't depend on how many threads we use.
+    pub fn accelerations(&self) -> Vec<[f64; D]> {
+        match self.params.solver {
+            Solver::Fmm => fmm::accelerations(&self.bodies, &self.tree, &self.params),
+            _ => {
+                let all = (0..self.bodies.len()).collect::<Vec<usize>>();
+                self.accelerations_of(&all)
+            }
+        }
+    }
+
+    // accelerations_of is accelerations for just the bodies with the
+    // passed indices, returned in the same order as the indices. The
+    // FMM can't do less than every body at once, so with it we work
+    // them all out and pick out the ones asked for.
+    pub fn accelerations_of(&self, active: &[usize]) -> Vec<[f64; D]> {
+        let mut acc = vec![[0.0; D]; active.len()];
+        if active.is_empty() {
+            return acc;
+        }
+
+        if self.params.solver == Solver::Fmm {
+            let all = fmm::accelerations(&self.bodies, &self.tree, &self.params);
+            for (&i, ai) in active.iter().zip(acc.iter_mut()) {
+                *ai = all[i];
+            }
+            return acc;
+        }
+
+        let threads = self.params.num_threads.max(1);
+        let chunk = active.len().div_ceil(threads);
+        let bodies = &self.bodies;
+        let tree = &self.tree;
+        let params = &self.params;
+        // the accelerations from the last update, for the relative
+        // opening criteria
+        let last = if self.acc.len() == self.bodies.len() {
+            &self.acc[..]
+        } else {
+            &[]
+        };
+
+        thread::scope(|s| {
+            for (active, acc) in active.chunks(chunk).zip(acc.chunks_mut(chunk)) {
+                s.spawn(move || {
+                    for (&i, ai) in active.iter().zip(acc.iter_mut()) {
+                        let a_old = match last.get(i) {
+                            Some(a) => a.iter().fold(0.0, |sum, ak| sum + ak * ak).sqrt(),
+                            None => 0.0
+                        };
+                        *ai = match params.solver {
+                            Solver::Direct => bodies[i].get_direct_acc(bodies, params),
+                            _ => bodies[i].get_total_acc_with(tree, params, a_old)
+                        };
+                    }
+                });
+            }
+        });
+        acc
+    }
+
+    // Advance the simulation by one timestep of params.dt, using the
+    // integrator params.integrator names.
+    pub fn step(&mut self) {
+        let scheme = self.params.integrator;
+        self.step_with(&scheme);
+    }
+
+    // step_with advances the simulation by one timestep using the
+    // passed integrator. The size of the step is params.dt, unless
+    // params.timestep says to pick one as we go.
+    pub fn step_with<I: Integrator>(&mut self, integrator: &I) {
+        // bodies were pushed since the last update
+        if self.acc.len() != self.bodies.len() {
+            self.update();
+        }
+        let escaped = self.escapes.len();
+
+        let dt = match self.params.timestep {
+            Timestep::Fixed => {
+                integrator.step(self, self.params.dt);
+                self.params.dt
+            },
+            Timestep::Accel => {
+                let dt = self.accel_dt();
+                integrator.step(self, dt);
+                dt
+            },
+            Timestep::Energy => self.energy_step(integrator)
+        };
+        self.collide();
+
+        self.time += dt;
+        self.last_dt = dt;
+
+        // the tree gets rebuilt part way through the step, before the
+        // time has moved on, so bodies that left during it get the time
+        // at the end of it now
+        for escape in self.escapes[escaped..].iter_mut() {
+            escape.time = self.time;
+        }
+    }
+
+    // accel_dt is the step the accel timestep asks for, going by the
+    // accelerations from the last update.
+    pub fn accel_dt(&self) -> f64 {
+        let mut dt = self.params.dt;
+        for ai in self.acc.iter() {
+            let a = ai.iter().fold(0.0, |sum, ak| sum + ak * ak).sqrt();
+            if a > 0.0 {
+                dt = dt.min(self.params.timestep_eta * (self.params.softening / a).sqrt());
+            }
+        }
+        dt.max(self.params.dt_min)
+    }
+
+    // energy_step takes one step with the energy timestep (see
+    // integrator::Timestep), returning the size of the step that got
+    // accepted.
+    fn energy_step<I: Integrator>(&mut self, integrator: &I) -> f64 {
+        let tolerance = self.params.energy_tolerance;
+        let e0 = self.energy();
+
+        let mut dt = if self.trial_dt > 0.0 {
+            self.trial_dt.min(self.params.dt)
+        } else {
+            self.params.dt
+        };
+
+        loop {
+            let saved = self.clone();
+            integrator.step(self, dt);
+
+            let e1 = self.energy();
+            let err = if e0 != 0.0 {
+                ((e1 - e0) / e0).abs()
+            } else {
+                (e1 - e0).abs()
+            };
+
+            if err <= tolerance || dt <= self.params.dt_min {
+                self.trial_dt = if err < 0.125 * tolerance {
+                    (2.0 * dt).min(self.params.dt)
+                } else {
+                    dt
+                };
+                return dt;
+            }
+
+            *self = saved;
+            dt = (0.5 * dt).max(self.params.dt_min);
+        }
+    }
+
+    // energy is the total energy of the bodies, with the potential
+    // worked out the way potential_energy does it.
+    pub fn energy(&self) -> f64 {
+        analysis::kinetic_energy(&self.bodies) + self.potential_energy()
+    }
+
+    // potential_energy is the potential energy of the bodies, worked
+    // out from the tree, so the same approximation as the forces and
+    // much cheaper than analysis::potential_energy for lots of bodies.
+    // With Solver::Direct the forces are exact, and so is this. The
+    // tree needs to be up to date.
+    pub fn potential_energy(&self) -> f64 {
+        if self.params.solver == Solver::Direct {
+            return analysis::potential_energy(&self.bodies, &self.params);
+        }
+        let mut total = 0.0;
+        for body in self.bodies.iter() {
+            // every pair gets counted from both ends
+            total += 0.5 * body.mass * body.get_total_potential(&self.tree, &self.params);
+        }
+        total
+    }
+
+    // kick changes every body's velocity by dt times its acceleration
+    // as of the last update.
+    pub fn kick(&mut self, dt: f64) {
+        for (body, ai) in self.bodies.iter_mut().zip(self.acc.iter()) {
+            body.kick(ai, dt);
+        }
+    }
+
+    // drift moves every body along its velocity for dt. The tree is
+    // out of date afterwards until the next update.
+    pub fn drift(&mut self, dt: f64) {
+        for body in self.bodies.iter_mut() {
+            body.update_pos(dt);
+        }
+    }
+
+    // accelerations_at gives the acceleration every body would have if
+    // they were at the passed positions, for integrators that need
+    // forces at trial positions partway through a step. Unlike
+    // update, no bodies get dropped or merged, and the bodies are
+    // left where they were (but the tree isn't, so call update before
+    // using it again).
+    pub fn accelerations_at(&mut self, positions: &[[f64; D]]) -> Vec<[f64; D]> {
+        let saved = self.bodies.iter()
+            .map(|body| body.pos_vec)
+            .collect::<Vec<[f64; D]>>();
+        for (body, pos) in self.bodies.iter_mut().zip(positions) {
+            body.pos_vec = match self.params.boundary {
+                Boundary::Periodic => wrap(*pos, &self.params),
+                _ => *pos
+            };
+        }
+
+        let root = self.root();
+        self.tree.build(&self.bodies, root.coord_vec, root.half_length, &self.params);
+        let acc = self.accelerations();
+
+        for (body, pos) in self.bodies.iter_mut().zip(saved) {
+            body.pos_vec = pos;
+        }
+        acc
+    }
+
+    // Every body in the simulation.
+    pub fn bodies(&self) -> &[Body<D>] {
+        &self.bodies
+    }
+
+    // root is the region the tree gets built in (see root_region).
+    // With Boundary::Expand and a fixed root, the box of params.max_len
+    // is only where it starts out, and it grows from wherever the last
+    // tree's root was until every body is inside again. It never
+    // shrinks back down.
+    fn root(&self) -> Region<D> {
+        let root = root_region(&self.bodies, &self.params);
+        if self.params.boundary != Boundary::Expand || self.params.root != Root::Fixed {
+            return root;
+        }
+
+        let mut root = match self.tree.root() {
+            Some(reg) => Region::new(reg.coord_vec, reg.half_length),
+            None => root
+        };
+        // bodies at infinity would never fit, so they get dropped
+        // instead
+        while let Some(body) = self.bodies.iter().find(|body| {
+            finite(body) && !root.contains(body)
+        }) {
+            root = grow(&root, body);
+        }
+        root
+    }
+}
+
+// root_region is the region a tree over bodies gets built in, going by
+// params.root: either the box of half-length params.max_len about the
+// origin, or the smallest cube about the middle (or the centre of
+// mass) of the bodies that holds every one of them. A fitted root is
+// never smaller than params.min_len, so that a single body still gets
+// a region of its own.
+pub fn root_region<const D: usize>(bodies: &[Body<D>], params: &Params) -> Region<D> {
+    let fixed = Region::new([0.0; D], params.max_len);
+    if params.boundary == Boundary::Periodic || params.root == Root::Fixed
+        || !bodies.iter().any(finite)
+    {
+        return fixed;
+    }
+
+    let mut lo = [f64::INFINITY; D];
+    let mut hi = [f64::NEG_INFINITY; D];
+    let mut com = [0.0; D];
+    let mut den = 0.0;
+    for body in bodies.iter().filter(|body| finite(body)) {
+        for k in 0..D {
+            lo[k] = lo[k].min(body.pos_vec[k]);
+            hi[k] = hi[k].max(body.pos_vec[k]);
+            com[k] += body.mass * body.pos_vec[k];
+        }
+        den += body.mass;
+    }
+
+    let mut coord_vec = [0.0; D];
+    for k in 0..D {
+        coord_vec[k] = match params.root {
+            // without any (positive) mass there's no centre of mass
+            // to go by, so we use the middle instead
+            Root::Com if den > 0.0 => com[k] / den,
+            _ => 0.5 * (lo[k] + hi[k])
+        };
+    }
+
+    // the half-length is worked out the same way Region::contains
+    // checks it, so rounding can't leave anyone just outside
+    let mut half_length = params.min_len;
+    for body in bodies.iter().filter(|body| finite(body)) {
+        for (ck, xk) in coord_vec.iter().zip(body.pos_vec.iter()) {
+            half_length = half_length.max((ck - xk).abs());
+        }
+    }
+    Region::new(coord_vec, half_length)
+}
+
+fn finite<const D: usize>(body: &Body<D>) -> bool {
+    body.pos_vec.iter().all(|xk| xk.is_finite())
+}
+
+// grow doubles the size of a region towards body. The old region
+// becomes one of the new one's children (the one on the far side from
+// the body), so the old root gets re-parented rather than moved, and
+// every region of the old tree is still a region of the new one.
+fn grow<const D: usize>(reg: &Region<D>, body: &Body<D>) -> Region<D> {
+    let mut coord_vec = reg.coord_vec;
+    for (ck, xk) in coord_vec.iter_mut().zip(body.pos_vec.iter()) {
+        if *xk >= *ck {
+            *ck += reg.half_length;
+        } else {
+            *ck -= reg.half_length;
+        }
+    }
+    Region::new(coord_vec, 2.0 * reg.half_length)
+}
+
+// reflect bounces a body that's gone through a wall of the root back
+// in off it: it ends up as far inside the wall as it had got outside,
+// heading away from it.
+fn reflect<const D: usize>(body: &mut Body<D>, root: &Region<D>) {
+    for k in 0..D {
+        let lo = root.coord_vec[k] - root.half_length;
+        let hi = root.coord_vec[k] + root.half_length;
+        // something going fast enough to cross the whole box in one
+        // step just gets stopped at the far wall
+        if body.pos_vec[k] > hi {
+            body.pos_vec[k] = (2.0 * hi - body.pos_vec[k]).max(lo);
+            body.vel_vec[k] = -body.vel_vec[k].abs();
+        } else if body.pos_vec[k] < lo {
+            body.pos_vec[k] = (2.0 * lo - body.pos_vec[k]).min(hi);
+            body.vel_vec[k] = body.vel_vec[k].abs();
+        }
+    }
+}
+
+// wrap moves a position back into the periodic box of half-length
+// params.max_len about the origin, by a whole number of boxes along
+// each axis.
+fn wrap<const D: usize>(pos_vec: [f64; D], params: &Params) -> [f64; D] {
+    let l = 2.0 * params.max_len;
+    let mut pos_vec = pos_vec;
+    for xk in pos_vec.iter_mut() {
+        *xk -= l * ((*xk + params.max_len) / l).floor();
+    }
+    pos_vec
+}
+
+// merger_energy is how much energy two bodies lose by merging.
+fn merger_energy<const D: usize>(b1: &Body<D>, b2: &Body<D>, params: &Params) -> f64 {
+    let den = b1.mass + b2.mass;
+    if den == 0.0 {
+        return 0.0;
+    }
+    let v2 = (0..D).fold(0.0, |sum, k| sum + (b1.vel_vec[k] - b2.vel_vec[k]).powi(2));
+    0.5 * b1.mass * b2.mass / den * v2 + b1.mass * b1.get_classical_potential(b2, params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use data::DIMS;
+    use physics::{Forces, Kernel, PostNewtonian};
+    use std::f64::consts::PI;
+    use data::rand::{SeedableRng, StdRng};
+    use data::rand::distributions::{IndependentSample, Range};
+    use testing::body_at;
+
+    #[test]
+    fn test_solver_names() {
+        for solver in SOLVERS.iter() {
+            assert_eq!(solver.name().parse::<Solver>(), Ok(*solver));
+        }
+        assert!("p3m".parse::<Solver>().is_err());
+    }
+
+    #[test]
+    fn test_independent_simulations() {
+        let mut sim1 = Simulation::new(Params::default());
+        let mut sim2 = Simulation::new(Params::default());
+        let mut sim3 = Simulation::new(Params::default());
+
+        sim1.push_body(body_at([-100.0; DIMS], 10.0));
+        sim1.push_body(body_at([100.0; DIMS], 10.0));
+        sim2.push_body(body_at([50.0; DIMS], 10.0));
+
+        // simulations in different dimensions can live side by side
+        sim3.push_body(body_at([1.0, 2.0, 3.0], 10.0));
+        sim3.push_body(body_at([-1.0, -2.0, -3.0], 10.0));
+        sim3.push_body(body_at([1.0, -2.0, 3.0], 10.0));
+
+        assert_eq!(sim1.update(), 2);
+        assert_eq!(sim2.update(), 1);
+        assert_eq!(sim3.update(), 3);
+        assert_eq!(sim1.bodies().len(), 2);
+        assert_eq!(sim2.bodies().len(), 1);
+    }
+
+    #[test]
+    fn test_push_body_outside_root() {
+        let mut sim = Simulation::new(Params { root: Root::Fixed, ..Params::default() });
+        sim.push_body(body_at([2.0 * sim.params.max_len; DIMS], 10.0));
+        sim.push_body(body_at([0.5 * sim.params.max_len; DIMS], 10.0));
+
+        assert_eq!(sim.update(), 1);
+    }
+
+    #[test]
+    fn test_coulomb_scattering() {
+        // a light charge flying past a heavy one of the same sign gets
+        // pushed away from it, where gravity would pull it in
+        let deflection = |forces: Forces| {
+            let params = Params {
+                forces,
+                dt: 1e-4,
+                ..Params::default()
+            };
+            let mut sim = Simulation::new(params);
+
+            let mut nucleus = body_at([0.0, 0.0], 1e5);
+            nucleus.charge = 1e5;
+            let mut projectile = body_at([-500.0, 100.0], 1e-3);
+            projectile.vel_vec = [5000.0, 0.0];
+            projectile.charge = 1e-3;
+            sim.push_body(nucleus);
+            sim.push_body(projectile);
+
+            sim.update();
+            let e0 = sim.energy();
+            for _ in 0..2000 {
+                sim.step();
+            }
+            let e1 = sim.energy();
+            assert!(((e1 - e0) / e0).abs() < 1e-4);
+            sim.bodies()[1].vel_vec[1]
+        };
+
+        assert!(deflection(Forces::Coulomb) > 0.0);
+        assert!(deflection(Forces::Gravity) < 0.0);
+        // with both, the push and the pull cancel out
+        assert!(deflection(Forces::Both).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_merge_conserves_momentum() {
+        let mut sim = Simulation::new(Params::default());
+        let mut b1 = body_at([10.0; DIMS], 1.0);
+        let mut b2 = body_at([10.0; DIMS], 3.0);
+        b1.vel_vec = [4.0; DIMS];
+        b2.vel_vec = [-4.0; DIMS];
+        b1.charge = 2.0;
+        b2.charge = -0.5;
+        sim.push_body(b1);
+        sim.push_body(b2);
+
+        assert_eq!(sim.update(), 1);
+        assert_eq!(sim.bodies[0].mass, 4.0);
+        assert_eq!(sim.bodies[0].charge, 1.5);
+        assert_eq!(sim.bodies[0].vel_vec, [-2.0; DIMS]);
+        assert_eq!(sim.bodies[0].pos_vec, [10.0; DIMS]);
+
+        // they were right on top of each other, so all that's lost is
+        // the kinetic energy of their relative motion
+        let v2 = 64.0 * DIMS as f64;
+        assert!((sim.lost_energy - 0.5 * 0.75 * v2).abs() < 1e-9);
+    }
+
+    // two bodies with radius 5 heading straight for each other
+    fn head_on(collisions: Collisions) -> Simulation<2> {
+        let params = Params {
+            dt: 0.0002,
+            collisions,
+            ..Params::default()
+        };
+        let mut sim = Simulation::new(params);
+
+        let mut b1 = body_at([-50.0, 1.0], 10.0);
+        let mut b2 = body_at([50.0, -1.0], 30.0);
+        b1.vel_vec = [150.0, 0.0];
+        b2.vel_vec = [-50.0, 0.0];
+        b1.radius = 5.0;
+        b2.radius = 5.0;
+        sim.push_body(b1);
+        sim.push_body(b2);
+        sim.update();
+        sim
+    }
+
+    #[test]
+    fn test_collisions_merge() {
+        let mut sim = head_on(Collisions::Merge);
+        let e0 = analysis::total_energy(sim.bodies(), &sim.params);
+        let p0 = analysis::total_momentum(sim.bodies());
+        for _ in 0..5000 {
+            sim.step();
+        }
+
+        assert_eq!(sim.bodies().len(), 1);
+        let merged = &sim.bodies()[0];
+        assert_eq!(merged.mass, 40.0);
+        assert!((merged.radius - 50.0f64.sqrt()).abs() < 1e-12);
+        // (their momenta were 1500 each way)
+        let p1 = analysis::total_momentum(sim.bodies());
+        for (a, b) in p0.iter().zip(p1.iter()) {
+            assert!((a - b).abs() < 1e-9 * 1500.0);
+        }
+
+        // the energy didn't go anywhere but lost_energy
+        assert!(sim.lost_energy > 0.0);
+        let e1 = analysis::total_energy(sim.bodies(), &sim.params);
+        assert!((e1 + sim.lost_energy - e0).abs() < 1e-4 * e0.abs(), "{} {} {}", e0, e1, sim.lost_energy);
+
+        // without collisions (or gravity) they fly straight through
+        // each other
+        let mut sim = head_on(Collisions::None);
+        sim.params.g = 0.0;
+        sim.update();
+        for _ in 0..5000 {
+            sim.step();
+        }
+        assert_eq!(sim.bodies().len(), 2);
+        assert_eq!(sim.lost_energy, 0.0);
+        assert!((sim.bodies()[0].pos_vec[0] - 100.0).abs() < 1e-6);
+        assert!(sim.bodies()[1].pos_vec[0].abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_merge_point_mass() {
+        // a point mass running into a ball merges with it whichever of
+        // them was pushed first
+        for &first in [0, 1].iter() {
+            let params = Params {
+                dt: 0.0002,
+                g: 0.0,
+                collisions: Collisions::Merge,
+                ..Params::default()
+            };
+            let mut sim = Simulation::new(params);
+
+            let mut point = body_at([-20.0, 0.5], 1.0);
+            let mut ball = body_at([20.0, 0.0], 100.0);
+            point.vel_vec = [100.0, 0.0];
+            ball.radius = 5.0;
+            if first == 0 {
+                sim.push_body(point);
+                sim.push_body(ball);
+            } else {
+                sim.push_body(ball);
+                sim.push_body(point);
+            }
+            sim.update();
+
+            for _ in 0..5000 {
+                sim.step();
+            }
+            assert_eq!(sim.bodies().len(), 1);
+            assert_eq!(sim.bodies()[0].mass, 101.0);
+        }
+    }
+
+    #[test]
+    fn test_bounce_point_mass() {
+        // a point mass running into a ball bounces off it whichever
+        // of them was pushed first
+        for &first in [0, 1].iter() {
+            let params = Params {
+                dt: 0.0002,
+                g: 0.0,
+                collisions: Collisions::Bounce,
+                ..Params::default()
+            };
+            let mut sim = Simulation::new(params);
+
+            let mut point = body_at([-20.0, 0.5], 1.0);
+            let mut ball = body_at([20.0, 0.0], 100.0);
+            point.vel_vec = [100.0, 0.0];
+            ball.radius = 5.0;
+            if first == 0 {
+                sim.push_body(point);
+                sim.push_body(ball);
+            } else {
+                sim.push_body(ball);
+                sim.push_body(point);
+            }
+            sim.update();
+
+            let mut collisions = 0;
+            for _ in 0..5000 {
+                sim.step();
+                collisions += sim.collisions;
+            }
+            assert_eq!(collisions, 1);
+            assert!(sim.bodies()[first].vel_vec[0] < 0.0);
+        }
+    }
+
+    #[test]
+    fn test_collisions_bounce() {
+        for &e in [1.0, 0.5].iter() {
+            let mut sim = head_on(Collisions::Bounce);
+            sim.params.restitution = e;
+            sim.params.g = 0.0;
+            sim.update();
+            let e0 = analysis::kinetic_energy(sim.bodies());
+            let p0 = analysis::total_momentum(sim.bodies());
+
+            let mut collisions = 0;
+            for _ in 0..5000 {
+                sim.step();
+                collisions += sim.collisions;
+            }
+            assert_eq!(collisions, 1);
+            assert_eq!(sim.bodies().len(), 2);
+
+            // they've bounced back the way they came
+            assert!(sim.bodies()[0].vel_vec[0] < 0.0);
+            assert!(sim.bodies()[1].vel_vec[0] > 0.0);
+
+            let p1 = analysis::total_momentum(sim.bodies());
+            for (a, b) in p0.iter().zip(p1.iter()) {
+                assert!((a - b).abs() < 1e-9 * 1500.0);
+            }
+            let e1 = analysis::kinetic_energy(sim.bodies());
+            assert!((e1 + sim.lost_energy - e0).abs() < 1e-9 * e0);
+            if e == 1.0 {
+                assert!(sim.lost_energy.abs() < 1e-9 * e0);
+            } else {
+                assert!(sim.lost_energy > 0.1 * e0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_boundary_names() {
+        for boundary in BOUNDARIES.iter() {
+            assert_eq!(boundary.name().parse::<Boundary>(), Ok(*boundary));
+        }
+        assert!("torus".parse::<Boundary>().is_err());
+    }
+
+    #[test]
+    fn test_periodic_wrap() {
+        // a body flying out of one side of a periodic box comes back in
+        // through the other, where with Delete it would be lost
+        for &boundary in [Boundary::Delete, Boundary::Periodic].iter() {
+            let params = Params {
+                boundary,
+                root: Root::Fixed,
+                g: 0.0,
+                ..Params::default()
+            };
+            let max_len = params.max_len;
+            let mut sim = Simulation::new(params);
+            let mut body = body_at([0.99 * max_len, -0.5 * max_len], 1.0);
+            body.vel_vec = [0.1 * max_len, -0.2 * max_len];
+            sim.push_body(body);
+            sim.push_body(body_at([0.0, 0.0], 1.0));
+
+            sim.update();
+            sim.params.dt = 1.0;
+            sim.step();
+            if boundary == Boundary::Periodic {
+                assert_eq!(sim.bodies().len(), 2);
+                let pos = sim.bodies()[0].pos_vec;
+                assert!((pos[0] + 0.91 * max_len).abs() < 1e-9 * max_len);
+                assert!((pos[1] + 0.7 * max_len).abs() < 1e-9 * max_len);
+            } else {
+                assert_eq!(sim.bodies().len(), 1);
+            }
+        }
+    }
+
+    // a body just inside the +x wall of the root, about to leave it, and
+    // one sitting still at the origin
+    fn leaving(boundary: Boundary) -> Simulation<2> {
+        let params = Params {
+            boundary,
+            root: Root::Fixed,
+            g: 0.0,
+            dt: 1.0,
+            ..Params::default()
+        };
+        let max_len = params.max_len;
+        let mut sim = Simulation::new(params);
+        let mut body = body_at([0.99 * max_len, -0.5 * max_len], 2.0);
+        body.vel_vec = [0.1 * max_len, -0.2 * max_len];
+        sim.push_body(body);
+        sim.push_body(body_at([0.0, 0.0], 1.0));
+        sim.update();
+        sim
+    }
+
+    #[test]
+    fn test_boundary_escape() {
+        let mut sim = leaving(Boundary::Delete);
+        let max_len = sim.params.max_len;
+        sim.step();
+        assert_eq!(sim.bodies().len(), 1);
+        assert_eq!(sim.escapes.len(), 1);
+        assert_eq!(sim.escapes[0].time, sim.params.dt);
+        assert_eq!(sim.escapes[0].body.mass, 2.0);
+        assert!(sim.escapes[0].body.pos_vec[0] > max_len);
+
+        // nothing else leaves, so nothing else gets recorded
+        sim.step();
+        assert_eq!(sim.escapes.len(), 1);
+    }
+
+    #[test]
+    fn test_boundary_reflect() {
+        let mut sim = leaving(Boundary::Reflect);
+        let max_len = sim.params.max_len;
+        let e0 = analysis::kinetic_energy(sim.bodies());
+        sim.step();
+
+        // it comes back in as far as it would have got out, heading
+        // back the way it came along x
+        assert_eq!(sim.bodies().len(), 2);
+        assert!(sim.escapes.is_empty());
+        let body = &sim.bodies()[0];
+        assert!((body.pos_vec[0] - 0.91 * max_len).abs() < 1e-9 * max_len);
+        assert!((body.pos_vec[1] + 0.7 * max_len).abs() < 1e-9 * max_len);
+        assert_eq!(body.vel_vec, [-0.1 * max_len, -0.2 * max_len]);
+        assert_eq!(analysis::kinetic_energy(sim.bodies()), e0);
+    }
+
+    #[test]
+    fn test_boundary_expand() {
+        let mut sim = leaving(Boundary::Expand);
+        let max_len = sim.params.max_len;
+        sim.step();
+
+        // the root doubles towards the body, with the old one as its
+        // child
+        assert_eq!(sim.bodies().len(), 2);
+        assert!(sim.escapes.is_empty());
+        let root = sim.tree.root().unwrap().clone();
+        assert_eq!(root.half_length, 2.0 * max_len);
+        assert_eq!(root.coord_vec, [max_len, -max_len]);
+        assert!(sim.tree.regions.iter().any(|reg| {
+            reg.coord_vec == [0.0, 0.0] && reg.half_length == max_len
+        }));
+
+        // something much further out takes a few doublings, and the
+        // last root is still in there (on the way down to the two
+        // bodies near the origin)
+        sim.bodies[0].pos_vec = [-10.0 * max_len, 0.3 * max_len];
+        sim.push_body(body_at([0.1 * max_len, 0.1 * max_len], 1.0));
+        sim.update();
+        let grown = sim.tree.root().unwrap();
+        assert_eq!(grown.half_length, 8.0 * max_len);
+        assert!(sim.bodies().iter().all(|body| grown.contains(body)));
+        assert!(sim.tree.regions.iter().any(|reg| {
+            reg.coord_vec == root.coord_vec && reg.half_length == root.half_length
+        }));
+    }
+
+    #[test]
+    fn test_root_names() {
+        for root in ROOTS.iter() {
+            assert_eq!(root.name().parse::<Root>(), Ok(*root));
+        }
+        assert!("floating".parse::<Root>().is_err());
+    }
+
+    // a clump of bodies in a square of half-length 1 about center
+    fn clump(center: [f64; 2]) -> Vec<Body<2>> {
+        let seed: &[_] = &[4, 3, 2, 1];
+        let mut rng: StdRng = SeedableRng::from_seed(seed);
+        let pos_gen = Range::new(-1.0, 1.0);
+        let mass_gen = Range::new(1.0, 10.0);
+        (0..200).map(|_| {
+            let x = center[0] + pos_gen.ind_sample(&mut rng);
+            let y = center[1] + pos_gen.ind_sample(&mut rng);
+            body_at([x, y], mass_gen.ind_sample(&mut rng))
+        }).collect::<Vec<Body<2>>>()
+    }
+
+    #[test]
+    fn test_root_fit() {
+        let mut params = Params { root: Root::Fixed, ..Params::default() };
+        let center = [50.0 * params.max_len, -20.0 * params.max_len];
+        let bodies = clump(center);
+
+        // way outside the fixed root, everything gets dropped
+        let mut sim = Simulation::new(params.clone());
+        sim.push_bodies(&bodies);
+        sim.update();
+        assert!(sim.bodies().is_empty());
+        assert_eq!(sim.escapes.len(), bodies.len());
+
+        for &root in [Root::Bounds, Root::Com].iter() {
+            params.root = root;
+            let mut sim = Simulation::new(params.clone());
+            sim.push_bodies(&bodies);
+            sim.update();
+            assert_eq!(sim.bodies().len(), bodies.len());
+
+            // the root is as tight as it can be about its center,
+            // with someone right on its edge
+            let reg = sim.tree.root().unwrap();
+            assert!(reg.half_length < 2.0);
+            assert!(bodies.iter().all(|body| reg.contains(body)));
+            assert!(bodies.iter().any(|body| {
+                (0..2).any(|k| (reg.coord_vec[k] - body.pos_vec[k]).abs() == reg.half_length)
+            }));
+            let expected = match root {
+                Root::Com => analysis::center_of_mass(&bodies).pos_vec,
+                _ => reg.coord_vec
+            };
+            for k in 0..2 {
+                assert!((reg.coord_vec[k] - center[k]).abs() < 1.0);
+                assert!((reg.coord_vec[k] - expected[k]).abs() < 1e-9 * params.max_len);
+            }
+
+            // and the forces come out as well as they would anywhere
+            let (rms, _) = analysis::force_error(&bodies, &params);
+            assert!(rms < 1e-2, "{} {}", root, rms);
+        }
+    }
+
+    #[test]
+    fn test_root_depth() {
+        // the same clump at the origin fits in the fixed root, but
+        // the tree has to split its way down to it first
+        let mut params = Params::default();
+        let bodies = clump([0.0, 0.0]);
+        let mut regions = Vec::new();
+        for &root in [Root::Fixed, Root::Bounds].iter() {
+            params.root = root;
+            let mut sim = Simulation::new(params.clone());
+            sim.push_bodies(&bodies);
+            sim.update();
+            assert_eq!(sim.bodies().len(), bodies.len());
+            regions.push(sim.tree.regions.len());
+        }
+        assert!(regions[1] < regions[0], "{:?}", regions);
+    }
+
+    // a cubic lattice of equal masses, n to a side, filling the whole
+    // box of params.max_len, and nudged by offset
+    fn lattice(n: usize, offset: [f64; 3], params: &Params) -> Vec<Body<3>> {
+        let spacing = 2.0 * params.max_len / n as f64;
+        let mut bodies = Vec::new();
+        for i in 0..n * n * n {
+            let mut pos = [0.0; 3];
+            for (k, idx) in [i % n, (i / n) % n, i / (n * n)].iter().enumerate() {
+                pos[k] = -params.max_len + (*idx as f64 + 0.5) * spacing + offset[k];
+            }
+            bodies.push(body_at(pos, 10.0));
+        }
+        bodies
+    }
+
+    #[test]
+    fn test_periodic_lattice() {
+        // in a periodic box, a perfect lattice pulls on every body
+        // equally from all sides, so nothing feels any force at all.
+        // Even sides mean some masses are exactly half a box away,
+        // which the Ewald correction has to make up for.
+        let mut params = Params {
+            boundary: Boundary::Periodic,
+            kernel: Kernel::None,
+            min_len: 1e-9,
+            ..Params::default()
+        };
+        let scale = params.g * 10.0 / (0.25 * params.max_len).powi(2);
+
+        for &solver in [Solver::Tree, Solver::Direct].iter() {
+            params.solver = solver;
+            for &n in [3, 4].iter() {
+                let mut sim = Simulation::new(params.clone());
+                sim.push_bodies(&lattice(n, [0.1, -0.2, 0.3], &params));
+                sim.update();
+                for ai in sim.acc.iter() {
+                    let a = ai.iter().fold(0.0, |sum, ak| sum + ak * ak).sqrt();
+                    assert!(a < 1e-2 * scale, "{} {} {}", solver, n, a / scale);
+                }
+            }
+        }
+
+        // with the box open, the masses near the edges get pulled in
+        params.boundary = Boundary::Delete;
+        let mut sim = Simulation::new(params.clone());
+        sim.push_bodies(&lattice(4, [0.0; 3], &params));
+        sim.update();
+        let a = sim.acc[0].iter().fold(0.0, |sum, ak| sum + ak * ak).sqrt();
+        assert!(a > 0.1 * scale);
+    }
+
+    #[test]
+    fn test_periodic_force_error() {
+        // the tree walk against summing over every pair (and their
+        // copies) in a periodic box. The bodies are spread evenly, so
+        // the forces on them mostly cancel, and the relative errors
+        // come out bigger than in an open box (rms 1.5e-3 at theta
+        // 0.5): with quadrupoles they're
+        //
+        //     theta 0.3:   rms 2.0e-3, max 9.1e-3
+        //     theta 0.5:   rms 1.2e-2, max 7.2e-2
+        //     theta 0.7:   rms 3.4e-2, max 2.0e-1
+        //
+        // and without the Ewald correction, over 0.6 at theta 0.5.
+        let mut params = Params {
+            boundary: Boundary::Periodic,
+            min_len: 1e-9,
+            num_threads: 1,
+            ..Params::default()
+        };
+        let seed: &[_] = &[1, 2, 3, 4];
+        let mut rng: StdRng = SeedableRng::from_seed(seed);
+        let pos_gen = Range::new(-params.max_len, params.max_len);
+        let bodies = (0..500).map(|_| {
+            let mut pos = [0.0; 3];
+            for xk in pos.iter_mut() {
+                *xk = pos_gen.ind_sample(&mut rng);
+            }
+            body_at(pos, 5.0)
+        }).collect::<Vec<Body<3>>>();
+
+        params.theta = 0.5;
+        let (rms, max) = analysis::force_error(&bodies, &params);
+        assert!(rms < 2e-2 && max < 0.15, "rms {} max {}", rms, max);
+    }
+
+    #[test]
+    fn test_step() {
+        let mut sim = Simulation::new(Params::default());
+        sim.push_body(body_at([-100.0; DIMS], 10.0));
+        sim.push_body(body_at([100.0; DIMS], 10.0));
+        sim.update();
+
+        for _ in 0..10 {
+            sim.step();
+        }
+
+        // the two masses attract, so they should have moved towards
+        // each other
+        let bodies = sim.bodies();
+        assert_eq!(bodies.len(), 2);
+        for body in bodies.iter() {
+            assert!(body.pos_vec[0].abs() < 100.0);
+        }
+        assert!((sim.time - 10.0 * sim.params.dt).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_potential_energy() {
+        // the tree's potential energy is close to the exact one, and
+        // with the direct solver it is the exact one
+        let params = Params {
+            num_threads: 1,
+            ..Params::default()
+        };
+        let seed: &[_] = &[5, 6, 7, 8];
+        let mut rng: StdRng = SeedableRng::from_seed(seed);
+        let pos_gen = Range::new(-0.5 * params.max_len, 0.5 * params.max_len);
+        let mut sim = Simulation::new(params);
+        for _ in 0..300 {
+            let x = pos_gen.ind_sample(&mut rng);
+            let y = pos_gen.ind_sample(&mut rng);
+            sim.push_body(body_at([x, y], 10.0));
+        }
+        sim.update();
+
+        let exact = analysis::potential_energy(sim.bodies(), &sim.params);
+        let tree = sim.potential_energy();
+        assert!(tree != exact);
+        assert!(((tree - exact) / exact).abs() < 1e-3, "{} {}", tree, exact);
+
+        sim.params.solver = Solver::Direct;
+        assert_eq!(sim.potential_energy(), exact);
+    }
+
+    #[test]
+    fn test_leapfrog_conserves_energy() {
+        // two equal masses on a circular orbit around their center of
+        // mass, with about 600 steps per orbit
+        let params = Params {
+            dt: 0.005,
+            ..Params::default()
+        };
+        let (m, a) = (1000.0, 100.0);
+        let v = (params.g * m / (4.0 * a)).sqrt();
+
+        let mut sim = Simulation::new(params);
+        let mut b1 = body_at([-a, 0.0], m);
+        let mut b2 = body_at([a, 0.0], m);
+        b1.vel_vec = [0.0, -v];
+        b2.vel_vec = [0.0, v];
+        sim.push_body(b1);
+        sim.push_body(b2);
+        sim.update();
+
+        let e0 = analysis::total_energy(sim.bodies(), &sim.params);
+        let mut max_err: f64 = 0.0;
+        for _ in 0..2000 {
+            sim.step();
+            let e = analysis::total_energy(sim.bodies(), &sim.params);
+            max_err = max_err.max(((e - e0) / e0).abs());
+        }
+        assert!(max_err < 1e-6, "energy error {}", max_err);
+
+        let momentum = analysis::total_momentum(sim.bodies());
+        for pi in momentum.iter() {
+            assert!(pi.abs() < 1e-6);
+        }
+    }
+
+    // Two equal masses on an orbit with semi-major axis a and
+    // eccentricity e about their center of mass, starting at apoapsis.
+    fn binary(params: Params, m: f64, a: f64, e: f64) -> Simulation<2> {
+        let gm = params.g * 2.0 * m;
+        let r = a * (1.0 + e);
+        let v = (gm / a * (1.0 - e) / (1.0 + e)).sqrt();
+
+        let mut sim = Simulation::new(params);
+        let mut b1 = body_at([-r / 2.0, 0.0], m);
+        let mut b2 = body_at([r / 2.0, 0.0], m);
+        b1.vel_vec = [0.0, -v / 2.0];
+        b2.vel_vec = [0.0, v / 2.0];
+        sim.push_body(b1);
+        sim.push_body(b2);
+        sim.update();
+        sim
+    }
+
+    // the direction of the Runge-Lenz vector of a binary, which points
+    // at periapsis and stays put unless the orbit precesses
+    fn periapsis_angle(sim: &Simulation<2>) -> f64 {
+        let (b1, b2) = (&sim.bodies()[0], &sim.bodies()[1]);
+        let gm = sim.params.g * (b1.mass + b2.mass);
+        let r = [b2.pos_vec[0] - b1.pos_vec[0], b2.pos_vec[1] - b1.pos_vec[1]];
+        let v = [b2.vel_vec[0] - b1.vel_vec[0], b2.vel_vec[1] - b1.vel_vec[1]];
+        let dist = (r[0] * r[0] + r[1] * r[1]).sqrt();
+        let l = r[0] * v[1] - r[1] * v[0];
+        (v[0] * l - gm * r[1] / dist).atan2(-v[1] * l - gm * r[0] / dist)
+    }
+
+    // the separation of a binary
+    fn separation(sim: &Simulation<2>) -> f64 {
+        sim.bodies()[0].squared_dist_to(&sim.bodies()[1]).sqrt()
+    }
+
+    // how far the periapsis of a binary turns each orbit, measured at
+    // apoapsis over the first few orbits
+    fn precession(params: Params, m: f64, a: f64, e: f64) -> f64 {
+        let orbits = 4;
+        let mut sim = binary(params, m, a, e);
+        let start = periapsis_angle(&sim);
+        let (mut passes, mut last, mut closing) = (0, separation(&sim), true);
+        while passes < orbits {
+            sim.step();
+            let r = separation(&sim);
+            if !closing && r < last {
+                passes += 1;
+            }
+            closing = r < last;
+            last = r;
+        }
+        let mut turned = periapsis_angle(&sim) - start;
+        if turned < -PI {
+            turned += 2.0 * PI;
+        }
+        turned / orbits as f64
+    }
+
+    #[test]
+    fn test_pn_precession() {
+        // 1PN turns the periapsis forward by 6 pi G M / (c^2 a (1 - e^2))
+        // every orbit, which is 0.05 here (it comes out at 0.0496)
+        let mut params = Params {
+            dt: 0.0005,
+            ..Params::default()
+        };
+        let (m, a, e) = (1000.0, 100.0, 0.5);
+        let gm = params.g * 2.0 * m;
+        let c = (6.0 * PI * gm / (0.05 * a * (1.0 - e * e))).sqrt();
+
+        // without it the orbit stays put
+        assert!(precession(params.clone(), m, a, e).abs() < 1e-3);
+
+        params.post_newtonian = PostNewtonian::OnePn;
+        params.speed_of_light = c;
+        let turned = precession(params.clone(), m, a, e);
+        assert!((turned - 0.05).abs() < 0.0025, "precession {}", turned);
+
+        // and it goes as 1 / c^2
+        params.speed_of_light = 2.0 * c;
+        let turned = precession(params, m, a, e);
+        assert!((turned - 0.0125).abs() < 0.0025, "precession {}", turned);
+    }
+
+    #[test]
+    fn test_pn_inspiral() {
+        // radiation reaction shrinks a circular orbit at
+        //
+        //     da/dt = -64/5 G^3 m1 m2 (m1 + m2) / (c^5 a^3)
+        //
+        // so a^4 falls off linearly, by about 10% here over the run
+        let params = Params {
+            dt: 0.0005,
+            post_newtonian: PostNewtonian::RadiationReaction,
+            speed_of_light: 2500.0,
+            ..Params::default()
+        };
+        let (m, a) = (1000.0, 100.0);
+        let gm = params.g * m;
+        let rate = 64.0 / 5.0 * gm * gm * 2.0 * gm / params.speed_of_light.powi(5);
+
+        let mut sim = binary(params, m, a, 0.0);
+        while sim.time < 2.0 {
+            sim.step();
+        }
+        // the semi-major axis from the (Newtonian) energy
+        let a_now = -gm * m / (2.0 * sim.energy());
+        let expected = (a.powi(4) - 4.0 * rate * sim.time).powf(0.25);
+        assert!(a_now < 0.99 * a);
+        assert!((a_now - expected).abs() < 0.05 * (a - expected), "a {} expected {}", a_now, expected);
+    }
+
+    // A light body flying past a heavy one, close enough to be turned
+    // back around. Returns the worst relative energy error over the
+    // flyby, the smallest step taken and the number of steps.
+    fn flyby(timestep: Timestep) -> (f64, f64, usize) {
+        let params = Params {
+            dt: 0.005,
+            timestep,
+            ..Params::default()
+        };
+
+        let mut sim = Simulation::new(params);
+        sim.push_body(body_at([0.0, 0.0], 100000.0));
+        let mut body = body_at([-500.0, 200.0], 1e-3);
+        body.vel_vec = [2000.0, 0.0];
+        sim.push_body(body);
+        sim.update();
+
+        let e0 = analysis::total_energy(sim.bodies(), &sim.params);
+        let (mut max_err, mut min_dt, mut steps): (f64, f64, usize) = (0.0, sim.params.dt, 0);
+        while sim.time < 0.5 {
+            sim.step();
+            let e = analysis::total_energy(sim.bodies(), &sim.params);
+            max_err = max_err.max(((e - e0) / e0).abs());
+            min_dt = min_dt.min(sim.last_dt);
+            steps += 1;
+        }
+        assert_eq!(sim.bodies().len(), 2);
+        (max_err, min_dt, steps)
+    }
+
+    #[test]
+    fn test_adaptive_timesteps() {
+        let (fixed_err, fixed_dt, fixed_steps) = flyby(Timestep::Fixed);
+        let (accel_err, accel_dt, accel_steps) = flyby(Timestep::Accel);
+        let (energy_err, energy_dt, _) = flyby(Timestep::Energy);
+
+        // a fixed step this big gets the periapsis badly wrong
+        assert_eq!(fixed_dt, 0.005);
+        assert_eq!(fixed_steps, 100);
+        assert!(fixed_err > 0.5);
+
+        // the adaptive ones shrink the step near periapsis, without
+        // taking the smallest step all the way through
+        assert!(accel_err < 1e-2);
+        assert!(accel_dt < 0.1 * fixed_dt);
+        assert!((accel_steps as f64) < 0.5 / accel_dt);
+
+        assert!(energy_err < 1e-3);
+        assert!(energy_dt < 0.1 * fixed_dt);
+    }
+
+    // a few thousand bodies scattered over the root, with some
+    // landing on top of each other so that they get merged
+    fn scattered_bodies(params: &Params) -> Vec<Body<DIMS>> {
+        let seed: &[_] = &[5, 6, 7, 8];
+        let mut rng: StdRng = SeedableRng::from_seed(seed);
+        let pos_gen = Range::new(-0.9 * params.max_len, 0.9 * params.max_len);
+        let vel_gen = Range::new(-100.0, 100.0);
+
+        let mut bodies = Vec::new();
+        for i in 0..3000 {
+            let mut body = body_at([0.0; DIMS], 1.0 + (i % 7) as f64);
+            for (pi, vi) in body.pos_vec.iter_mut().zip(body.vel_vec.iter_mut()) {
+                *pi = pos_gen.ind_sample(&mut rng);
+                *vi = vel_gen.ind_sample(&mut rng);
+            }
+            bodies.push(body);
+        }
+        for i in 0..20 {
+            let mut body = bodies[i * 100].clone();
+            body.mass = 0.5;
+            bodies.push(body);
+        }
+        bodies
+    }
+
+    #[test]
+    fn test_threads_match_serial() {
+        let mut serial = Simulation::new(Params::default());
+        serial.params.num_threads = 1;
+        let bodies = scattered_bodies(&serial.params);
+        serial.push_bodies(&bodies);
+
+        let mut parallel = serial.clone();
+        parallel.params.num_threads = 3;
+
+        serial.update();
+        parallel.update();
+        assert_eq!(serial.bodies().len(), bodies.len() - 20);
+
+        for _ in 0..3 {
+            assert_eq!(serial.accelerations(), parallel.accelerations());
+            serial.step();
+            parallel.step();
+        }
+
+        for (b1, b2) in serial.bodies().iter().zip(parallel.bodies()) {
+            assert_eq!(b1.pos_vec, b2.pos_vec);
+            assert_eq!(b1.vel_vec, b2.vel_vec);
+            assert_eq!(b1.mass, b2.mass);
+        }
+    }
+}
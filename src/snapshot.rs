@@ -0,0 +1,205 @@
+// Snapshots are plain text files holding the state of every body at
+// some instant. The first line is a header of the form
+//
+//     # time <t> dims <D>
+//
+// followed by one line per body with its mass, then its position,
+// then its velocity, then its charge and its radius, all separated by
+// tabs. The radius, or both, can be left off (for 0), which is how
+// snapshots from before bodies had charges or radii look. Lines
+// starting with # are otherwise ignored, so snapshots can be annotated
+// by hand.
+
+use super::tree::*;
+
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{self, BufReader, BufWriter};
+use std::path::Path;
+
+pub fn write_snapshot<P: AsRef<Path>, const D: usize>(path: P, time: f64, masses: &[Body<D>]) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    writeln!(file, "# time {} dims {}", time, D)?;
+
+    for m in masses.iter() {
+        write!(file, "{}", m.mass)?;
+        for xi in m.pos_vec.iter().chain(m.vel_vec.iter()) {
+            write!(file, "\t{}", xi)?;
+        }
+        writeln!(file, "\t{}\t{}", m.charge, m.radius)?;
+    }
+    Ok(())
+}
+
+// read_dims returns the number of dimensions recorded in the header of
+// a snapshot, if it has one, so that callers can pick which D to read
+// it back with.
+pub fn read_dims<P: AsRef<Path>>(path: P) -> io::Result<Option<usize>> {
+    let file = BufReader::new(File::open(path)?);
+
+    for line in file.lines() {
+        let line = line?;
+        let line = line.trim();
+
+        if line.is_empty() {
+            continue;
+        }
+        let header = match line.strip_prefix('#') {
+            Some(header) => header,
+            None => break
+        };
+
+        let words = header.split_whitespace().collect::<Vec<&str>>();
+        for pair in words.chunks(2) {
+            if let ("dims", Some(d)) = (pair[0], pair.get(1)) {
+                return parse_dims(d).map(Some);
+            }
+        }
+    }
+    Ok(None)
+}
+
+// read_snapshot returns the time stored in the header (or 0.0 if
+// there isn't one) along with the bodies in the file, which need to
+// be D-dimensional.
+pub fn read_snapshot<P: AsRef<Path>, const D: usize>(path: P) -> io::Result<(f64, Vec<Body<D>>)> {
+    let file = BufReader::new(File::open(path)?);
+    let mut time = 0.0;
+    let mut masses = Vec::new();
+
+    for line in file.lines() {
+        let line = line?;
+        let line = line.trim();
+
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(header) = line.strip_prefix('#') {
+            let words = header.split_whitespace().collect::<Vec<&str>>();
+            for pair in words.chunks(2) {
+                match (pair[0], pair.get(1)) {
+                    ("time", Some(t)) => time = parse_f64(t)?,
+                    ("dims", Some(d)) if parse_dims(d)? != D => {
+                        return Err(invalid(format!(
+                            "snapshot has {} dims, but we're reading it as {}", d, D
+                        )));
+                    },
+                    _ => ()
+                }
+            }
+            continue;
+        }
+
+        let values = line.split_whitespace()
+            .map(parse_f64)
+            .collect::<io::Result<Vec<f64>>>()?;
+
+        if values.len() < 2 * D + 1 || values.len() > 2 * D + 3 {
+            return Err(invalid(format!(
+                "expected {} to {} values per body, found {}", 2 * D + 1, 2 * D + 3, values.len()
+            )));
+        }
+
+        let mut body = Body {
+            pos_vec: [0.0; D],
+            vel_vec: [0.0; D],
+            mass: values[0],
+            charge: values.get(2 * D + 1).cloned().unwrap_or(0.0),
+            radius: values.get(2 * D + 2).cloned().unwrap_or(0.0)
+        };
+        body.pos_vec.copy_from_slice(&values[1..D+1]);
+        body.vel_vec.copy_from_slice(&values[D+1..2*D+1]);
+        masses.push(body);
+    }
+
+    Ok((time, masses))
+}
+
+fn parse_f64(s: &str) -> io::Result<f64> {
+    s.parse::<f64>()
+        .map_err(|_| invalid(format!("couldn't parse {:?} as a number", s)))
+}
+
+fn parse_dims(s: &str) -> io::Result<usize> {
+    s.parse::<usize>()
+        .map_err(|_| invalid(format!("couldn't parse {:?} as a number of dims", s)))
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use data::DIMS;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let masses = [
+            Body {
+                pos_vec: [1.5; DIMS],
+                vel_vec: [-2.25; DIMS],
+                mass: 3.0,
+                charge: -2.5,
+                radius: 0.75
+            },
+            Body {
+                pos_vec: [0.1; DIMS],
+                vel_vec: [1e-7; DIMS],
+                mass: 1e5,
+                charge: 0.0,
+                radius: 0.0
+            },
+        ];
+
+        let path = env::temp_dir().join("barnes_rust_snapshot_round_trip.txt");
+        write_snapshot(&path, 0.125, &masses).unwrap();
+        assert_eq!(read_dims(&path).unwrap(), Some(DIMS));
+        let (time, read) = read_snapshot::<_, DIMS>(&path).unwrap();
+
+        // reading it back in the wrong dimension should fail
+        assert!(read_snapshot::<_, 3>(&path).is_err());
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(time, 0.125);
+        assert_eq!(read.len(), 2);
+        for (m, r) in masses.iter().zip(read.iter()) {
+            assert_eq!(m.mass, r.mass);
+            assert_eq!(m.pos_vec, r.pos_vec);
+            assert_eq!(m.vel_vec, r.vel_vec);
+            assert_eq!(m.charge, r.charge);
+            assert_eq!(m.radius, r.radius);
+        }
+    }
+
+    #[test]
+    fn test_snapshot_without_charges() {
+        let path = env::temp_dir().join("barnes_rust_snapshot_without_charges.txt");
+        fs::write(&path, "# time 2 dims 2\n1\t2\t3\t4\t5\n").unwrap();
+        let (time, read) = read_snapshot::<_, 2>(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(time, 2.0);
+        assert_eq!(read[0].mass, 1.0);
+        assert_eq!(read[0].vel_vec, [4.0, 5.0]);
+        assert_eq!(read[0].charge, 0.0);
+        assert_eq!(read[0].radius, 0.0);
+    }
+
+    #[test]
+    fn test_snapshot_bad_dims() {
+        // dims has to be a whole number, not something that rounds to
+        // one
+        let path = env::temp_dir().join("barnes_rust_snapshot_bad_dims.txt");
+        for dims in ["2.9", "-1", "two"].iter() {
+            fs::write(&path, format!("# dims {}\n1\t2\t3\t4\t5\n", dims)).unwrap();
+            assert!(read_dims(&path).is_err(), "{}", dims);
+            assert!(read_snapshot::<_, 2>(&path).is_err(), "{}", dims);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,36 @@
+// Helpers shared by the tests of more than one module. Only compiled
+// for tests.
+
+use super::config::Params;
+use super::data::rand::{SeedableRng, StdRng};
+use super::data::rand::distributions::{IndependentSample, Range};
+use super::tree::Body;
+
+// body_at is a body of the passed mass sitting still at pos_vec, with
+// no charge or radius.
+pub fn body_at<const D: usize>(pos_vec: [f64; D], mass: f64) -> Body<D> {
+    Body {
+        pos_vec,
+        vel_vec: [0.0; D],
+        mass,
+        charge: 0.0,
+        radius: 0.0
+    }
+}
+
+// random_bodies scatters num bodies of mass 1 to 10 uniformly through
+// most of the root region in 3D, sitting still. The seed is fixed, so
+// every test gets the same ones.
+pub fn random_bodies(num: usize, params: &Params) -> Vec<Body<3>> {
+    let seed: &[_] = &[1, 2, 3, 4];
+    let mut rng: StdRng = SeedableRng::from_seed(seed);
+    let pos_gen = Range::new(-0.9 * params.max_len, 0.9 * params.max_len);
+    let mass_gen = Range::new(1.0, 10.0);
+    (0..num).map(|_| {
+        let mut pos_vec = [0.0; 3];
+        for xi in pos_vec.iter_mut() {
+            *xi = pos_gen.ind_sample(&mut rng);
+        }
+        body_at(pos_vec, mass_gen.ind_sample(&mut rng))
+    }).collect()
+}
@@ -1,13 +1,7 @@
-// use std::thread;       // For fearless concurrency
+use super::config::Params;
+use super::physics::Opening;
 
-// use std::fmt;
-
-use super::data::*;
-use super::physics::*;
-
-// Static -> valid globally throughout the lifetime of the program
-// mut allows us to modify the value contained in the static.
-// TODO: implement a more intelligent thread limit thing.
+use std::thread;
 
 // derive(Clone) tells rust to try and implement the clone trait on
 // our Coord automatically. This allows us to clone the data inside of
@@ -16,536 +10,837 @@ use super::physics::*;
 
 // TODO: implement a method for element-wise addition on Body
 
-// TODO: implement some form of thread queue flagging at the end of
-// each velocity update run.
-
 // Body is going to end up being our class to represent masses. Each
-// one will have a float vector to describe position, then some mass
-// value assigned to it.
+// one will have a float array to describe position, then some mass
+// value assigned to it. D is the number of spatial dimensions; it's a
+// const generic rather than a global so that 2D and 3D simulations can
+// both live in the same binary. Bodies can carry a (signed) charge as
+// well, which only matters when params.forces includes Coulomb forces,
+// and a radius, which only matters when params.collisions says bodies
+// that touch should collide (0 for point masses that never do).
 
 #[derive(Clone, Debug)]
-pub struct Body {
-    pub pos_vec: Vec<f64>,
-    pub vel_vec: Vec<f64>,
-    pub mass: f64
+pub struct Body<const D: usize> {
+    pub pos_vec: [f64; D],
+    pub vel_vec: [f64; D],
+    pub mass: f64,
+    pub charge: f64,
+    pub radius: f64
 }
 
 /*
-// This is our top-level class that we'll use to represent regions in
-// our recursive tree. We made the mistake of defining everything with
-// option enums --- FIXME: refactor to make this not be the case.
-// reg_vec is an optional vector of child regions --- if we're at a
-// leaf in the tree, then this will be None, else we'll have a Some
+// The tree used to be built out of Arc<Mutex<Region>>s, with every
+// region holding a vector of pointers to its children. Cloning and
+// locking all of those dominated the runtime, so now the regions all
+// live in one contiguous arena (Tree::regions) and point at each
+// other by index. The bodies live in their own arena (the body
+// vector owned by the Simulation), and leaves refer to them by index
+// as well. regions[0] is always the root.
 
-// containing a vector of references to child regions.
+// children is the index of the first of this region's NUM_CHILDREN
+// child regions, which always sit next to each other in the arena. If
+// we're at a leaf in the tree, then this will be None.
 
-// coord_vec is going to be a vector of floats describing the position
-// of the center of our region (which is an n-dimensional box).
+// coord_vec is going to be an array of floats describing the position
+// of the center of our region (which is a D-dimensional box).
 
 // half_length, as the name indicates, is going to be a float whose
 // value is half of the length of a side of our box. We chose to use
 // half lengths because it makes determining whether a region contains
 // some mass faster.
 
-// remove is a bool flag that will tell the update function whether or
-// not the center-of-mass from the previous timestep in our tree is
-// invalid or not. For instance, if any mass moves in the subtree, the
-// COM is no longer valid. FIXME: I'm questioning whether this flag
-// should even be a part of our struct or not. It seems that the com
-// should _always_ change after a timestep.
-
-// add_queue is an optional queue for pushing masses into the region.
-// The way our code currently works, when a mass enters some region,
-// we push it into an add-queue for the region. Then, our region
-// determines whether or not it needs to split into sub-regions, and
-// if so, it splits and sequentially pushes the masses in its
-// add-queue into the sub-queues for its children. In the end, the
-// only region that'll actually do any work incorporating the mass
-// will be the lowest-level sub-region. We call this model "corporate
-// delegation."
-
-// Finally, com is an optional Bodyinate that contains a position and
-// a mass (center of mass of our region).
+// body is the index of the mass held by a leaf, if it holds one.
+
+// com is an optional Body that contains a position and a mass (center
+// of mass of our region). Leaves holding a mass get a copy of it, and
+// every internal region gets one once update_com has run.
+
+// Charges can have either sign, so a single center of charge could sit
+// anywhere (or nowhere, for a neutral region). Instead, internal
+// regions keep the positive and the negative charge inside them apart:
+// plus and minus are massless Bodies holding the total charge of each
+// sign, sitting at its center. Internal coms have no charge, and at a
+// leaf the com carries the charge of its mass, so every charge gets
+// counted once whichever of them a walk uses.
+
+// Finally, the Tree keeps the second and third moments of the mass
+// in each region about its com, sum m y_j y_k and sum m y_j y_k y_l
+// over the masses inside, where y is a mass's position relative to
+// the com. They're zero for leaves; the force walk uses them to
+// correct the pull of a far away region beyond treating it as a
+// point mass. They live off to the side (see Tree::quads) rather
+// than in here, since they're big and most walks don't need both.
 
 // ******** TODO / TOFIX ********
 // + calculate distance metric in parent node
 // + create better implementations for generic-dimensional spaces
-// + implement dropping of dead branches
-// + collisions
-//   - really really close bodies merge, but add a bonding energy
-//     term to maintain conservation of energy
-// + reimplement contains method by constructing indices using our
-//   binary string construction method on the global multiplier array.
  */
-use std::sync::{Mutex, Arc};
-use std::thread;
 
-// use std::rc::Rc;
-// use std::cell::RefCell;
+// child_offset gives the direction of the center of child k from the
+// center of its parent along axis: +1.0 if bit axis of k is set, and
+// -1.0 otherwise. Scaling these by a quarter of the parent's side
+// length gets us the centers of all the children, without needing a
+// table built at runtime for each dimensionality.
+pub const fn child_offset(k: usize, axis: usize) -> f64 {
+    if k & (1 << axis) != 0 { 1.0 } else { -1.0 }
+}
 
 #[derive(Clone, Debug)]
-pub struct Region {
-    pub reg_vec: Option<Vec<Arc<Mutex<Region>>>>,
-    pub coord_vec: Vec<f64>,
+pub struct Region<const D: usize> {
+    pub children: Option<usize>,
+    pub coord_vec: [f64; D],
     pub half_length: f64,
-    pub add_queue: Option<Vec<Arc<Mutex<Body>>>>,
-    pub com: Option<Arc<Mutex<Body>>>
+    pub body: Option<usize>,
+    pub com: Option<Body<D>>,
+    pub plus: Option<Body<D>>,
+    pub minus: Option<Body<D>>
 }
 
-
-// Let's implement methods on REgion!
-impl Region {
+// Let's implement methods on Region!
+impl<const D: usize> Region<D> {
+
+    // Every split region gets one child per orthant.
+    pub const NUM_CHILDREN: usize = 1 << D;
+
+    pub fn new(coord_vec: [f64; D], half_length: f64) -> Region<D> {
+        Region {
+            children: None,
+            coord_vec,
+            half_length,
+            body: None,
+            com: None,
+            plus: None,
+            minus: None
+        }
+    }
 
     // contains takes some body, and then compares each of the i
     // coordinates in its position vector to determine whether it's
     // contained in the calling region or not.
-
-
-    pub fn contains(&self, body_arc: Arc<Mutex<Body>>) -> bool {
-        //println!("called contains");
-        let body = &body_arc.lock().unwrap();
+    pub fn contains(&self, body: &Body<D>) -> bool {
         // Iterate through all pairs of the i components of our
         // position coordinate
-
         for (qi, pi) in self.coord_vec.iter().zip(&body.pos_vec) {
-
-            // TODO: make sure nothing funny happens if it happens to
-            // be directly on the boundary... I think this is handeled
-            // because we'll pop a mass as soon as it passes for one
-            // of the regions, but let's double-check.
-
             if (qi-pi).abs() > self.half_length {
-                // println!("shit, return false");
                 return false
             }
         }
         true // implicit "return true" if it doesn't fail any checks
     }
 
-    // update does two jobs at once. It recursively pushes masses from
-    // add queues
-    pub fn update(&mut self) -> i32 {
-
-        // First check whether the calling region has any child
-        // regions. This will determine how we handle our updating.
-        // Currently, we check whether
-
-        match self.reg_vec.clone() {
-
-            None => {
+    // bmax is how far the furthest corner of the region is from its
+    // com, which bounds how far any mass inside can be from it. A leaf
+    // only ever holds the one mass sitting right on its com, so it
+    // gets 0, and so does an empty region.
+    pub fn bmax(&self) -> f64 {
+        let com = match (self.children, &self.com) {
+            (Some(_), Some(com)) => com,
+            _ => return 0.0
+        };
+        let mut r2 = 0.0;
+        for (ck, xk) in self.coord_vec.iter().zip(com.pos_vec.iter()) {
+            let far = (ck - xk).abs() + self.half_length;
+            r2 += far * far;
+        }
+        r2.sqrt()
+    }
 
-                // if we don't have a defined region vector, then that
-                // means that either we're a leaf node, or we're doing
-                // the first initial push of masses into the tree.
+    // child_index gives the offset (from the first child) of the child
+    // region that a body in the calling region belongs to. Bit k of
+    // the index is set when the body is on the positive side of our
+    // center along axis k, which matches child_offset.
+    pub fn child_index(&self, body: &Body<D>) -> usize {
+        let mut index = 0;
+        for (k, (qi, pi)) in self.coord_vec.iter().zip(&body.pos_vec).enumerate() {
+            if pi > qi {
+                index |= 1 << k;
+            }
+        }
+        index
+    }
+}
 
-                // we want to check whether there are any new
-                // masses waiting to be added to our region. If
-                // there aren't, we return 0 (because Harry had
-                // the idea of using our recursive update function
-                // to simultaneously calculate how many bodies
-                // were contained in subregions of our region, as
-                // idea of calculating a metric for the number of
-                // bodies contained below the given body, which
-                // will be useful in multithreading), else we
-                // recurse down into the tree.
+// Below this many bodies, building the tree in parallel costs more
+// in thread startup than it saves.
+pub const PARALLEL_BUILD_MIN: usize = 1024;
 
-                match self.add_queue.clone() {
+#[derive(Clone, Debug)]
+pub struct Tree<const D: usize> {
+    pub regions: Vec<Region<D>>,
+    // the quadrupole and octupole moments of every region, in the same
+    // order as regions. They only get worked out when the last build's
+    // params are going to use them (see Tree::moments_for), and are
+    // left empty otherwise, which keeps the arena small.
+    pub quads: Vec<[[f64; D]; D]>,
+    pub octs: Vec<[[[f64; D]; D]; D]>
+}
 
-                    None => {
-                        // println!("nothing to add");
-                        match &self.com {
-                            &None => 0,
-                            &Some(_) => 1
-                        }
-                    },
+impl<const D: usize> Default for Tree<D> {
+    fn default() -> Tree<D> {
+        Tree::new()
+    }
+}
 
-                    // if our add_queue is nonempty, then we need
-                    // to handle ingesting of the masses.
+impl<const D: usize> Tree<D> {
 
-                    Some(mut queue) => {
+    pub fn new() -> Tree<D> {
+        Tree {
+            regions: Vec::new(),
+            quads: Vec::new(),
+            octs: Vec::new()
+        }
+    }
 
-                        match self.com.clone() {
+    pub fn root(&self) -> Option<&Region<D>> {
+        self.regions.first()
+    }
 
-                            None => self.recurse(true),
+    // The child regions of reg, which is empty at a leaf.
+    pub fn children(&self, reg: &Region<D>) -> &[Region<D>] {
+        match reg.children {
+            None => &[],
+            Some(first) => &self.regions[first..first + Region::<D>::NUM_CHILDREN]
+        }
+    }
 
-                            // If we have a current com, we push
-                            // it into the queue (because we're
-                            // still at a leaf node), and then
-                            // subdivide accordingly, returning
-                            // the number of submasses contained.
+    // build throws away the old tree and inserts every body in bodies
+    // that lies inside the root region described by coord_vec and
+    // half_length, then computes the centers of mass. We keep the
+    // arena's allocation around between builds.
+    //
+    // Masses that land in a leaf smaller than params.min_len aren't
+    // split any further. Instead they're left out of the tree, and
+    // we return them as (kept, absorbed) index pairs, sorted by the
+    // absorbed index, so the caller can merge them; the kept body is
+    // the one the leaf holds.
+    //
+    // With more than one thread and enough bodies, the subtrees under
+    // the root's children get built in parallel (see build_parallel).
+    // Either way we end up with the same tree.
+    pub fn build(
+        &mut self,
+        bodies: &[Body<D>],
+        coord_vec: [f64; D],
+        half_length: f64,
+        params: &Params
+    ) -> Vec<(usize, usize)> {
+        self.regions.clear();
+        self.regions.push(Region::new(coord_vec, half_length));
+
+        let inside = (0..bodies.len())
+            .filter(|&i| self.regions[0].contains(&bodies[i]))
+            .collect::<Vec<usize>>();
+
+        if params.num_threads > 1
+            && inside.len() >= PARALLEL_BUILD_MIN
+            && half_length > params.min_len
+        {
+            return self.build_parallel(&inside, bodies, params);
+        }
 
-                            Some(mut com) => {
-                                queue.push(com);
-                                self.com = None;
-                                self.add_queue = Some(queue);
-                                self.recurse(true)
-                            },
-                        }
-                    },
-                }
-            },
+        let merges = self.insert_all(&inside, bodies, params);
+        self.size_moments(params);
+        self.update_com(bodies);
+        merges
+    }
 
-            // Case that our region has a defined vector of child
-            // regions. TODO: check for dead regions, and prune those.
-            // Perhaps we should make each of the entries in the
-            // vector options on Regions?
+    // moments_for says which of the moments (quadrupoles, octupoles) a
+    // walk with the passed params is going to look at: the quadrupoles
+    // for multipole_order 2 or more, or for the SalmonWarren criterion,
+    // and the octupoles for multipole_order 3. The octupoles are built
+    // up from the quadrupoles, so they never come without them.
+    pub fn moments_for(params: &Params) -> (bool, bool) {
+        let octs = params.multipole_order >= 3;
+        let quads = octs || params.multipole_order >= 2 || params.opening == Opening::SalmonWarren;
+        (quads, octs)
+    }
 
-            Some(mut reg_vec) => {
+    // size_moments makes room for whichever moments params needs, one
+    // per region, and throws away the rest.
+    fn size_moments(&mut self, params: &Params) {
+        let (quads, octs) = Tree::<D>::moments_for(params);
+        let n = if quads { self.regions.len() } else { 0 };
+        self.quads.clear();
+        self.quads.resize(n, [[0.0; D]; D]);
+        let n = if octs { self.regions.len() } else { 0 };
+        self.octs.clear();
+        self.octs.resize(n, [[[0.0; D]; D]; D]);
+    }
 
-                // Invalidate com, because it's gonna be invalid no
-                // matter what if we aren't at a leaf node.
+    // build_parallel splits the root straight away, sorts the bodies
+    // into its children, and builds the subtree under each child on
+    // its own thread before copying them all into our arena. Each
+    // subtree sees its bodies in the same order the serial build
+    // would, so the shape of the tree (and every com) comes out
+    // exactly the same; only the order of the regions in the arena
+    // differs, and nothing depends on that.
+    fn build_parallel(
+        &mut self,
+        inside: &[usize],
+        bodies: &[Body<D>],
+        params: &Params
+    ) -> Vec<(usize, usize)> {
+        let first = self.split(0);
+
+        // (subtree, bodies in it, merges found in it) for each child
+        let mut subtrees = self.regions[first..]
+            .iter()
+            .map(|reg| {
+                let mut tree = Tree::new();
+                tree.regions.push(reg.clone());
+                (tree, Vec::new(), Vec::new())
+            })
+            .collect::<Vec<(Tree<D>, Vec<usize>, Vec<(usize, usize)>)>>();
+
+        for &i in inside.iter() {
+            let k = self.regions[0].child_index(&bodies[i]);
+            subtrees[k].1.push(i);
+        }
 
-                self.com = None;
+        let chunk = subtrees.len().div_ceil(params.num_threads);
+        thread::scope(|s| {
+            for group in subtrees.chunks_mut(chunk) {
+                s.spawn(move || {
+                    for &mut (ref mut tree, ref indices, ref mut merges) in group.iter_mut() {
+                        *merges = tree.insert_all(indices, bodies, params);
+                        tree.size_moments(params);
+                        tree.update_com(bodies);
+                    }
+                });
+            }
+        });
+
+        // Child indices in a subtree point into its own arena, where
+        // its root sits at 0 and everything else comes after. The root
+        // takes the place of the child region we split off above, and
+        // everything else goes on the end of our arena. Their moments
+        // (if any) go to the same places.
+        self.size_moments(params);
+        let mut merges = Vec::new();
+        for (k, (tree, _, sub_merges)) in subtrees.into_iter().enumerate() {
+            let base = self.regions.len();
+            for (j, mut reg) in tree.regions.into_iter().enumerate() {
+                reg.children = reg.children.map(|c| base + c - 1);
+                if j == 0 {
+                    self.regions[first + k] = reg;
+                } else {
+                    self.regions.push(reg);
+                }
+            }
+            if let Some((root, rest)) = tree.quads.split_first() {
+                self.quads[first + k] = *root;
+                self.quads.extend_from_slice(rest);
+            }
+            if let Some((root, rest)) = tree.octs.split_first() {
+                self.octs[first + k] = *root;
+                self.octs.extend_from_slice(rest);
+            }
+            merges.extend(sub_merges);
+        }
 
-                //
-                match &self.add_queue {
+        self.update_node(0, bodies);
+        merges.sort_by_key(|&(_, absorbed)| absorbed);
+        merges
+    }
 
-                    // If the add_queue is None, we only want to look
-                    // at the child regions.
+    // insert_all inserts the bodies with the passed indices in order,
+    // returning the (kept, absorbed) pairs for any that had to be
+    // left out.
+    fn insert_all(&mut self, indices: &[usize], bodies: &[Body<D>], params: &Params) -> Vec<(usize, usize)> {
+        let mut merges = Vec::new();
+        for &i in indices.iter() {
+            if let Some(kept) = self.insert(i, bodies, params) {
+                merges.push((kept, i));
+            }
+        }
+        merges
+    }
 
-                    &None => {
-                        //println!("updating children");
-                        let mut return_me = 0;
-                        for reg_arc in reg_vec.iter() {
-                            let mut reg = reg_arc.lock().unwrap();
-                            return_me += reg.update();
-                        }
-                        if return_me == 0 {
-                            // println!("\n\nDeleted region vector: {:#?}\n\n", self.coord_vec);
-                            self.reg_vec = None;
-                        }
-                        self.reg_vec = Some(reg_vec);
-                        return_me
+    // insert walks body i down from the root to the leaf it belongs
+    // in, splitting occupied leaves as it goes. If the leaf it ends
+    // up in is already at the minimum size, we give up and return
+    // the index of the body that's already there.
+    fn insert(&mut self, i: usize, bodies: &[Body<D>], params: &Params) -> Option<usize> {
+        let mut node = 0;
+        loop {
+            match self.regions[node].children {
+                Some(first) => {
+                    node = first + self.regions[node].child_index(&bodies[i]);
+                },
+                None => match self.regions[node].body {
+                    None => {
+                        self.regions[node].body = Some(i);
+                        return None;
                     },
-
-                    // I think this case should never be called
-                    // because the way we inject masses should mean they
-                    // always go into leaf nodes
-                    // right????
-                    &Some(_) => {
-                        // println!("whee!");
-                        // for some reason, this case is never
-                        // reached. (or is it?)
-                        //println!("injecting bodies into child regions");
-
-                        // recurse on false because we don't need to
-                        // split the region (it's already splitted)
-                        let result = self.recurse(false);
-                        if result == 0 {
-                            // println!("\n\nDeleted region vector: {:#?}\n\n", self.coord_vec);
-                            // self.reg_vec = None
+                    Some(j) => {
+                        if self.regions[node].half_length <= params.min_len {
+                            return Some(j);
                         }
-                        result
-                    },
+
+                        // push the mass that was already here down
+                        // into the right child, then carry on
+                        // looking for a home for body i
+                        let first = self.split(node);
+                        let k = first + self.regions[node].child_index(&bodies[j]);
+                        self.regions[k].body = Some(j);
+                        self.regions[node].body = None;
+                    }
                 }
-            },
+            }
         }
     }
 
-    fn split(&mut self) {
-        // println!("\n\n\n\nsplitting self: \n {:#?}", self);
-        // First, we check whether the calling region has any child
-        // regions or not. Don't want to split a region that's already
-        // been split.
-
-        match self.reg_vec {
-
-            None => {
-
-                // There's no children (as expected), so we'll create
-                // the children vector. First, we need to make a
-                // placeholder vector to write to, since None can't be
-                // unwrapped.
+    // split gives the leaf at index node its children, returning the
+    // index of the first one.
+    fn split(&mut self, node: usize) -> usize {
+        let first = self.regions.len();
+
+        // quarter_length will be used to efficiently calculate the
+        // coordinate vectors of the newly-created child regions.
+        let quarter_length = self.regions[node].half_length * 0.5;
+
+        // child_offset gives the displacement between the center of
+        // the calling region and the center of child k along each
+        // axis, scaled by a factor of quarter_length.
+        for k in 0..Region::<D>::NUM_CHILDREN {
+            let mut coord_vec = self.regions[node].coord_vec;
+            for (axis, qi) in coord_vec.iter_mut().enumerate() {
+                *qi += child_offset(k, axis) * quarter_length;
+            }
+            self.regions.push(Region::new(coord_vec, quarter_length));
+        }
 
-                let mut reg_vec = Vec::new();
+        self.regions[node].children = Some(first);
+        first
+    }
 
-                // quarter_length will be used to efficiently
-                // calculate the coordinate vectors of the
-                // newly-created child regions. We compute
-                // it up here so that we don't have to during each
-                // iteration of the loop below.
+    // reinsert is a partial rebuild, for when the bodies have moved a
+    // little since the last build. Bodies that have left the leaf that
+    // holds them get taken out and inserted again from the root, which
+    // puts them in the leaf they're in now, and then every com and
+    // moment gets worked out afresh; the rest of the tree stays as it
+    // was. Bodies that have left the root altogether can't go anywhere
+    // else, so they stay put until the next build, and so do ones that
+    // would end up within params.min_len of another body (the next
+    // build merges those), unless their old leaf has been taken in the
+    // meantime, in which case they're left out until then.
+    pub fn reinsert(&mut self, bodies: &[Body<D>], params: &Params) {
+        let moved = (0..self.regions.len())
+            .filter_map(|n| {
+                let reg = &self.regions[n];
+                match reg.body {
+                    Some(i) if !reg.contains(&bodies[i]) && self.regions[0].contains(&bodies[i]) => Some((n, i)),
+                    _ => None
+                }
+            })
+            .collect::<Vec<(usize, usize)>>();
 
-                let quarter_length = self.half_length * 0.5;
+        for &(n, _) in moved.iter() {
+            self.regions[n].body = None;
+        }
+        for &(n, i) in moved.iter() {
+            if self.insert(i, bodies, params).is_some()
+                && self.regions[n].body.is_none() && self.regions[n].children.is_none()
+            {
+                self.regions[n].body = Some(i);
+            }
+        }
 
-                // MULTIPLIERS is stored in a Mutex, so we have to
-                // lock and unwrap it, then clone it so that we can
-                // use its data while freeing it for other waiting
-                // threads to access threads can access it once we're
-                // done. FIXME: why do we need a mutex for multipliers
-                // at all...? I can't see why we'd be modifying it.
-                // I think it'd be faster to just pass a reference to
-                // the global MULTIPLIERS vector everywhere than it is
-                // locking and unwrapping it.
+        self.size_moments(params);
+        self.update_com(bodies);
+    }
 
-                for vec in MULTIPLIERS.lock().unwrap().clone().iter_mut() {
+    // update_com recomputes the center of mass (and whichever of the
+    // higher moments the last build made room for) of every region.
+    // Child regions always come after their parents in the arena, so
+    // one pass from the back is enough to do it bottom-up.
+    pub fn update_com(&mut self, bodies: &[Body<D>]) {
+        for n in (0..self.regions.len()).rev() {
+            self.update_node(n, bodies);
+        }
+    }
 
-                    // Here, MULTIPLIERS represents all the possible
-                    // displacement vectors between the center of the
-                    // calling region and the centers of its child
-                    // regions, scaled by a factor of quarter_length.
+    // update_node recomputes the com and moments of the region at
+    // index n from its children's.
+    fn update_node(&mut self, n: usize, bodies: &[Body<D>]) {
+        self.regions[n].com = self.node_com(n, bodies);
+        let (plus, minus) = self.node_charges(n);
+        self.regions[n].plus = plus;
+        self.regions[n].minus = minus;
+        if n < self.quads.len() {
+            self.node_moments(n);
+        }
+    }
 
-                    for i in 0..DIMS {
-                        vec[i] *= quarter_length;
-                        vec[i] += self.coord_vec[i];
+    // node_moments works out the second (and, if there's room for
+    // them, third) moments of the region at index n about its com,
+    // which needs to be up to date, along with everything about its
+    // children. Each child's moments are about its own com, so they
+    // get shifted over to ours with the parallel axis theorem; the
+    // children's first moments about their own coms vanish, which
+    // keeps this short.
+    fn node_moments(&mut self, n: usize) {
+        let with_oct = n < self.octs.len();
+        let mut quad = [[0.0; D]; D];
+        let mut oct = [[[0.0; D]; D]; D];
+
+        if let (Some(first), Some(com)) = (self.regions[n].children, &self.regions[n].com) {
+            for c in first..first + Region::<D>::NUM_CHILDREN {
+                let child_com = match self.regions[c].com {
+                    Some(ref cc) if cc.mass != 0.0 => cc,
+                    _ => continue
+                };
+
+                let mut shift = [0.0; D];
+                for (sk, (ck, xk)) in shift.iter_mut().zip(child_com.pos_vec.iter().zip(com.pos_vec.iter())) {
+                    *sk = ck - xk;
+                }
+                let m = child_com.mass;
+                let child_quad = &self.quads[c];
+
+                for j in 0..D {
+                    for k in 0..D {
+                        quad[j][k] += child_quad[j][k] + m * shift[j] * shift[k];
+                        if !with_oct {
+                            continue;
+                        }
+                        for l in 0..D {
+                            oct[j][k][l] += self.octs[c][j][k][l]
+                                + child_quad[j][k] * shift[l]
+                                + child_quad[j][l] * shift[k]
+                                + child_quad[k][l] * shift[j]
+                                + m * shift[j] * shift[k] * shift[l];
+                        }
                     }
-
-                    // Construct the empty child region corresponding
-                    // to the relative position given by vec, and push
-                    // it into the calling region's region vector
-
-                    reg_vec.push(
-                        Arc::new(
-                            Mutex::new(
-                                Region {
-                                    reg_vec: None,
-                                    // vec is currently a mutable reference,
-                                    // so we call .to_vec() on it to extrac
-                                    // the underlying vec.
-                                    coord_vec: vec.to_vec(),
-                                    add_queue: None,
-                                    com: None,
-                                    half_length: quarter_length,
-                                }
-                            )
-                        )
-                    )
                 }
+            }
+        }
 
-                // Now that we're done pushing all the child regions,
-                // we write reg_vec out to the calling region, and
-                // place it into an Option enum so that we can perform
-                // matches on it later.
+        self.quads[n] = quad;
+        if with_oct {
+            self.octs[n] = oct;
+        }
+    }
 
-                self.reg_vec = Some(reg_vec);
+    // node_com works out the center of mass of the region at index n,
+    // assuming its children's are already up to date.
+    fn node_com(&self, n: usize, bodies: &[Body<D>]) -> Option<Body<D>> {
+        match self.regions[n].children {
+
+            // Leaf coms are just the masses themselves.
+            None => self.regions[n].body.map(|i| Body {
+                pos_vec: bodies[i].pos_vec,
+                vel_vec: bodies[i].vel_vec,
+                mass: bodies[i].mass,
+                charge: bodies[i].charge,
+                radius: bodies[i].radius
+            }),
+
+            Some(first) => {
+                let mut num = [0.0; D];
+                let mut den = 0.0;
+
+                for child in self.regions[first..first + Region::<D>::NUM_CHILDREN].iter() {
+                    if let Some(ref com) = child.com {
+                        den += com.mass;
+                        for (ni, pi) in num.iter_mut().zip(&com.pos_vec) {
+                            *ni += pi * com.mass;
+                        }
+                    }
+                }
 
-            },
+                //if we didn't add any masses, make sure we're not dividing by 0
+                if den != 0.0 {
+                    for ni in num.iter_mut() {
+                        *ni /= den;
+                    }
+                } else {
+                    num = self.regions[n].coord_vec;
+                }
 
-            // We shouldn't ever be calling split on a pre-split
-            // region, so one of the calls must've been wrong.
-            Some(_) => {
-                panic!("this wasn't supposed to happen. {:#?}", self);
+                Some(Body {
+                    pos_vec: num,
+                    vel_vec: [0.0; D],
+                    mass: den,
+                    charge: 0.0,
+                    radius: 0.0
+                })
             }
         }
     }
 
-    fn recurse(&mut self, split: bool) -> i32 {
-        // println!("\n\n\nrecursing with {} on self: \n{:#?}", split, self);
-        // we call recurse(true) only when we need to split the
-        // region, so first call split then recurse on false.
-        //println!("called recurse");
-
-        if split {
-
-
-            if self.add_queue.clone().unwrap().len() == 1 {
-
-                //note that we can overwrite com because it
-                //would already have been added to
-                //the add queue, if it existed
-
-                self.com = self.add_queue.clone().unwrap().pop();
-                self.add_queue = None;
-                return 1
-
-            } else {
-
-                //if this region is very small and we don't want to subdivide it
-                //further, combine all the masses here into one
-                 if self.half_length <= MIN_LEN {
-                    let mut pos = vec![0.0; DIMS as usize];
-                    let mut vel = vec![0.0; DIMS as usize];
-                    let mut den = 0.0;
-
-                    for mass in self.add_queue.clone().unwrap() {
-                        // drop(match_me);
-                        let mut com = mass.try_lock().unwrap();
-                        den += com.mass.clone();
-                        //TODO: we shouldn't have to be cloning pos_vec
-                        pos =pos
-                            .iter()
-                            .zip(com.pos_vec.clone())
-                            .map(|(pi, pv)| pi + pv * com.mass)
-                            .collect::<Vec<f64>>();
-                        vel =vel
-                            .iter()
-                            .zip(com.vel_vec.clone())
-                            .map(|(pi, pv)| pi + pv * com.mass)
-                            .collect::<Vec<f64>>();
-
-
+    // node_charges works out the centers of positive and negative
+    // charge of the region at index n (see Region), assuming its
+    // children's are already up to date. Leaves don't get any.
+    fn node_charges(&self, n: usize) -> (Option<Body<D>>, Option<Body<D>>) {
+        let first = match self.regions[n].children {
+            None => return (None, None),
+            Some(first) => first
+        };
+
+        // the charge of each sign, and the sum of charge times
+        // position
+        let mut totals = [0.0; 2];
+        let mut nums = [[0.0; D]; 2];
+        for child in self.regions[first..first + Region::<D>::NUM_CHILDREN].iter() {
+            let parts = [&child.com, &child.plus, &child.minus];
+            for part in parts.iter() {
+                if let Some(ref p) = **part {
+                    let sign = if p.charge > 0.0 { 0 } else { 1 };
+                    totals[sign] += p.charge;
+                    for (ni, pi) in nums[sign].iter_mut().zip(&p.pos_vec) {
+                        *ni += pi * p.charge;
                     }
-                    //if we didn't add any masses, make sure we're not dividing by 0
-                    if den != 0.0 {
-                        pos = pos
-                            .iter()
-                            .map(|n| n / den)
-                            .collect::<Vec<f64>>();
-                        vel = vel
-                            .iter()
-                            .map(|n| n / den)
-                            .collect::<Vec<f64>>();
-                    }
-                    self.add_queue = None;
-                    self.com = Some(Arc::new(Mutex::new(Body {
-                        pos_vec: pos,
-                        vel_vec: vel,
-                        mass: den
-                    })));
-                    return 1;
-                } else {
-                    self.split();
-                    return self.recurse(false)
                 }
             }
+        }
 
-        } else {
-            self.push_masses_to_children();
+        let center = |sign: usize| {
+            if totals[sign] == 0.0 {
+                return None;
+            }
+            let mut pos = nums[sign];
+            for pi in pos.iter_mut() {
+                *pi /= totals[sign];
+            }
+            Some(Body {
+                pos_vec: pos,
+                vel_vec: [0.0; D],
+                mass: 0.0,
+                charge: totals[sign],
+                radius: 0.0
+            })
+        };
+        (center(0), center(1))
+    }
 
-            let mut remove = 0;
+    // Copies of every mass held by a leaf in the tree.
+    pub fn list_masses(&self, bodies: &[Body<D>]) -> Vec<Body<D>> {
+        self.regions.iter()
+            .filter_map(|reg| reg.body)
+            .map(|i| bodies[i].clone())
+            .collect::<Vec<Body<D>>>()
+    }
+}
 
-            match self.reg_vec.clone() {
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use data::DIMS;
+    use testing::body_at;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_build() {
+        let bodies = vec![
+            body_at([-100.0; DIMS], 1.0),
+            body_at([100.0; DIMS], 3.0),
+            body_at([101.0; DIMS], 4.0),
+            body_at([5000.0; DIMS], 1.0),
+        ];
+
+        let mut tree = Tree::new();
+        let merges = tree.build(&bodies, [0.0; DIMS], 1000.0, &Params::default());
+        assert!(merges.is_empty());
+
+        // the body outside the root is left out
+        assert_eq!(tree.list_masses(&bodies).len(), 3);
+
+        let com = tree.root().unwrap().com.clone().unwrap();
+        assert_eq!(com.mass, 8.0);
+        for xi in com.pos_vec.iter() {
+            assert!((xi - (-100.0 + 300.0 + 404.0) / 8.0).abs() < 1e-9);
+        }
 
-                None => {
-                    match self.com {
-                        None => 0,
-                        Some(_) => 1
-                    }
-                },
+        // every leaf holding a mass should contain it
+        for reg in tree.regions.iter() {
+            if let Some(i) = reg.body {
+                assert!(reg.contains(&bodies[i]));
+                assert!(reg.children.is_none());
+            }
+        }
+    }
 
-                Some(mut reg_vec) => {
+    #[test]
+    fn test_build_merges_below_min_len() {
+        let params = Params {
+            min_len: 1.0,
+            ..Params::default()
+        };
+
+        let bodies = vec![
+            body_at([10.0; DIMS], 1.0),
+            body_at([10.001; DIMS], 1.0),
+        ];
+
+        let mut tree = Tree::new();
+        let merges = tree.build(&bodies, [0.0; DIMS], 1000.0, &params);
+        assert_eq!(merges, vec![(0, 1)]);
+        assert_eq!(tree.list_masses(&bodies).len(), 1);
+    }
 
-                    for reg_arc in reg_vec.iter() {
-                        let mut region = reg_arc.lock().unwrap();
-                        // println!("updating child regions");
-                        remove += region.update();
-                    }
-                    // println!("child regions are {:#?}", reg_vec);
-                    self.reg_vec = Some(reg_vec);
+    #[test]
+    fn test_charge_centers() {
+        let params = Params {
+            num_threads: 1,
+            ..Params::default()
+        };
+        let mut bodies = vec![
+            body_at([-100.0; DIMS], 1.0),
+            body_at([100.0; DIMS], 1.0),
+            body_at([300.0; DIMS], 1.0),
+            body_at([-300.0, 200.0], 1.0),
+        ];
+        let charges = [2.0, 6.0, -1.0, -3.0];
+        for (body, &q) in bodies.iter_mut().zip(charges.iter()) {
+            body.charge = q;
+        }
+
+        let mut tree = Tree::new();
+        tree.build(&bodies, [0.0; DIMS], params.max_len, &params);
+        let root = tree.root().unwrap();
+
+        let plus = root.plus.as_ref().unwrap();
+        let minus = root.minus.as_ref().unwrap();
+        assert_eq!(plus.charge, 8.0);
+        assert_eq!(plus.pos_vec, [50.0; DIMS]);
+        assert_eq!(minus.charge, -4.0);
+        assert_eq!(minus.pos_vec, [-150.0, 225.0]);
+        assert_eq!(root.com.as_ref().unwrap().charge, 0.0);
+
+        // and the leaves carry their own charges on their coms
+        let leaf_charge = tree.regions.iter()
+            .filter(|reg| reg.children.is_none())
+            .filter_map(|reg| reg.com.as_ref())
+            .fold(0.0, |sum, com| sum + com.charge);
+        assert_eq!(leaf_charge, 4.0);
+    }
 
-                    //println!("returning {:#?} from recurse false", remove);
-                    return remove;
+    #[test]
+    fn test_moments() {
+        // the moments of the root should match summing over the
+        // bodies directly, however the tree happens to be split up
+        let bodies = vec![
+            body_at([-100.0, 30.0, 5.0], 1.0),
+            body_at([100.0, -20.0, 40.0], 3.0),
+            body_at([101.0, 400.0, -7.0], 4.0),
+            body_at([-300.0, -200.0, 600.0], 2.5),
+            body_at([-310.0, -190.0, 601.0], 0.5),
+        ];
+
+        let mut params = Params {
+            multipole_order: 3,
+            ..Params::default()
+        };
+        let mut tree = Tree::new();
+        tree.build(&bodies, [0.0; 3], 1000.0, &params);
+        let root = tree.root().unwrap();
+        let com = root.com.clone().unwrap();
+
+        let mut quad = [[0.0; 3]; 3];
+        let mut oct = [[[0.0; 3]; 3]; 3];
+        for body in bodies.iter() {
+            let mut y = [0.0; 3];
+            for (yi, (xi, ci)) in y.iter_mut().zip(body.pos_vec.iter().zip(com.pos_vec.iter())) {
+                *yi = xi - ci;
+            }
+            for j in 0..3 {
+                for k in 0..3 {
+                    quad[j][k] += body.mass * y[j] * y[k];
+                    for (o, yl) in oct[j][k].iter_mut().zip(y.iter()) {
+                        *o += body.mass * y[j] * y[k] * yl;
+                    }
                 }
             }
         }
-    }
 
-    //remove masses from the add_queue
-    //and place them in the child nodes
-    //FIXME: not properly removing masses that aren't in the
-    //simulation anymore
-    pub fn push_masses_to_children(&mut self) {
-        // println!("pushing masses to children");
-        // FIXME: do this actually properly.
-
-        // we clone the queue so that we can pop masses out of it
-        // as we're working. Shouldn't need to do this though.
-
-        let mut queue = self.add_queue.clone().unwrap();
-        let queue_len = queue.clone().len();
-
-        // take the raw Option enum on the vector of child regions
-        // and not the wrapped vector itself, so that we can do
-        // checks on it first to make sure it's nonempty.
-
-        let opt_vec = self.reg_vec.clone();
-
-        match opt_vec {
-
-            None => {
-                // If we don't have a set of child regions, then we
-                // only want what's on the top of the queue (there
-                // should only be one thing, else we'd have split in
-                // the self.recurse() call)
-                self.com = queue.pop();
-                // never triggered because handeled in recurse
-                // println!("None case com is \n{:#?}", self.com);
-                assert_eq!(queue.len(), 0);
-            },
-
-            Some(mut reg_vec) => {
-                // let vec_len = reg_vec.len();
-
-                'outer: for _ in 0..queue_len {
-                    // println!("this mass is \n{:#?}", mass.clone());
-                    // println!("the queue is {:#?}", queue.clone());
-                    // println!("the current region vector is {:#?}", reg_vec);
-
-                    let m_arc = queue.pop().unwrap();
-
-                    'inner: for reg_arc in reg_vec.iter() {
-                        let mut region = reg_arc.lock().unwrap();
-                        if region.contains(Arc::clone(&m_arc)) {
-                            // define reg_queue here for the Some arm
-                            // of our match
-                            let mut reg_queue = region.add_queue.clone();
-
-                            let mut reg_queue = match reg_queue {
-                                None => {Some(Vec::new())},
-                                Some(_) => {reg_queue},
-                            }.unwrap();
-
-                            reg_queue.push(m_arc);
-                            region.add_queue = Some(reg_queue);
-                            continue 'outer
-                        }
-                    }
+        for j in 0..3 {
+            for k in 0..3 {
+                assert!((tree.quads[0][j][k] - quad[j][k]).abs() < 1e-8 * quad[j][k].abs().max(1.0));
+                for (l, &expected) in oct[j][k].iter().enumerate() {
+                    assert!((tree.octs[0][j][k][l] - expected).abs() < 1e-8 * expected.abs().max(1.0));
                 }
-                self.reg_vec = Some(reg_vec);
             }
         }
-        self.add_queue = None;
-    }
 
-    // push a body to this region's add_queue
-    pub fn push_body_global(body_arc: Arc<Mutex<Body>>) {
-        let ref tree = &TREE_POINTER.lock().unwrap().tree.clone();
-        let mut add_queue = tree.add_queue.clone();
-
-        //if the added mass is outside of the tree region, don't add it
-        // println!("about to call contains");
-        if(!tree.contains(Arc::clone(&body_arc))) {
-            // panic!("wwaaaa");
-            // println!("\n\nDeleted mass: {:#?}\n\n", body_arc);
-            return;
-        } else {
-            // panic!("panci");
-            // println!("didn't delete mass\n\n\n\n\n\n\n\n\n");
-            //if the add queue doesn't already exist, create it
-            match add_queue {
-
-                None => {
-                    let mut queue = Vec::new();
-                    queue.push(body_arc);
-                    TREE_POINTER.lock().unwrap().tree.add_queue = Some(queue);
-                },
+        // and only the moments the params need get worked out at all
+        for &(order, quads, octs) in [(1, 0, 0), (2, tree.regions.len(), 0)].iter() {
+            params.multipole_order = order;
+            tree.build(&bodies, [0.0; 3], 1000.0, &params);
+            assert_eq!((tree.quads.len(), tree.octs.len()), (quads, octs));
+        }
+    }
 
-                Some(mut queue) => {
-                    queue.push(body_arc);
-                    TREE_POINTER.lock().unwrap().tree.add_queue = Some(queue);
-                }
-            };
-            //println!("TREE add queue: {:#?}", TREE_POINTER.lock().unwrap().tree.add_queue.clone());
+    #[test]
+    fn test_parallel_build_matches_serial() {
+        let mut params = Params {
+            min_len: 1.0,
+            multipole_order: 3,
+            ..Params::default()
+        };
+
+        // a grid of bodies, plus a few that will need to be merged
+        let mut bodies = Vec::new();
+        for i in 0..40 {
+            for j in 0..40 {
+                let x = -900.0 + 45.0 * i as f64;
+                let y = -900.0 + 45.0 * j as f64 + 0.37 * i as f64;
+                bodies.push(body_at([x, y], 1.0 + (i * j % 5) as f64));
+            }
+        }
+        for i in 0..10 {
+            let pos_vec = bodies[i * 37].pos_vec;
+            bodies.push(body_at(pos_vec, 2.0));
+        }
 
+        params.num_threads = 1;
+        let mut serial = Tree::new();
+        let serial_merges = serial.build(&bodies, [0.0; 2], 1000.0, &params);
+
+        params.num_threads = 4;
+        let mut parallel = Tree::new();
+        let parallel_merges = parallel.build(&bodies, [0.0; 2], 1000.0, &params);
+
+        assert_eq!(serial_merges.len(), 10);
+        assert_eq!(serial_merges, parallel_merges);
+        assert_eq!(serial.regions.len(), parallel.regions.len());
+
+        let com1 = serial.root().unwrap().com.clone().unwrap();
+        let com2 = parallel.root().unwrap().com.clone().unwrap();
+        assert_eq!(com1.pos_vec, com2.pos_vec);
+        assert_eq!(com1.mass, com2.mass);
+
+        // every region's moments end up next to it, wherever it went
+        let key = |reg: &Region<2>| (reg.coord_vec[0].to_bits(), reg.coord_vec[1].to_bits(), reg.half_length.to_bits());
+        let moments = serial.regions.iter()
+            .zip(serial.quads.iter().zip(serial.octs.iter()))
+            .map(|(reg, moments)| (key(reg), moments))
+            .collect::<HashMap<_, _>>();
+        assert_eq!(parallel.quads.len(), parallel.regions.len());
+        assert_eq!(parallel.octs.len(), parallel.regions.len());
+        for (reg, (quad, oct)) in parallel.regions.iter().zip(parallel.quads.iter().zip(parallel.octs.iter())) {
+            assert_eq!(moments[&key(reg)], (quad, oct));
         }
     }
 
-    pub fn list_masses(&self) -> Vec<Body> {
+    fn check_child_index_matches_offsets<const D: usize>() {
+        let mut tree = Tree::<D>::new();
+        tree.regions.push(Region::new([0.0; D], 8.0));
+        let first = tree.split(0);
+        assert_eq!(tree.regions.len() - first, Region::<D>::NUM_CHILDREN);
 
-        match self.reg_vec.clone() {
-            None => {
-                match self.com.clone() {
-                    None => vec![],
-                    Some(ref mut com) => vec![com.lock().unwrap().clone()]
-                }
-            },
-            Some(ref reg_vec) => {
-                let mut result = Vec::new();
-                for mut child_arc in reg_vec {
-                    let child = child_arc.lock().unwrap();
-                    result.append(&mut child.list_masses());
-                }
-                result
-            }
+        for (k, child) in tree.regions[first..].iter().enumerate() {
+            let body = body_at(child.coord_vec, 1.0);
+            assert_eq!(tree.regions[0].child_index(&body), k);
+            assert!(tree.regions[0].contains(&body));
         }
     }
+
+    #[test]
+    fn test_child_index_matches_offsets() {
+        check_child_index_matches_offsets::<1>();
+        check_child_index_matches_offsets::<2>();
+        check_child_index_matches_offsets::<3>();
+        check_child_index_matches_offsets::<4>();
+    }
 }